@@ -0,0 +1,121 @@
+use crate::anki_config::DuplicateScope;
+use crate::ankiconnect::AnkiConnectError;
+use crate::note_type::NewNoteType;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Maps local media files to the names under which Anki stored them.
+/// Anki renames a file if a different file with the same name already exists.
+/// Files that were already in Anki with the same content are not included and keep their names.
+pub type StoredMedia = HashMap<PathBuf, String>;
+
+/// An existing note, as returned by `notesInfo`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoteInfo {
+    pub note_id: u64,
+    pub model_name: String,
+    pub tags: Vec<String>,
+    /// Field names and values, in the order of the note type
+    pub fields: Vec<(String, String)>,
+}
+
+impl NoteInfo {
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(field_name, _)| field_name == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// The Anki operations needed to select the Anki settings and send a card.
+///
+/// [`crate::ankiconnect::AnkiConnectClient`] is the implementation used by the
+/// application, but the menus only depend on this trait.
+pub trait AnkiBackend {
+    /// Changes the API key sent with every following request
+    fn set_api_key(&self, api_key: Option<String>);
+
+    /// Stores all files in the media folder with one `multi` request, after looking up which
+    /// of them are already there. If one of them fails, the others are removed again.
+    fn store_files(&self, files: &[&PathBuf]) -> Result<StoredMedia, AnkiConnectError>;
+
+    fn delete_media_files(&self, stored_media: &StoredMedia) -> Result<(), AnkiConnectError>;
+
+    /// Adds a note with a separate `addNote` request and returns its ID. The media has to be
+    /// stored first with [`AnkiBackend::store_files`], because the fields refer to the names
+    /// Anki stored it under. If Anki rejects the note, the media in `stored_media` is removed
+    /// again, so that a failed send leaves the collection unchanged.
+    fn send_note(
+        &self,
+        deck: &str,
+        note_type: &str,
+        contents: &HashMap<String, String>,
+        tags: &[String],
+        stored_media: &StoredMedia,
+    ) -> Result<u64, AnkiConnectError>;
+
+    /// Opens the Add dialog of Anki, filled with the given note
+    fn gui_add_cards(
+        &self,
+        deck: &str,
+        note_type: &str,
+        contents: &HashMap<String, String>,
+        tags: &[String],
+    ) -> Result<(), AnkiConnectError>;
+
+    /// Overwrites the given fields of an existing note. Other fields are left unchanged.
+    fn update_note_fields(
+        &self,
+        note_id: u64,
+        contents: &HashMap<String, String>,
+    ) -> Result<(), AnkiConnectError>;
+
+    /// Replaces all tags of an existing note
+    fn update_note_tags(&self, note_id: u64, tags: &[String]) -> Result<(), AnkiConnectError>;
+
+    /// Overwrites the given fields of an existing note and then replaces its tags.
+    /// If Anki rejects the fields, the tags are left unchanged and the media in
+    /// `stored_media` is removed again, like with [`AnkiBackend::send_note`].
+    fn update_note(
+        &self,
+        note_id: u64,
+        contents: &HashMap<String, String>,
+        tags: &[String],
+        stored_media: &StoredMedia,
+    ) -> Result<(), AnkiConnectError>;
+
+    /// Returns whether Anki would accept the note without allowing duplicates,
+    /// i.e. whether no note with the same first field exists in `scope`.
+    fn can_add_note(
+        &self,
+        deck: &str,
+        note_type: &str,
+        contents: &HashMap<String, String>,
+        scope: DuplicateScope,
+    ) -> Result<bool, AnkiConnectError>;
+
+    /// Returns the IDs of all notes matching an Anki search query
+    fn find_notes(&self, query: &str) -> Result<Vec<u64>, AnkiConnectError>;
+
+    fn notes_info(&self, note_ids: &[u64]) -> Result<Vec<NoteInfo>, AnkiConnectError>;
+
+    /// Opens the card browser of Anki with a search query
+    fn gui_browse(&self, query: &str) -> Result<(), AnkiConnectError>;
+
+    fn get_deck_names(&self) -> Result<Vec<String>, AnkiConnectError>;
+
+    fn get_model_names(&self) -> Result<Vec<String>, AnkiConnectError>;
+
+    fn get_field_names(&self, model_name: &str) -> Result<Vec<String>, AnkiConnectError>;
+
+    /// Returns the LaTeX preamble (`latexPre`) of a note type
+    fn get_latex_preamble(&self, model_name: &str) -> Result<String, AnkiConnectError>;
+
+    fn is_cloze_note_type(&self, model_name: &str) -> Result<bool, AnkiConnectError>;
+
+    /// Creates a deck. `Parent::Child` names create the parent deck as well.
+    fn create_deck(&self, deck: &str) -> Result<(), AnkiConnectError>;
+
+    fn create_note_type(&self, note_type: &NewNoteType) -> Result<(), AnkiConnectError>;
+}
@@ -1,7 +1,9 @@
+use crate::math_format::MathFormat;
 use crate::paths::get_anki_config_cache_file;
 use crate::possible_entries::PossibleContent;
 use anyhow::{anyhow, Context, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -9,6 +11,74 @@ pub struct AnkiConfig {
     pub deck_name: String,
     pub note_type: String,
     pub field_mapping: Vec<(String, PossibleContent)>,
+    #[serde(default)]
+    pub duplicate_scope: DuplicateScope,
+    #[serde(default)]
+    pub duplicate_policy: DuplicatePolicy,
+    /// Format of the fields with LaTeX, fields that are missing use [`MathFormat::Latex`]
+    #[serde(default)]
+    pub math_formats: HashMap<String, MathFormat>,
+}
+
+impl AnkiConfig {
+    /// Settings with the default duplicate handling and math formats
+    pub fn new(
+        deck_name: &str,
+        note_type: &str,
+        field_mapping: Vec<(String, PossibleContent)>,
+    ) -> Self {
+        Self {
+            deck_name: deck_name.to_string(),
+            note_type: note_type.to_string(),
+            field_mapping,
+            duplicate_scope: DuplicateScope::default(),
+            duplicate_policy: DuplicatePolicy::default(),
+            math_formats: HashMap::new(),
+        }
+    }
+
+    /// Returns the first field that is filled with `content`
+    pub fn field_for_content(&self, content: &PossibleContent) -> Option<&str> {
+        self.field_mapping
+            .iter()
+            .find(|(_, mapped)| mapped == content)
+            .map(|(field_name, _)| field_name.as_str())
+    }
+
+    /// Replaces the field mapping and drops the math formats of fields that are no longer
+    /// in it, so that a new field with the same name starts with the default format
+    pub fn set_field_mapping(&mut self, field_mapping: Vec<(String, PossibleContent)>) {
+        self.math_formats
+            .retain(|field_name, _| field_mapping.iter().any(|(name, _)| name == field_name));
+        self.field_mapping = field_mapping;
+    }
+
+    pub fn math_format(&self, field_name: &str) -> MathFormat {
+        self.math_formats
+            .get(field_name)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Where to look for existing notes that are similar to the new one
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateScope {
+    #[default]
+    Deck,
+    Collection,
+}
+
+/// What to do when similar notes already exist
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicatePolicy {
+    /// Show the similar notes and let the user decide
+    #[default]
+    Ask,
+    /// Don't check for duplicates
+    Allow,
+    /// Show the similar notes, but never add a duplicate
+    Reject,
 }
 
 pub fn save_anki_config(config: &AnkiConfig) -> Result<()> {
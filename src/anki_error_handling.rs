@@ -1,18 +1,204 @@
+use crate::anki_backend::AnkiBackend;
+use crate::anki_config::save_anki_config;
 use crate::ankiconnect::AnkiConnectError;
+use crate::main_application::ApplicationState;
+use crate::possible_entries::find_section_names;
+use crate::settings::save_api_key;
+use crate::tui_windows::{
+    select_anki_deck, select_anki_note_type, select_field_mapping_for_note_type, show_message,
+};
 use crate::ui::tui::Tui;
 
+/// How the user can fix an error that a request ran into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ErrorFix {
+    StartAnki,
+    EnterApiKey,
+    /// The deck doesn't exist, so another one has to be selected
+    SelectDeck(String),
+    /// The note type doesn't exist, so another one has to be selected
+    SelectNoteType(String),
+    RedoFieldMapping,
+}
+
+/// Returns how `error` can be fixed, or `None` if the user can't fix it
+pub fn error_fix(error: &AnkiConnectError) -> Option<ErrorFix> {
+    match error {
+        AnkiConnectError::NotRunning => Some(ErrorFix::StartAnki),
+        AnkiConnectError::InvalidApiKey => Some(ErrorFix::EnterApiKey),
+        AnkiConnectError::DeckNotFound(deck) => Some(ErrorFix::SelectDeck(deck.clone())),
+        AnkiConnectError::ModelNotFound(model) => Some(ErrorFix::SelectNoteType(model.clone())),
+        AnkiConnectError::EmptyNote => Some(ErrorFix::RedoFieldMapping),
+        _ => None,
+    }
+}
+
+/// Sends `request` until it succeeds. The errors that any request can run into, Anki not
+/// running and a wrong API key, are passed to `fix`, which returns whether to retry.
+/// Other errors, and errors that weren't fixed, are returned.
+pub fn retry_request<T>(
+    anki: &dyn AnkiBackend,
+    request: impl Fn(&dyn AnkiBackend) -> Result<T, AnkiConnectError>,
+    mut fix: impl FnMut(ErrorFix) -> anyhow::Result<bool>,
+) -> anyhow::Result<T> {
+    loop {
+        let error = match request(anki) {
+            Ok(value) => return Ok(value),
+            Err(error) => error,
+        };
+        let fixed = match error_fix(&error) {
+            Some(error_fix @ (ErrorFix::StartAnki | ErrorFix::EnterApiKey)) => fix(error_fix)?,
+            _ => false,
+        };
+        if !fixed {
+            return Err(error.into());
+        }
+    }
+}
+
+/// [`retry_request`] that asks the user to fix the errors
+pub fn request_with_retry<T>(
+    tui: &mut Tui,
+    anki: &dyn AnkiBackend,
+    request: impl Fn(&dyn AnkiBackend) -> Result<T, AnkiConnectError>,
+) -> anyhow::Result<T> {
+    retry_request(anki, request, |fix| ask_to_fix(tui, anki, fix))
+}
+
+/// Lets the user fix the errors that any request can run into, Anki not running and a
+/// wrong API key. Returns `Ok` if the request should be retried.
 pub fn check_should_retry(
     error: AnkiConnectError,
     tui: &mut Tui,
-) -> anyhow::Result<(), AnkiConnectError> {
-    match error {
-        AnkiConnectError::NotRunning => {
+    anki: &dyn AnkiBackend,
+) -> anyhow::Result<()> {
+    let fixed = match error_fix(&error) {
+        Some(fix @ (ErrorFix::StartAnki | ErrorFix::EnterApiKey)) => ask_to_fix(tui, anki, fix)?,
+        _ => false,
+    };
+    if fixed {
+        Ok(())
+    } else {
+        Err(error.into())
+    }
+}
+
+/// Returns whether the request should be retried. Quits if the user doesn't want to
+/// wait for Anki.
+fn ask_to_fix(tui: &mut Tui, anki: &dyn AnkiBackend, fix: ErrorFix) -> anyhow::Result<bool> {
+    match fix {
+        ErrorFix::StartAnki => {
             match tui.show_dialog("Anki is not running. Do you want to retry?") {
-                Ok(true) => Ok(()),                 // Retry
+                Ok(true) => Ok(true),               // Retry
                 Ok(false) => std::process::exit(1), // Quit
-                Err(_) => Err(error),               // Dialog error, propagate the original error
+                Err(_) => Ok(false),                // Dialog error, return the original error
             }
         }
-        _ => Err(error),
+        ErrorFix::EnterApiKey => ask_for_api_key(tui, anki),
+        _ => Ok(false),
     }
 }
+
+/// Returns whether a new key was entered. The key is saved, so that it's used
+/// instead of the one in `config.toml` from now on.
+fn ask_for_api_key(tui: &mut Tui, anki: &dyn AnkiBackend) -> anyhow::Result<bool> {
+    let Some(api_key) = tui.show_text_input("AnkiConnect API key", true)? else {
+        return Ok(false);
+    };
+    anki.set_api_key(Some(api_key.clone()));
+    if let Err(e) = save_api_key(&api_key) {
+        show_message(tui, &format!("The API key couldn't be saved: {:#}", e))?;
+    }
+    Ok(true)
+}
+
+pub enum SendErrorResolution {
+    Retry,
+    QueueForLater,
+    /// Return to the menu without sending
+    Cancel,
+    /// Quit bookminer without sending
+    Quit,
+}
+
+/// Like [`check_should_retry`], but also offers to keep a finished note for later
+/// and leads to the matching fix if Anki rejected the note because of wrong settings.
+pub fn resolve_send_error(
+    error: AnkiConnectError,
+    state: &mut ApplicationState,
+) -> anyhow::Result<SendErrorResolution> {
+    let Some(fix) = error_fix(&error) else {
+        if matches!(error, AnkiConnectError::Duplicate) {
+            show_message(&mut state.tui, "Anki rejected the note as a duplicate")?;
+            return Ok(SendErrorResolution::Cancel);
+        }
+        return Err(error.into());
+    };
+
+    match fix {
+        ErrorFix::StartAnki => {
+            let options = ["Retry", "Queue for later", "Quit"];
+            match state
+                .tui
+                .show_single_selection_menu("Anki is not running", &options)
+            {
+                Ok(0) => Ok(SendErrorResolution::Retry),
+                Ok(1) => Ok(SendErrorResolution::QueueForLater),
+                Ok(_) => Ok(SendErrorResolution::Quit),
+                Err(_) => Err(error.into()), // Menu error, propagate the original error
+            }
+        }
+        ErrorFix::SelectDeck(deck) => {
+            let msg = format!("The deck \"{}\" doesn't exist. Select another deck?", deck);
+            if !state.tui.show_dialog(&msg)? {
+                return Ok(SendErrorResolution::Cancel);
+            }
+
+            state.anki_config.deck_name = select_anki_deck(&mut state.tui, state.anki.as_ref())?;
+            save_anki_config(&state.anki_config)?;
+            Ok(SendErrorResolution::Retry)
+        }
+        ErrorFix::SelectNoteType(model) => {
+            let msg = format!(
+                "The note type \"{}\" doesn't exist. Select another note type?",
+                model
+            );
+            if !state.tui.show_dialog(&msg)? {
+                return Ok(SendErrorResolution::Cancel);
+            }
+
+            state.anki_config.note_type =
+                select_anki_note_type(&mut state.tui, state.anki.as_ref())?;
+            redo_field_mapping(state)?;
+            Ok(SendErrorResolution::Retry)
+        }
+        ErrorFix::RedoFieldMapping => {
+            let msg = "The first field is empty or the fields don't match. Redo the field mapping?";
+            if !state.tui.show_dialog(msg)? {
+                return Ok(SendErrorResolution::Cancel);
+            }
+
+            redo_field_mapping(state)?;
+            Ok(SendErrorResolution::Retry)
+        }
+        ErrorFix::EnterApiKey => {
+            if ask_for_api_key(&mut state.tui, state.anki.as_ref())? {
+                Ok(SendErrorResolution::Retry)
+            } else {
+                Ok(SendErrorResolution::Cancel)
+            }
+        }
+    }
+}
+
+fn redo_field_mapping(state: &mut ApplicationState) -> anyhow::Result<()> {
+    let field_mapping = select_field_mapping_for_note_type(
+        &mut state.tui,
+        state.anki.as_ref(),
+        &state.anki_config.note_type,
+        &find_section_names(&state.tmp_dir),
+    )?;
+    state.anki_config.set_field_mapping(field_mapping);
+    save_anki_config(&state.anki_config)?;
+    Ok(())
+}
@@ -0,0 +1,15 @@
+/// Creates a quoted `key:value` term for an Anki search query, matching `value` literally
+pub fn search_term(key: &str, value: &str) -> String {
+    format!("\"{}:{}\"", key, escape_search_text(value))
+}
+
+fn escape_search_text(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '"' | '*' | '_') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
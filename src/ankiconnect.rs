@@ -1,10 +1,16 @@
+use crate::anki_backend::{AnkiBackend, NoteInfo, StoredMedia};
+use crate::anki_config::DuplicateScope;
+use crate::note_type::NewNoteType;
+use crate::settings::AnkiConnectSettings;
 use anyhow::Result;
 use base64::engine::general_purpose;
 use base64::Engine;
 use reqwest::blocking::Client;
 use serde_json::{json, Value};
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 use std::{fs, io};
 use thiserror::Error;
 
@@ -16,6 +22,21 @@ pub enum AnkiConnectError {
     #[error("Invalid filename: {0}")]
     InvalidFilename(String),
 
+    #[error("The deck \"{0}\" was not found")]
+    DeckNotFound(String),
+
+    #[error("The note type \"{0}\" was not found")]
+    ModelNotFound(String),
+
+    #[error("The note is empty. The first field is empty or the fields don't match the note type")]
+    EmptyNote,
+
+    #[error("The note is a duplicate")]
+    Duplicate,
+
+    #[error("AnkiConnect requires a valid API key")]
+    InvalidApiKey,
+
     #[error("Bad request error: {0}")]
     BadRequestError(String),
 
@@ -33,39 +54,372 @@ pub enum AnkiConnectError {
     },
 }
 
-fn send_request(action: &str, params: Value) -> Result<Value, AnkiConnectError> {
-    let client = Client::new();
-    const URL: &str = "http://localhost:8765";
-    let request_body = json!({
-        "action": action,
-        "version": 6,
-        "params": params
-    });
+const API_VERSION: u8 = 6;
+
+pub struct AnkiConnectClient {
+    url: String,
+    /// Can be changed by the menus when AnkiConnect rejects the key
+    api_key: RefCell<Option<String>>,
+    client: Client,
+}
 
-    match client.post(URL).json(&request_body).send() {
-        Ok(response) => {
-            let result: Value = response
-                .json()
-                .map_err(|_e| AnkiConnectError::ParsingError)?;
+impl AnkiConnectClient {
+    pub fn new(settings: &AnkiConnectSettings) -> Result<Self, AnkiConnectError> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(settings.timeout_secs))
+            .build()?;
 
-            match result.get("error") {
-                Some(error) if !error.is_null() => {
-                    Err(AnkiConnectError::BadRequestError(error.to_string()))
+        Ok(Self {
+            url: settings.url.clone(),
+            api_key: RefCell::new(settings.api_key.clone()),
+            client,
+        })
+    }
+
+    fn send_request(&self, action: &str, params: Value) -> Result<Value, AnkiConnectError> {
+        let request_body = create_request_body(action, params, self.api_key.borrow().as_deref());
+
+        match self.client.post(&self.url).json(&request_body).send() {
+            Ok(response) => {
+                let result: Value = response
+                    .json()
+                    .map_err(|_e| AnkiConnectError::ParsingError)?;
+
+                parse_response(result)
+            }
+            Err(e) => {
+                if e.is_connect() {
+                    Err(AnkiConnectError::NotRunning)
+                } else {
+                    Err(AnkiConnectError::HttpError(e))
                 }
-                _ => Ok(result["result"].clone()),
             }
         }
-        Err(e) => {
-            if e.is_connect() {
-                Err(AnkiConnectError::NotRunning)
-            } else {
-                Err(AnkiConnectError::HttpError(e))
+    }
+
+    /// Creates an action for [`Self::send_multi`]. AnkiConnect checks the API key
+    /// of every action inside a `multi` request, not only of the outer one.
+    fn create_action(&self, action: &str, params: Value) -> Value {
+        create_request_body(action, params, self.api_key.borrow().as_deref())
+    }
+
+    /// Sends several actions in a single `multi` request.
+    /// AnkiConnect executes all of them, even if some fail, and returns one result per action.
+    fn send_multi(
+        &self,
+        actions: Vec<Value>,
+    ) -> Result<Vec<Result<Value, AnkiConnectError>>, AnkiConnectError> {
+        let action_count = actions.len();
+        let result = self.send_request("multi", json!({ "actions": actions }))?;
+
+        let results: Vec<_> = result
+            .as_array()
+            .ok_or(AnkiConnectError::ParsingError)?
+            .iter()
+            .cloned()
+            .map(parse_response)
+            .collect();
+
+        if results.len() != action_count {
+            return Err(AnkiConnectError::ParsingError);
+        }
+        Ok(results)
+    }
+
+    /// Returns the description of a note type from `findModelsByName`
+    fn find_model(&self, model_name: &str) -> Result<Value, AnkiConnectError> {
+        let params = json!({
+            "modelNames": [model_name]
+        });
+        let mut result = self.send_request("findModelsByName", params)?;
+        result
+            .get_mut(0)
+            .map(Value::take)
+            .ok_or(AnkiConnectError::ParsingError)
+    }
+}
+
+impl AnkiBackend for AnkiConnectClient {
+    fn set_api_key(&self, api_key: Option<String>) {
+        self.api_key.replace(api_key);
+    }
+
+    fn store_files(&self, files: &[&PathBuf]) -> Result<StoredMedia, AnkiConnectError> {
+        if files.is_empty() {
+            return Ok(StoredMedia::new());
+        }
+
+        let params = files
+            .iter()
+            .map(|file| create_store_file_params(file))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Only the names are requested, not the data. Anki compares the checksum of a file
+        // with an existing file of the same name and keeps the name if they are equal.
+        let lookup_actions = params
+            .iter()
+            .map(|p| {
+                let pattern = escape_media_pattern(p["filename"].as_str().unwrap_or_default());
+                self.create_action("getMediaFilesNames", json!({ "pattern": pattern }))
+            })
+            .collect();
+        let existing = self
+            .send_multi(lookup_actions)?
+            .into_iter()
+            .map(|names| names.map(|names| parse_string_list(&names).unwrap_or_default()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let actions = params
+            .into_iter()
+            .map(|p| self.create_action("storeMediaFile", p))
+            .collect();
+
+        let mut stored_media = StoredMedia::with_capacity(files.len());
+        let mut media_error = None;
+        for ((file, existing), result) in files.iter().zip(existing).zip(self.send_multi(actions)?)
+        {
+            match result.and_then(|name| {
+                name.as_str()
+                    .map(String::from)
+                    .ok_or(AnkiConnectError::ParsingError)
+            }) {
+                // A file that was already there with the same content may be used by other
+                // notes, so it's left out of the stored media and a rollback doesn't delete it
+                Ok(stored_name) if existing.contains(&stored_name) => {}
+                Ok(stored_name) => {
+                    stored_media.insert(file.to_path_buf(), stored_name);
+                }
+                Err(e) => {
+                    media_error.get_or_insert(e);
+                }
+            }
+        }
+
+        match media_error {
+            None => Ok(stored_media),
+            Some(e) => {
+                let _ = self.delete_media_files(&stored_media);
+                Err(e)
+            }
+        }
+    }
+
+    fn delete_media_files(&self, stored_media: &StoredMedia) -> Result<(), AnkiConnectError> {
+        let actions: Vec<Value> = stored_media
+            .values()
+            .map(|filename| self.create_action("deleteMediaFile", json!({ "filename": filename })))
+            .collect();
+
+        if !actions.is_empty() {
+            self.send_multi(actions)?
+                .into_iter()
+                .try_for_each(|result| result.map(|_| ()))?;
+        }
+        Ok(())
+    }
+
+    fn send_note(
+        &self,
+        deck: &str,
+        note_type: &str,
+        contents: &HashMap<String, String>,
+        tags: &[String],
+        stored_media: &StoredMedia,
+    ) -> Result<u64, AnkiConnectError> {
+        let params = create_add_note_params(deck, note_type, contents, tags);
+
+        match self.send_request("addNote", params) {
+            Ok(result) => result.as_u64().ok_or(AnkiConnectError::ParsingError),
+            Err(e) => {
+                // Best effort, the rejection is the more useful error to report
+                let _ = self.delete_media_files(stored_media);
+                Err(e)
+            }
+        }
+    }
+
+    fn gui_add_cards(
+        &self,
+        deck: &str,
+        note_type: &str,
+        contents: &HashMap<String, String>,
+        tags: &[String],
+    ) -> Result<(), AnkiConnectError> {
+        let params = json!({
+            "note": {
+                "deckName": deck,
+                "modelName": note_type,
+                "fields": contents,
+                "tags": tags
+            }
+        });
+        self.send_request("guiAddCards", params)?;
+        Ok(())
+    }
+
+    fn update_note_fields(
+        &self,
+        note_id: u64,
+        contents: &HashMap<String, String>,
+    ) -> Result<(), AnkiConnectError> {
+        let params = json!({
+            "note": {
+                "id": note_id,
+                "fields": contents
             }
+        });
+        self.send_request("updateNoteFields", params)?;
+        Ok(())
+    }
+
+    fn update_note_tags(&self, note_id: u64, tags: &[String]) -> Result<(), AnkiConnectError> {
+        let params = json!({
+            "note": note_id,
+            "tags": tags
+        });
+        self.send_request("updateNoteTags", params)?;
+        Ok(())
+    }
+
+    fn update_note(
+        &self,
+        note_id: u64,
+        contents: &HashMap<String, String>,
+        tags: &[String],
+        stored_media: &StoredMedia,
+    ) -> Result<(), AnkiConnectError> {
+        if let Err(e) = self.update_note_fields(note_id, contents) {
+            let _ = self.delete_media_files(stored_media);
+            return Err(e);
+        }
+        // The fields use the media now, so it's kept even if the tags fail
+        self.update_note_tags(note_id, tags)
+    }
+
+    fn can_add_note(
+        &self,
+        deck: &str,
+        note_type: &str,
+        contents: &HashMap<String, String>,
+        scope: DuplicateScope,
+    ) -> Result<bool, AnkiConnectError> {
+        let params = create_can_add_notes_params(deck, note_type, contents, scope);
+        let result = self.send_request("canAddNotes", params)?;
+        result
+            .as_array()
+            .and_then(|results| results.first())
+            .and_then(Value::as_bool)
+            .ok_or(AnkiConnectError::ParsingError)
+    }
+
+    fn find_notes(&self, query: &str) -> Result<Vec<u64>, AnkiConnectError> {
+        let result = self.send_request("findNotes", json!({ "query": query }))?;
+        result
+            .as_array()
+            .map(|ids| ids.iter().filter_map(Value::as_u64).collect())
+            .ok_or(AnkiConnectError::ParsingError)
+    }
+
+    fn notes_info(&self, note_ids: &[u64]) -> Result<Vec<NoteInfo>, AnkiConnectError> {
+        let result = self.send_request("notesInfo", json!({ "notes": note_ids }))?;
+        result
+            .as_array()
+            .ok_or(AnkiConnectError::ParsingError)?
+            .iter()
+            .map(parse_note_info)
+            .collect()
+    }
+
+    fn gui_browse(&self, query: &str) -> Result<(), AnkiConnectError> {
+        self.send_request("guiBrowse", json!({ "query": query }))?;
+        Ok(())
+    }
+
+    fn get_deck_names(&self) -> Result<Vec<String>, AnkiConnectError> {
+        let result = self.send_request("deckNames", json!({}))?;
+        parse_string_list(&result)
+    }
+
+    fn get_model_names(&self) -> Result<Vec<String>, AnkiConnectError> {
+        let result = self.send_request("modelNames", json!({}))?;
+        parse_string_list(&result)
+    }
+
+    fn get_field_names(&self, model_name: &str) -> Result<Vec<String>, AnkiConnectError> {
+        let params = json!({
+            "modelName": model_name
+        });
+        let result = self.send_request("modelFieldNames", params)?;
+        parse_string_list(&result)
+    }
+
+    fn get_latex_preamble(&self, model_name: &str) -> Result<String, AnkiConnectError> {
+        self.find_model(model_name)?["latexPre"]
+            .as_str()
+            .map(String::from)
+            .ok_or(AnkiConnectError::ParsingError)
+    }
+
+    fn is_cloze_note_type(&self, model_name: &str) -> Result<bool, AnkiConnectError> {
+        // Anki uses the type 1 for cloze note types and 0 for standard ones
+        self.find_model(model_name)?["type"]
+            .as_u64()
+            .map(|model_type| model_type == 1)
+            .ok_or(AnkiConnectError::ParsingError)
+    }
+
+    fn create_deck(&self, deck: &str) -> Result<(), AnkiConnectError> {
+        self.send_request("createDeck", json!({ "deck": deck }))?;
+        Ok(())
+    }
+
+    fn create_note_type(&self, note_type: &NewNoteType) -> Result<(), AnkiConnectError> {
+        self.send_request("createModel", create_model_params(note_type))?;
+        Ok(())
+    }
+}
+
+fn create_request_body(action: &str, params: Value, api_key: Option<&str>) -> Value {
+    let mut request_body = json!({
+        "action": action,
+        "version": API_VERSION,
+        "params": params
+    });
+
+    if let Some(key) = api_key {
+        request_body["key"] = json!(key);
+    }
+
+    request_body
+}
+
+fn parse_response(response: Value) -> Result<Value, AnkiConnectError> {
+    match response.get("error") {
+        Some(Value::String(error)) => Err(classify_error(error)),
+        Some(error) if !error.is_null() => {
+            Err(AnkiConnectError::BadRequestError(error.to_string()))
         }
+        _ => Ok(response["result"].clone()),
+    }
+}
+
+/// Maps the known error messages of AnkiConnect to the matching error variant
+fn classify_error(message: &str) -> AnkiConnectError {
+    if let Some(deck) = message.strip_prefix("deck was not found: ") {
+        AnkiConnectError::DeckNotFound(deck.to_string())
+    } else if let Some(model) = message.strip_prefix("model was not found: ") {
+        AnkiConnectError::ModelNotFound(model.to_string())
+    } else if message == "cannot create note because it is empty" {
+        AnkiConnectError::EmptyNote
+    } else if message == "cannot create note because it is a duplicate" {
+        AnkiConnectError::Duplicate
+    } else if message.to_lowercase().contains("api key") {
+        AnkiConnectError::InvalidApiKey
+    } else {
+        AnkiConnectError::BadRequestError(message.to_string())
     }
 }
 
-pub fn store_file(filepath: &Path) -> Result<(), AnkiConnectError> {
+fn create_store_file_params(filepath: &Path) -> Result<Value, AnkiConnectError> {
     let filename = filepath
         .file_name()
         .and_then(|name| name.to_str())
@@ -77,24 +431,96 @@ pub fn store_file(filepath: &Path) -> Result<(), AnkiConnectError> {
         source: e,
     })?;
 
-    let params = json!({
+    Ok(json!({
         "filename": filename,
         "data":  general_purpose::STANDARD.encode(file_content),
         "deleteExisting": false,
-    });
+    }))
+}
 
-    send_request("storeMediaFile", params)?;
+/// `getMediaFilesNames` matches a glob pattern, so the wildcards of a file name are escaped
+fn escape_media_pattern(filename: &str) -> String {
+    filename
+        .chars()
+        .map(|c| match c {
+            '*' | '?' | '[' => format!("[{}]", c),
+            c => c.to_string(),
+        })
+        .collect()
+}
 
-    // TODO: If there is a file with the same name (highly unlikely), Anki will rename the file.
-    //       The response will contain the name of the renamed file
-    Ok(())
+fn parse_string_list(result: &Value) -> Result<Vec<String>, AnkiConnectError> {
+    result
+        .as_array()
+        .map(|arr| {
+            arr.iter()
+                .filter_map(|v| v.as_str().map(String::from))
+                .collect()
+        })
+        .ok_or_else(|| AnkiConnectError::BadRequestError("Invalid response format".to_string()))
+}
+
+fn parse_note_info(note: &Value) -> Result<NoteInfo, AnkiConnectError> {
+    let note_id = note["noteId"]
+        .as_u64()
+        .ok_or(AnkiConnectError::ParsingError)?;
+    let model_name = note["modelName"].as_str().unwrap_or_default().to_string();
+    let tags = parse_string_list(&note["tags"]).unwrap_or_default();
+
+    let mut fields: Vec<(u64, String, String)> = note["fields"]
+        .as_object()
+        .ok_or(AnkiConnectError::ParsingError)?
+        .iter()
+        .map(|(name, field)| {
+            (
+                field["order"].as_u64().unwrap_or(u64::MAX),
+                name.clone(),
+                field["value"].as_str().unwrap_or_default().to_string(),
+            )
+        })
+        .collect();
+    fields.sort_by_key(|(order, _, _)| *order);
+
+    Ok(NoteInfo {
+        note_id,
+        model_name,
+        tags,
+        fields: fields
+            .into_iter()
+            .map(|(_, name, value)| (name, value))
+            .collect(),
+    })
+}
+
+fn create_can_add_notes_params(
+    deck: &str,
+    note_type: &str,
+    contents: &HashMap<String, String>,
+    scope: DuplicateScope,
+) -> Value {
+    let duplicate_scope = match scope {
+        DuplicateScope::Deck => "deck",
+        DuplicateScope::Collection => "collection",
+    };
+
+    json!({
+        "notes": [{
+            "deckName": deck,
+            "modelName": note_type,
+            "fields": contents,
+            "options": {
+                "allowDuplicate": false,
+                "duplicateScope": duplicate_scope
+            }
+        }]
+    })
 }
 
 fn create_add_note_params(
     deck: &str,
     note_type: &str,
     contents: &HashMap<String, String>,
-    tags: &Vec<String>,
+    tags: &[String],
 ) -> Value {
     json!({
         "note": {
@@ -110,56 +536,26 @@ fn create_add_note_params(
     })
 }
 
-pub fn send_note(
-    deck: &str,
-    note_type: &str,
-    contents: &HashMap<String, String>,
-    tags: &Vec<String>,
-    files: &[&PathBuf],
-) -> Result<(), AnkiConnectError> {
-    files.iter().try_for_each(|file| store_file(file))?;
-    let params = create_add_note_params(deck, note_type, contents, tags);
-    send_request("addNote", params)?;
-    Ok(())
-}
-
-pub fn get_deck_names() -> Result<Vec<String>, AnkiConnectError> {
-    let result = send_request("deckNames", json!({}))?;
-    result
-        .as_array()
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_str().map(String::from))
-                .collect()
-        })
-        .ok_or_else(|| AnkiConnectError::BadRequestError("Invalid response format".to_string()))
-}
-
-pub fn get_model_names() -> Result<Vec<String>, AnkiConnectError> {
-    let result = send_request("modelNames", json!({}))?;
-    result
-        .as_array()
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_str().map(String::from))
-                .collect()
+fn create_model_params(note_type: &NewNoteType) -> Value {
+    let card_templates: Vec<Value> = note_type
+        .card_templates
+        .iter()
+        .map(|template| {
+            json!({
+                "Name": template.name,
+                "Front": template.front,
+                "Back": template.back
+            })
         })
-        .ok_or_else(|| AnkiConnectError::BadRequestError("Invalid response format".to_string()))
-}
+        .collect();
 
-pub fn get_field_names(model_name: &str) -> Result<Vec<String>, AnkiConnectError> {
-    let params = json!({
-        "modelName": model_name
-    });
-    let result = send_request("modelFieldNames", params)?;
-    result
-        .as_array()
-        .map(|arr| {
-            arr.iter()
-                .filter_map(|v| v.as_str().map(String::from))
-                .collect()
-        })
-        .ok_or_else(|| AnkiConnectError::BadRequestError("Invalid response format".to_string()))
+    json!({
+        "modelName": note_type.name,
+        "inOrderFields": note_type.fields,
+        "css": note_type.css,
+        "isCloze": false,
+        "cardTemplates": card_templates
+    })
 }
 
 #[cfg(test)]
@@ -253,4 +649,59 @@ mod tests {
 
         assert_eq!(result, expected);
     }
+
+    #[test]
+    fn test_classify_error() {
+        assert!(matches!(
+            classify_error("deck was not found: Analysis::Chapter 1"),
+            AnkiConnectError::DeckNotFound(deck) if deck == "Analysis::Chapter 1"
+        ));
+        assert!(matches!(
+            classify_error("model was not found: Basic"),
+            AnkiConnectError::ModelNotFound(model) if model == "Basic"
+        ));
+        assert!(matches!(
+            classify_error("cannot create note because it is empty"),
+            AnkiConnectError::EmptyNote
+        ));
+        assert!(matches!(
+            classify_error("cannot create note because it is a duplicate"),
+            AnkiConnectError::Duplicate
+        ));
+        assert!(matches!(
+            classify_error("valid api key must be provided"),
+            AnkiConnectError::InvalidApiKey
+        ));
+        assert!(matches!(
+            classify_error("collection is not available"),
+            AnkiConnectError::BadRequestError(_)
+        ));
+    }
+
+    #[test]
+    fn test_escape_media_pattern() {
+        assert_eq!(escape_media_pattern("page_3.png"), "page_3.png");
+        assert_eq!(escape_media_pattern("a[1]*?.png"), "a[[]1][*][?].png");
+    }
+
+    #[test]
+    fn test_create_request_body_with_api_key() {
+        let result = create_request_body("deckNames", json!({}), Some("secret"));
+
+        let expected = json!({
+            "action": "deckNames",
+            "version": 6,
+            "params": {},
+            "key": "secret"
+        });
+
+        assert_eq!(result, expected);
+    }
+
+    #[test]
+    fn test_create_request_body_without_api_key() {
+        let result = create_request_body("deckNames", json!({}), None);
+
+        assert!(result.get("key").is_none());
+    }
 }
@@ -0,0 +1,284 @@
+use crate::latex_math::{next_token, tokenize, MathDelimiter, TokenKind};
+use anyhow::{anyhow, Result};
+
+/// Marks a cloze deletion in `front.tex`: `\cloze{...}` or `\cloze[2]{...}`.
+/// Deletions without a number are numbered in order of appearance.
+pub const CLOZE_MARKER: &str = r"\cloze";
+
+#[derive(Debug, PartialEq)]
+pub enum ClozeSegment {
+    Text(String),
+    Deletion { number: u32, text: String },
+}
+
+/// Whether `source` uses the cloze marker as a command, outside of comments.
+/// Longer commands like `\clozeinfo` are not markers.
+pub fn has_cloze_markers(source: &str) -> bool {
+    tokenize(source)
+        .iter()
+        .any(|token| token.kind == TokenKind::Other && token.text == CLOZE_MARKER)
+}
+
+/// Splits `source` at the cloze markers. Inside math, the math is closed before
+/// and reopened after a deletion, so that every segment is valid LaTeX on its own.
+/// A marker inside braces or an environment, including math environments like
+/// `align`, is an error, because they can't be closed and reopened.
+pub fn split_cloze_segments(source: &str) -> Result<Vec<ClozeSegment>> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    let mut math: Option<MathDelimiter> = None;
+    // The open braces and environments, as they are described in errors
+    let mut groups: Vec<&str> = Vec::new();
+    let mut reopen_math = false;
+    let mut next_number = 1;
+    let mut rest = source;
+
+    while let Some(token) = next_token(rest) {
+        if reopen_math {
+            reopen_math = false;
+            let delimiter = math.expect("Only reopened inside math");
+            // The deletion was at the end of the math, so it doesn't have to be reopened
+            if token.kind == TokenKind::MathDelimiter && token.text == delimiter.close {
+                math = None;
+                rest = &rest[token.text.len()..];
+                continue;
+            }
+            text.push_str(delimiter.open);
+        }
+        rest = &rest[token.text.len()..];
+
+        match token.kind {
+            TokenKind::Other if token.text == CLOZE_MARKER => {
+                if let Some(group) = groups.last() {
+                    return Err(anyhow!(
+                        "{} can't be inside {}, only in text or in math between delimiters",
+                        CLOZE_MARKER,
+                        group
+                    ));
+                }
+                let (number, content, after_deletion) = parse_deletion(rest)?;
+                let number = number.unwrap_or(next_number);
+                next_number = next_number.max(number + 1);
+
+                let deletion = match math {
+                    Some(delimiter) => {
+                        match text.strip_suffix(delimiter.open) {
+                            Some(before_open) => text.truncate(before_open.len()),
+                            None => text.push_str(delimiter.close),
+                        }
+                        reopen_math = true;
+                        format!("{}{}{}", delimiter.open, content, delimiter.close)
+                    }
+                    None => content.to_string(),
+                };
+
+                push_text(&mut segments, &mut text);
+                segments.push(ClozeSegment::Deletion {
+                    number,
+                    text: deletion,
+                });
+                rest = after_deletion;
+                continue;
+            }
+            TokenKind::MathDelimiter => match math {
+                Some(delimiter) if token.text == delimiter.close => math = None,
+                Some(_) => {}
+                None => math = token.opened_math(),
+            },
+            TokenKind::OpenBrace => groups.push("braces"),
+            TokenKind::CloseBrace => {
+                if groups.last() == Some(&"braces") {
+                    groups.pop();
+                }
+            }
+            TokenKind::Begin => groups.push(token.text),
+            TokenKind::End => {
+                if groups
+                    .last()
+                    .is_some_and(|group| group.starts_with(r"\begin{"))
+                {
+                    groups.pop();
+                }
+            }
+            TokenKind::Comment | TokenKind::Other => {}
+        }
+        text.push_str(token.text);
+    }
+
+    push_text(&mut segments, &mut text);
+    Ok(segments)
+}
+
+fn push_text(segments: &mut Vec<ClozeSegment>, text: &mut String) {
+    if !text.is_empty() {
+        segments.push(ClozeSegment::Text(std::mem::take(text)));
+    }
+}
+
+/// Parses `[number]{content}` after a cloze marker.
+/// Returns the number, the content and the text after the closing brace.
+fn parse_deletion(source: &str) -> Result<(Option<u32>, &str, &str)> {
+    let (number, source) = match source.strip_prefix('[') {
+        Some(rest) => {
+            let (number, rest) = rest
+                .split_once(']')
+                .ok_or_else(|| anyhow!("Missing ] after {}[", CLOZE_MARKER))?;
+            let number = number
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid cloze number: {}", number))?;
+            (Some(number), rest)
+        }
+        None => (None, source),
+    };
+
+    let content = source
+        .strip_prefix('{')
+        .ok_or_else(|| anyhow!("Missing {{ after {}", CLOZE_MARKER))?;
+
+    let mut depth = 1;
+    let mut escaped = false;
+    for (index, c) in content.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    let (content, after) = (&content[..index], &content[index + 1..]);
+                    // Anki would start the hint of the deletion at the first `::`
+                    if content.contains("::") {
+                        return Err(anyhow!(
+                            ":: can't be inside {}{{...}}, because Anki reads it as a hint",
+                            CLOZE_MARKER
+                        ));
+                    }
+                    return Ok((number, content, after));
+                }
+            }
+            _ => {}
+        }
+    }
+    Err(anyhow!("Missing }} to close {}", CLOZE_MARKER))
+}
+
+/// Turns the segments into the text of a Cloze field, with every segment in its own
+/// `[latex]` block. Segments that are only whitespace are kept as they are.
+pub fn create_cloze_text(segments: &[ClozeSegment]) -> String {
+    segments
+        .iter()
+        .map(|segment| match segment {
+            ClozeSegment::Text(text) if text.trim().is_empty() => text.clone(),
+            ClozeSegment::Text(text) => format!("[latex]{}[/latex]", text),
+            ClozeSegment::Deletion { number, text } => {
+                // Anki would end the deletion at the first `}}`
+                let mut text = text.clone();
+                while text.contains("}}") {
+                    text = text.replace("}}", "} }");
+                }
+                format!("{{{{c{}::[latex]{}[/latex]}}}}", number, text)
+            }
+        })
+        .collect()
+}
+
+/// Recovers `front.tex` from the text of a Cloze field created by [`create_cloze_text`].
+/// Deletions become `\cloze[number]{...}`, which renders the same as the original source.
+pub fn extract_cloze_source(cloze_text: &str) -> String {
+    let mut source = String::new();
+    let mut rest = cloze_text;
+
+    while let Some(start) = rest.find("{{c") {
+        source.push_str(&rest[..start]);
+        let deletion = &rest[start + 3..];
+
+        let parsed = deletion.split_once("::").and_then(|(number, content)| {
+            let number: u32 = number.parse().ok()?;
+            let end = content.find("}}")?;
+            Some((number, &content[..end], &content[end + 2..]))
+        });
+        match parsed {
+            Some((number, content, after)) => {
+                let content = content
+                    .strip_prefix("[latex]")
+                    .and_then(|c| c.strip_suffix("[/latex]"))
+                    .unwrap_or(content);
+                source.push_str(&format!("{}[{}]{{{}}}", CLOZE_MARKER, number, content));
+                rest = after;
+            }
+            None => {
+                source.push_str("{{c");
+                rest = deletion;
+            }
+        }
+    }
+    source.push_str(rest);
+
+    source.replace("[latex]", "").replace("[/latex]", "")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cloze_in_text_and_math() {
+        let source =
+            r"A \cloze{group} has $e \cdot g = \cloze[3]{g}$ and $\cloze{\sqrt{\mathbb{R}}}$.";
+
+        let segments = split_cloze_segments(source).unwrap();
+
+        assert_eq!(
+            create_cloze_text(&segments),
+            concat!(
+                "[latex]A [/latex]{{c1::[latex]group[/latex]}}",
+                r"[latex] has $e \cdot g = $[/latex]{{c3::[latex]$g$[/latex]}}",
+                r"[latex] and [/latex]{{c4::[latex]$\sqrt{\mathbb{R} }$[/latex]}}[latex].[/latex]"
+            )
+        );
+    }
+
+    #[test]
+    fn test_extract_cloze_source() {
+        let source = r"Let $x = \cloze[1]{2}$ in \cloze[2]{$\mathbb{R}$}";
+        let cloze_text = create_cloze_text(&split_cloze_segments(source).unwrap());
+
+        let extracted = extract_cloze_source(&cloze_text);
+
+        assert_eq!(
+            extracted,
+            r"Let $x = $\cloze[1]{$2$} in \cloze[2]{$\mathbb{R}$}"
+        );
+        assert_eq!(
+            create_cloze_text(&split_cloze_segments(&extracted).unwrap()),
+            cloze_text
+        );
+    }
+
+    #[test]
+    fn test_cloze_in_display_math_and_groups() {
+        let segments = split_cloze_segments(r"\[ a = \cloze{b} + c \]").unwrap();
+
+        assert_eq!(
+            create_cloze_text(&segments),
+            r"[latex]\[ a = \][/latex]{{c1::[latex]\[b\][/latex]}}[latex]\[ + c \][/latex]"
+        );
+        assert!(split_cloze_segments(r"\begin{align} x &= \cloze{1} \end{align}").is_err());
+        assert!(split_cloze_segments(r"\textbf{\cloze{x}}").is_err());
+        assert!(split_cloze_segments(r"$\frac{\cloze{1}}{2}$").is_err());
+    }
+
+    #[test]
+    fn test_unclosed_cloze_marker() {
+        assert!(split_cloze_segments(r"\cloze{x").is_err());
+        assert!(split_cloze_segments(r"\cloze{a::b}").is_err());
+    }
+
+    #[test]
+    fn test_has_cloze_markers() {
+        assert!(has_cloze_markers(r"A \cloze{group}"));
+        assert!(has_cloze_markers(r"$\cloze[2]{x}$"));
+        assert!(!has_cloze_markers(r"\clozeinfo{x} % \cloze{y}"));
+    }
+}
@@ -0,0 +1,136 @@
+use crate::anki_backend::AnkiBackend;
+use crate::anki_config::AnkiConfig;
+use crate::ankiconnect::AnkiConnectError;
+use crate::possible_entries::PossibleContent;
+
+/// Differences between a stored [`AnkiConfig`] and the current Anki collection
+#[derive(Debug, Default, PartialEq)]
+pub struct StaleSettings {
+    pub deck_missing: bool,
+    pub note_type_missing: bool,
+    /// Mapped fields that the note type doesn't have anymore
+    pub removed_fields: Vec<String>,
+    /// Fields of the note type that are not mapped yet
+    pub new_fields: Vec<String>,
+    /// The current fields of the note type, in order
+    pub field_names: Vec<String>,
+}
+
+impl StaleSettings {
+    pub fn is_empty(&self) -> bool {
+        !self.deck_missing
+            && !self.note_type_missing
+            && self.removed_fields.is_empty()
+            && self.new_fields.is_empty()
+    }
+
+    pub fn describe(&self, config: &AnkiConfig) -> Vec<String> {
+        let mut lines = Vec::new();
+        if self.deck_missing {
+            lines.push(format!("The deck \"{}\" doesn't exist", config.deck_name));
+        }
+        if self.note_type_missing {
+            lines.push(format!(
+                "The note type \"{}\" doesn't exist",
+                config.note_type
+            ));
+        }
+        for field in &self.removed_fields {
+            lines.push(format!("The field \"{}\" was removed or renamed", field));
+        }
+        for field in &self.new_fields {
+            lines.push(format!("The field \"{}\" is new", field));
+        }
+        lines
+    }
+}
+
+pub fn find_stale_settings(
+    anki: &dyn AnkiBackend,
+    config: &AnkiConfig,
+) -> Result<StaleSettings, AnkiConnectError> {
+    let deck_missing = !anki.get_deck_names()?.contains(&config.deck_name);
+    let note_type_missing = !anki.get_model_names()?.contains(&config.note_type);
+
+    let mut stale_settings = StaleSettings {
+        deck_missing,
+        note_type_missing,
+        ..Default::default()
+    };
+
+    if !note_type_missing {
+        let field_names = anki.get_field_names(&config.note_type)?;
+        let mapped_names: Vec<&String> =
+            config.field_mapping.iter().map(|(name, _)| name).collect();
+
+        stale_settings.removed_fields = mapped_names
+            .iter()
+            .filter(|name| !field_names.contains(name))
+            .map(|name| name.to_string())
+            .collect();
+        stale_settings.new_fields = field_names
+            .iter()
+            .filter(|name| !mapped_names.contains(name))
+            .cloned()
+            .collect();
+        stale_settings.field_names = field_names;
+    }
+
+    Ok(stale_settings)
+}
+
+/// Updates the field mapping to the current fields of the note type.
+/// Existing mappings are kept, new fields are mapped to [`PossibleContent::Empty`]
+/// and fields that don't exist anymore are dropped, together with their math format.
+/// A renamed field is a new field, as the note type doesn't tell its old name.
+pub fn update_field_mapping(anki_config: &mut AnkiConfig, field_names: &[String]) {
+    let field_mapping = field_names
+        .iter()
+        .map(|field_name| {
+            let content = anki_config
+                .field_mapping
+                .iter()
+                .find(|(name, _)| name == field_name)
+                .map(|(_, content)| content.clone())
+                .unwrap_or(PossibleContent::Empty);
+            (field_name.clone(), content)
+        })
+        .collect();
+    anki_config.set_field_mapping(field_mapping);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math_format::MathFormat;
+
+    #[test]
+    fn test_update_field_mapping() {
+        let field_mapping = vec![
+            ("Front".to_string(), PossibleContent::Front),
+            ("Old".to_string(), PossibleContent::Back),
+            ("Page".to_string(), PossibleContent::PageNumber),
+        ];
+        let field_names = vec!["Front".to_string(), "New".to_string(), "Page".to_string()];
+        let mut anki_config = AnkiConfig::new("Deck", "Basic", field_mapping);
+        anki_config
+            .math_formats
+            .insert("Front".to_string(), MathFormat::MathJax);
+        anki_config
+            .math_formats
+            .insert("Old".to_string(), MathFormat::Raw);
+
+        update_field_mapping(&mut anki_config, &field_names);
+
+        assert_eq!(
+            anki_config.field_mapping,
+            vec![
+                ("Front".to_string(), PossibleContent::Front),
+                ("New".to_string(), PossibleContent::Empty),
+                ("Page".to_string(), PossibleContent::PageNumber),
+            ]
+        );
+        assert_eq!(anki_config.math_format("Front"), MathFormat::MathJax);
+        assert!(!anki_config.math_formats.contains_key("Old"));
+    }
+}
@@ -0,0 +1,148 @@
+use crate::anki_backend::{AnkiBackend, NoteInfo};
+use crate::anki_config::{AnkiConfig, DuplicateScope};
+use crate::anki_search::search_term;
+use crate::ankiconnect::AnkiConnectError;
+use crate::possible_entries::PossibleContent;
+use std::collections::HashMap;
+
+/// Existing notes that look like the note that is about to be added
+pub struct SimilarNotes {
+    /// Anki itself considers the note a duplicate, because the first field is already used
+    pub first_field_duplicate: bool,
+    /// Notes with the same first field or the same source file and page
+    pub notes: Vec<NoteInfo>,
+}
+
+impl SimilarNotes {
+    pub fn is_empty(&self) -> bool {
+        !self.first_field_duplicate && self.notes.is_empty()
+    }
+}
+
+pub fn find_similar_notes(
+    anki: &dyn AnkiBackend,
+    config: &AnkiConfig,
+    contents: &HashMap<String, String>,
+) -> Result<SimilarNotes, AnkiConnectError> {
+    let can_add = anki.can_add_note(
+        &config.deck_name,
+        &config.note_type,
+        contents,
+        config.duplicate_scope,
+    )?;
+
+    let mut note_ids: Vec<u64> = Vec::new();
+    for query in create_similarity_queries(config, contents) {
+        for note_id in anki.find_notes(&query)? {
+            if !note_ids.contains(&note_id) {
+                note_ids.push(note_id);
+            }
+        }
+    }
+
+    let notes = if note_ids.is_empty() {
+        Vec::new()
+    } else {
+        anki.notes_info(&note_ids)?
+    };
+
+    Ok(SimilarNotes {
+        first_field_duplicate: !can_add,
+        notes,
+    })
+}
+
+/// Creates the Anki search queries for notes with the same first field
+/// and for notes with the same source file and page.
+pub fn create_similarity_queries(
+    config: &AnkiConfig,
+    contents: &HashMap<String, String>,
+) -> Vec<String> {
+    let scope = match config.duplicate_scope {
+        DuplicateScope::Deck => vec![search_term("deck", &config.deck_name)],
+        DuplicateScope::Collection => Vec::new(),
+    };
+
+    let mut queries = Vec::with_capacity(2);
+
+    if let Some((first_field, _)) = config.field_mapping.first() {
+        if let Some(value) = non_empty_content(contents, first_field) {
+            let mut terms = scope.clone();
+            terms.push(search_term("note", &config.note_type));
+            terms.push(search_term(first_field, value));
+            queries.push(terms.join(" "));
+        }
+    }
+
+    let file_field = config.field_for_content(&PossibleContent::FileName);
+    let page_field = config.field_for_content(&PossibleContent::PageNumber);
+    if let (Some(file_field), Some(page_field)) = (file_field, page_field) {
+        let file = non_empty_content(contents, file_field);
+        let page = non_empty_content(contents, page_field);
+        if let (Some(file), Some(page)) = (file, page) {
+            let mut terms = scope;
+            terms.push(search_term(file_field, file));
+            terms.push(search_term(page_field, page));
+            queries.push(terms.join(" "));
+        }
+    }
+
+    queries
+}
+
+fn non_empty_content<'a>(contents: &'a HashMap<String, String>, field: &str) -> Option<&'a str> {
+    contents
+        .get(field)
+        .map(String::as_str)
+        .filter(|value| !value.trim().is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_config(scope: DuplicateScope) -> AnkiConfig {
+        let field_mapping = vec![
+            ("Front".to_string(), PossibleContent::Front),
+            ("Source".to_string(), PossibleContent::FileName),
+            ("Page".to_string(), PossibleContent::PageNumber),
+        ];
+        AnkiConfig {
+            duplicate_scope: scope,
+            ..AnkiConfig::new("Analysis", "Basic", field_mapping)
+        }
+    }
+
+    #[test]
+    fn test_similarity_queries_in_deck() {
+        let contents = HashMap::from([
+            ("Front".to_string(), "[latex]$x_1$[/latex]".to_string()),
+            ("Source".to_string(), "book.pdf".to_string()),
+            ("Page".to_string(), "42".to_string()),
+        ]);
+
+        let queries = create_similarity_queries(&create_config(DuplicateScope::Deck), &contents);
+
+        assert_eq!(
+            queries,
+            vec![
+                r#""deck:Analysis" "note:Basic" "Front:[latex]$x\_1$[/latex]""#,
+                r#""deck:Analysis" "Source:book.pdf" "Page:42""#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_similarity_queries_skip_empty_fields() {
+        let contents = HashMap::from([
+            ("Front".to_string(), "Theorem \"A\"".to_string()),
+            ("Source".to_string(), "book.pdf".to_string()),
+            ("Page".to_string(), "".to_string()),
+        ]);
+
+        let queries =
+            create_similarity_queries(&create_config(DuplicateScope::Collection), &contents);
+
+        assert_eq!(queries, vec![r#""note:Basic" "Front:Theorem \"A\"""#]);
+    }
+}
@@ -0,0 +1,215 @@
+use anyhow::{anyhow, Result};
+use screenshots::image::imageops::{self, FilterType};
+use screenshots::image::{DynamicImage, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// How much a channel may differ from the margin colour to still count as margin
+const TRIM_TOLERANCE: u8 = 16;
+
+/// An operation applied to the screenshot before it is saved
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum ImageOperation {
+    /// Removes margins of the colour of the top left pixel
+    Trim,
+    /// Inverts the colours, e.g. for reviewing in dark mode
+    Invert,
+    Grayscale,
+    /// Scales the image down to at most this width, keeping the aspect ratio
+    MaxWidth(u32),
+    /// Scales the image down to at most this height, keeping the aspect ratio
+    MaxHeight(u32),
+}
+
+impl FromStr for ImageOperation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let size = |value: &str| {
+            value
+                .parse()
+                .map_err(|_| anyhow!("Invalid size \"{}\" in \"{}\"", value, s))
+        };
+
+        match s.trim().split_once('=') {
+            None if s.trim() == "trim" => Ok(ImageOperation::Trim),
+            None if s.trim() == "invert" => Ok(ImageOperation::Invert),
+            None if s.trim() == "grayscale" => Ok(ImageOperation::Grayscale),
+            Some(("max-width", width)) => Ok(ImageOperation::MaxWidth(size(width)?)),
+            Some(("max-height", height)) => Ok(ImageOperation::MaxHeight(size(height)?)),
+            _ => Err(anyhow!(
+                "Invalid image operation \"{}\", expected trim, invert, grayscale, \
+                 max-width=<pixels> or max-height=<pixels>",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ImageOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageOperation::Trim => write!(f, "trim"),
+            ImageOperation::Invert => write!(f, "invert"),
+            ImageOperation::Grayscale => write!(f, "grayscale"),
+            ImageOperation::MaxWidth(width) => write!(f, "max-width={}", width),
+            ImageOperation::MaxHeight(height) => write!(f, "max-height={}", height),
+        }
+    }
+}
+
+impl TryFrom<String> for ImageOperation {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<ImageOperation> for String {
+    fn from(operation: ImageOperation) -> Self {
+        operation.to_string()
+    }
+}
+
+/// Parses a comma separated list of operations. An empty list disables the processing.
+pub fn parse_operations(s: &str) -> Result<Vec<ImageOperation>> {
+    s.split(',')
+        .filter(|operation| !operation.trim().is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Applies the operations in order
+pub fn process_image(image: RgbaImage, operations: &[ImageOperation]) -> RgbaImage {
+    operations.iter().fold(image, apply_operation)
+}
+
+fn apply_operation(mut image: RgbaImage, operation: &ImageOperation) -> RgbaImage {
+    match *operation {
+        ImageOperation::Trim => trim_margins(&image),
+        ImageOperation::Invert => {
+            imageops::invert(&mut image);
+            image
+        }
+        ImageOperation::Grayscale => DynamicImage::ImageRgba8(image).grayscale().to_rgba8(),
+        ImageOperation::MaxWidth(max_width) => limit_size(image, max_width.max(1), u32::MAX),
+        ImageOperation::MaxHeight(max_height) => limit_size(image, u32::MAX, max_height.max(1)),
+    }
+}
+
+fn trim_margins(image: &RgbaImage) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return image.clone();
+    }
+
+    let margin = *image.get_pixel(0, 0);
+    let is_margin = |pixel: &Rgba<u8>| {
+        pixel
+            .0
+            .iter()
+            .zip(margin.0)
+            .all(|(channel, margin)| channel.abs_diff(margin) <= TRIM_TOLERANCE)
+    };
+    let row_is_margin = |y: u32| (0..width).all(|x| is_margin(image.get_pixel(x, y)));
+    let column_is_margin =
+        |x: u32, top: u32, bottom: u32| (top..bottom).all(|y| is_margin(image.get_pixel(x, y)));
+
+    let Some(top) = (0..height).find(|&y| !row_is_margin(y)) else {
+        // The whole image is margin
+        return image.clone();
+    };
+    let bottom = (0..height)
+        .rev()
+        .find(|&y| !row_is_margin(y))
+        .unwrap_or(top)
+        + 1;
+    let left = (0..width)
+        .find(|&x| !column_is_margin(x, top, bottom))
+        .unwrap_or(0);
+    let right = (0..width)
+        .rev()
+        .find(|&x| !column_is_margin(x, top, bottom))
+        .unwrap_or(left)
+        + 1;
+
+    imageops::crop_imm(image, left, top, right - left, bottom - top).to_image()
+}
+
+fn limit_size(image: RgbaImage, max_width: u32, max_height: u32) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    if width <= max_width && height <= max_height {
+        return image;
+    }
+
+    let scale = (max_width as f64 / width as f64).min(max_height as f64 / height as f64);
+    let new_width = ((width as f64 * scale).round() as u32).max(1);
+    let new_height = ((height as f64 * scale).round() as u32).max(1);
+    imageops::resize(&image, new_width, new_height, FilterType::Lanczos3)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: Rgba<u8> = Rgba([255, 255, 255, 255]);
+    const BLACK: Rgba<u8> = Rgba([0, 0, 0, 255]);
+
+    /// A white 6x4 image with a dark 2x1 block at (2, 1)
+    fn create_fixture() -> RgbaImage {
+        let mut image = RgbaImage::from_pixel(6, 4, WHITE);
+        image.put_pixel(2, 1, BLACK);
+        image.put_pixel(3, 1, Rgba([10, 10, 10, 255]));
+        image
+    }
+
+    #[test]
+    fn test_trim_and_invert() {
+        let trimmed = process_image(create_fixture(), &[ImageOperation::Trim]);
+        assert_eq!(trimmed.dimensions(), (2, 1));
+
+        let inverted = process_image(create_fixture(), &[ImageOperation::Invert]);
+        assert_eq!(*inverted.get_pixel(0, 0), BLACK);
+        assert_eq!(*inverted.get_pixel(2, 1), WHITE);
+
+        let uniform = RgbaImage::from_pixel(3, 3, WHITE);
+        assert_eq!(
+            process_image(uniform, &[ImageOperation::Trim]).dimensions(),
+            (3, 3)
+        );
+    }
+
+    #[test]
+    fn test_grayscale_and_max_size() {
+        let mut image = RgbaImage::from_pixel(8, 4, Rgba([255, 0, 0, 128]));
+        image.put_pixel(0, 0, WHITE);
+
+        let processed = process_image(
+            image,
+            &[
+                ImageOperation::Grayscale,
+                ImageOperation::MaxWidth(4),
+                ImageOperation::MaxHeight(10),
+            ],
+        );
+
+        assert_eq!(processed.dimensions(), (4, 2));
+        let pixel = processed.get_pixel(3, 1);
+        assert!(pixel[0] == pixel[1] && pixel[1] == pixel[2]);
+        assert_eq!(pixel[3], 128);
+    }
+
+    #[test]
+    fn test_parse_operations() {
+        assert_eq!(
+            parse_operations("trim, max-width=800").unwrap(),
+            vec![ImageOperation::Trim, ImageOperation::MaxWidth(800)]
+        );
+        assert_eq!(parse_operations("").unwrap(), vec![]);
+        assert!(parse_operations("trim,sharpen").is_err());
+        assert!(parse_operations("max-height=tall").is_err());
+    }
+}
@@ -0,0 +1,253 @@
+use crate::latex_math::{tokenize, TokenKind};
+use crate::latex_preamble::{find_undefined_commands, find_used_commands};
+
+/// Closing tags that would end the wrapping of the field early
+const CLOSING_TAGS: [&str; 3] = ["[/latex]", "[/$$]", "[/$]"];
+
+/// A problem found in `front.tex` or `back.tex`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintProblem {
+    /// Line of the problem, starting at 1
+    pub line: usize,
+    pub message: String,
+}
+
+/// Checks `latex` for mistakes that would break the LaTeX images in Anki:
+/// unbalanced braces and environments, unclosed or mismatched math, closing tags of the
+/// field wrapping, and, if a preamble is given, commands that neither it nor the packages it
+/// loads define (see [`find_undefined_commands`]). The problems are sorted by line.
+pub fn lint_latex(latex: &str, preamble: Option<&str>) -> Vec<LintProblem> {
+    let mut problems = check_structure(latex);
+
+    for (index, line) in latex.lines().enumerate() {
+        let code = strip_comment(line);
+        for tag in CLOSING_TAGS {
+            // [/$] is part of [/$$]
+            if code.contains(tag) && !(tag == "[/$]" && code.contains("[/$$]")) {
+                problems.push(problem(index + 1, format!("{} ends the field early", tag)));
+            }
+        }
+    }
+
+    if let Some(preamble) = preamble {
+        for command in find_undefined_commands(latex, preamble) {
+            let line = latex
+                .lines()
+                .position(|line| find_used_commands(line).contains(&command))
+                .map_or(1, |index| index + 1);
+            problems.push(problem(
+                line,
+                format!("\\{} is not defined in the preamble", command),
+            ));
+        }
+    }
+
+    problems.sort_by_key(|problem| problem.line);
+    problems
+}
+
+/// An open brace, environment or math, with the line where it was opened
+enum Group<'a> {
+    Brace,
+    Environment(&'a str),
+    Math { open: &'a str, close: &'a str },
+}
+
+/// Checks that braces, environments and math are closed in the order they were opened
+fn check_structure(latex: &str) -> Vec<LintProblem> {
+    let mut problems = Vec::new();
+    let mut open_groups: Vec<(Group, usize)> = Vec::new();
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(latex.match_indices('\n').map(|(index, _)| index + 1))
+        .collect();
+
+    for token in tokenize(latex) {
+        let line_number = line_starts.partition_point(|&start| start <= token.start);
+        let in_math = open_groups.iter().rev().find_map(|(group, _)| match group {
+            Group::Math { close, .. } => Some(*close),
+            _ => None,
+        });
+
+        match token.kind {
+            TokenKind::Begin => {
+                let name = token.environment().expect("A \\begin token");
+                open_groups.push((Group::Environment(name), line_number));
+            }
+            TokenKind::End => {
+                let name = token.environment().expect("An \\end token");
+                let closes =
+                    |group: &Group| matches!(group, Group::Environment(open) if *open == name);
+                match open_groups
+                    .iter()
+                    .rposition(|(group, _)| !matches!(group, Group::Brace))
+                {
+                    Some(position) if closes(&open_groups[position].0) => {
+                        report_unclosed(&mut problems, open_groups.drain(position + 1..));
+                        open_groups.pop();
+                    }
+                    Some(position) => {
+                        let (group, opened) = &open_groups[position];
+                        problems.push(problem(
+                            line_number,
+                            format!(
+                                "\\end{{{}}} closes {} from line {}",
+                                name,
+                                describe(group),
+                                opened
+                            ),
+                        ));
+                    }
+                    None => problems.push(problem(
+                        line_number,
+                        format!("\\end{{{}}} without \\begin{{{}}}", name, name),
+                    )),
+                }
+            }
+            TokenKind::OpenBrace => open_groups.push((Group::Brace, line_number)),
+            TokenKind::CloseBrace => match open_groups.last() {
+                Some((Group::Brace, _)) => {
+                    open_groups.pop();
+                }
+                _ => problems.push(problem(line_number, "} without {".to_string())),
+            },
+            TokenKind::MathDelimiter if in_math == Some(token.text) => {
+                let position = open_groups
+                    .iter()
+                    .rposition(|(group, _)| matches!(group, Group::Math { .. }))
+                    .expect("Inside math");
+                report_unclosed(&mut problems, open_groups.drain(position + 1..));
+                open_groups.pop();
+            }
+            TokenKind::MathDelimiter => match (token.opened_math(), in_math) {
+                (Some(_), Some(math_close)) => problems.push(problem(
+                    line_number,
+                    format!(
+                        "{} inside math that is closed with {}",
+                        token.text, math_close
+                    ),
+                )),
+                (Some(delimiter), None) => open_groups.push((
+                    Group::Math {
+                        open: delimiter.open,
+                        close: delimiter.close,
+                    },
+                    line_number,
+                )),
+                (None, _) => problems.push(problem(
+                    line_number,
+                    format!("{} without opening it", token.text),
+                )),
+            },
+            // Escaped characters like \{, \$ or \\ are single tokens, so they don't open or
+            // close anything
+            TokenKind::Comment | TokenKind::Other => {}
+        }
+    }
+
+    report_unclosed(&mut problems, open_groups.drain(..));
+    problems
+}
+
+fn report_unclosed<'a>(
+    problems: &mut Vec<LintProblem>,
+    groups: impl Iterator<Item = (Group<'a>, usize)>,
+) {
+    for (group, line) in groups {
+        problems.push(problem(line, format!("{} is not closed", describe(&group))));
+    }
+}
+
+fn describe(group: &Group) -> String {
+    match group {
+        Group::Brace => "{".to_string(),
+        Group::Environment(name) => format!("\\begin{{{}}}", name),
+        Group::Math { open, .. } => open.to_string(),
+    }
+}
+
+/// Removes a `%` comment, but not an escaped `\%`
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            '%' if !escaped => return &line[..index],
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    line
+}
+
+fn problem(line: usize, message: String) -> LintProblem {
+    LintProblem { line, message }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lint(latex: &str) -> Vec<(usize, String)> {
+        lint_latex(latex, None)
+            .into_iter()
+            .map(|problem| (problem.line, problem.message))
+            .collect()
+    }
+
+    #[test]
+    fn test_valid_latex_has_no_problems() {
+        let latex = "Let $a \\in \\{1, 2\\}$ and \\$5 % a comment with { and $\n\
+                     \\begin{align}\n  x &= \\frac{1}{2} \\\\\n  y &= 3\n\\end{align}\n\
+                     $$\\sum_{i=1}^n i$$ and \\(z\\) in \\[w\\]";
+
+        assert_eq!(lint(latex), vec![]);
+    }
+
+    #[test]
+    fn test_structure_problems() {
+        let latex = "\\frac{1}{2\n$x + 1\n\\begin{proof}\n\\end{theorem}\n\\]\n[/latex]";
+
+        assert_eq!(
+            lint(latex),
+            vec![
+                (1, "{ is not closed".to_string()),
+                (2, "$ is not closed".to_string()),
+                (3, "\\begin{proof} is not closed".to_string()),
+                (
+                    4,
+                    "\\end{theorem} closes \\begin{proof} from line 3".to_string()
+                ),
+                (5, "\\] without opening it".to_string()),
+                (6, "[/latex] ends the field early".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_undefined_commands_with_line() {
+        let latex = "First line\nUses \\R and \\alpha";
+
+        assert_eq!(
+            lint_latex(latex, Some("\\newcommand{\\N}{\\mathbb{N}}")),
+            vec![LintProblem {
+                line: 2,
+                message: "\\R is not defined in the preamble".to_string()
+            }]
+        );
+        assert!(lint_latex(latex, None).is_empty());
+    }
+
+    #[test]
+    fn test_commands_of_loaded_packages_are_not_problems() {
+        let latex = "$x \\coloneqq \\tikz$ \\qedhere";
+        let preamble = "\\usepackage{amsthm,mathtools}\n\\usepackage[all]{tikz}";
+
+        assert!(lint_latex(latex, Some(preamble)).is_empty());
+        assert_eq!(
+            lint_latex(latex, Some("\\usepackage{amsthm,mathtools}")),
+            vec![problem(
+                1,
+                "\\tikz is not defined in the preamble".to_string()
+            )]
+        );
+    }
+}
@@ -0,0 +1,314 @@
+/// A pair of delimiters that enclose math, like `$...$` or `\[...\]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MathDelimiter {
+    pub open: &'static str,
+    pub close: &'static str,
+    pub display: bool,
+}
+
+/// Delimiters of math in LaTeX, longer ones first
+pub const MATH_DELIMITERS: [MathDelimiter; 4] = [
+    MathDelimiter {
+        open: "$$",
+        close: "$$",
+        display: true,
+    },
+    MathDelimiter {
+        open: "$",
+        close: "$",
+        display: false,
+    },
+    MathDelimiter {
+        open: r"\[",
+        close: r"\]",
+        display: true,
+    },
+    MathDelimiter {
+        open: r"\(",
+        close: r"\)",
+        display: false,
+    },
+];
+
+/// Environments that are display math on their own, without delimiters
+pub const MATH_ENVIRONMENTS: [&str; 15] = [
+    "equation",
+    "equation*",
+    "align",
+    "align*",
+    "alignat",
+    "alignat*",
+    "flalign",
+    "flalign*",
+    "gather",
+    "gather*",
+    "multline",
+    "multline*",
+    "eqnarray",
+    "eqnarray*",
+    "displaymath",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenKind {
+    /// `\begin{name}`
+    Begin,
+    /// `\end{name}`
+    End,
+    /// One of the delimiters of [`MATH_DELIMITERS`]
+    MathDelimiter,
+    OpenBrace,
+    CloseBrace,
+    /// A `%` comment up to the end of the line
+    Comment,
+    /// A command like `\frac`, an escaped character like `\$` or `\\`, or any other character
+    Other,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Token<'a> {
+    pub kind: TokenKind,
+    pub text: &'a str,
+    /// Byte offset of the token in the scanned text
+    pub start: usize,
+}
+
+impl<'a> Token<'a> {
+    pub fn end(&self) -> usize {
+        self.start + self.text.len()
+    }
+
+    /// The name of the environment of a [`TokenKind::Begin`] or [`TokenKind::End`] token
+    pub fn environment(&self) -> Option<&'a str> {
+        let prefix = match self.kind {
+            TokenKind::Begin => r"\begin{",
+            TokenKind::End => r"\end{",
+            _ => return None,
+        };
+        self.text.strip_prefix(prefix)?.strip_suffix('}')
+    }
+
+    /// The delimiters that this token opens math with
+    pub fn opened_math(&self) -> Option<MathDelimiter> {
+        match self.kind {
+            TokenKind::MathDelimiter => MATH_DELIMITERS
+                .iter()
+                .find(|delimiter| delimiter.open == self.text)
+                .copied(),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the first token of `latex`, starting at offset 0
+pub fn next_token(latex: &str) -> Option<Token<'_>> {
+    let c = latex.chars().next()?;
+    let token = |kind, len: usize| Token {
+        kind,
+        text: &latex[..len],
+        start: 0,
+    };
+
+    for (prefix, kind) in [(r"\begin{", TokenKind::Begin), (r"\end{", TokenKind::End)] {
+        if let Some(end) = latex
+            .strip_prefix(prefix)
+            .and_then(|after| after.find(['}', '\n']))
+            .filter(|end| latex[prefix.len() + end..].starts_with('}'))
+        {
+            return Some(token(kind, prefix.len() + end + 1));
+        }
+    }
+
+    // The opening and closing delimiters, longer ones first
+    let delimiter = MATH_DELIMITERS
+        .iter()
+        .flat_map(|delimiter| [delimiter.open, delimiter.close])
+        .find(|delimiter| latex.starts_with(delimiter));
+    if let Some(delimiter) = delimiter {
+        return Some(token(TokenKind::MathDelimiter, delimiter.len()));
+    }
+
+    Some(match c {
+        '%' => token(TokenKind::Comment, latex.find('\n').unwrap_or(latex.len())),
+        '{' => token(TokenKind::OpenBrace, 1),
+        '}' => token(TokenKind::CloseBrace, 1),
+        '\\' => {
+            let after = &latex[1..];
+            let name_len = after
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(after.len());
+            let len = match (name_len, after.chars().next()) {
+                (0, Some(escaped)) => escaped.len_utf8(),
+                _ => name_len,
+            };
+            token(TokenKind::Other, 1 + len)
+        }
+        c => token(TokenKind::Other, c.len_utf8()),
+    })
+}
+
+/// Splits `latex` into tokens
+pub fn tokenize(latex: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut start = 0;
+    while let Some(token) = next_token(&latex[start..]) {
+        tokens.push(Token { start, ..token });
+        start += token.text.len();
+    }
+    tokens
+}
+
+#[derive(Debug, PartialEq)]
+pub enum MathSegment {
+    Text(String),
+    /// Math between delimiters, without the delimiters
+    Math {
+        delimiter: MathDelimiter,
+        content: String,
+    },
+    /// A math environment from `\begin` to `\end`, like `\begin{align}...\end{align}`
+    Environment(String),
+}
+
+/// Splits `latex` into prose and math. Escaped dollars stay in the prose, and math
+/// without a closing delimiter in the same braces is treated as prose.
+pub fn split_math(latex: &str) -> Vec<MathSegment> {
+    let tokens = tokenize(latex);
+    let mut segments = Vec::new();
+    let mut text_start = 0;
+    let mut index = 0;
+
+    while index < tokens.len() {
+        let token = &tokens[index];
+        let math_environment = token
+            .environment()
+            .filter(|name| token.kind == TokenKind::Begin && MATH_ENVIRONMENTS.contains(name));
+        let closing = match (token.opened_math(), math_environment) {
+            (Some(delimiter), _) => find_closing(&tokens[index + 1..], |t| {
+                t.kind == TokenKind::MathDelimiter && t.text == delimiter.close
+            }),
+            (None, Some(name)) => find_closing(&tokens[index + 1..], |t| {
+                t.kind == TokenKind::End && t.environment() == Some(name)
+            }),
+            (None, None) => None,
+        };
+        let Some(closing) = closing.map(|offset| index + 1 + offset) else {
+            index += 1;
+            continue;
+        };
+
+        if text_start < token.start {
+            segments.push(MathSegment::Text(
+                latex[text_start..token.start].to_string(),
+            ));
+        }
+        let close = &tokens[closing];
+        segments.push(match token.opened_math() {
+            Some(delimiter) => MathSegment::Math {
+                delimiter,
+                content: latex[token.end()..close.start].to_string(),
+            },
+            None => MathSegment::Environment(latex[token.start..close.end()].to_string()),
+        });
+        text_start = close.end();
+        index = closing + 1;
+    }
+
+    if text_start < latex.len() {
+        segments.push(MathSegment::Text(latex[text_start..].to_string()));
+    }
+    segments
+}
+
+/// Finds the token that closes the math at the same brace depth
+fn find_closing(tokens: &[Token], is_closing: impl Fn(&Token) -> bool) -> Option<usize> {
+    let mut depth = 0;
+    for (index, token) in tokens.iter().enumerate() {
+        match token.kind {
+            _ if depth == 0 && is_closing(token) => return Some(index),
+            TokenKind::OpenBrace => depth += 1,
+            // The braces around the math end before the math
+            TokenKind::CloseBrace if depth == 0 => return None,
+            TokenKind::CloseBrace => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn math(delimiter: usize, content: &str) -> MathSegment {
+        MathSegment::Math {
+            delimiter: MATH_DELIMITERS[delimiter],
+            content: content.to_string(),
+        }
+    }
+
+    fn text(text: &str) -> MathSegment {
+        MathSegment::Text(text.to_string())
+    }
+
+    #[test]
+    fn test_tokenize() {
+        let kinds: Vec<(TokenKind, &str)> = tokenize(r"\begin{x}\frac{\$}$$\\% c")
+            .into_iter()
+            .map(|token| (token.kind, token.text))
+            .collect();
+
+        assert_eq!(
+            kinds,
+            vec![
+                (TokenKind::Begin, r"\begin{x}"),
+                (TokenKind::Other, r"\frac"),
+                (TokenKind::OpenBrace, "{"),
+                (TokenKind::Other, r"\$"),
+                (TokenKind::CloseBrace, "}"),
+                (TokenKind::MathDelimiter, "$$"),
+                (TokenKind::Other, r"\\"),
+                (TokenKind::Comment, "% c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_math() {
+        assert_eq!(
+            split_math(r"Let $a < b$ and $$x^2$$ or \[ y \] and \(z\)."),
+            vec![
+                text("Let "),
+                math(1, "a < b"),
+                text(" and "),
+                math(0, "x^2"),
+                text(" or "),
+                math(2, " y "),
+                text(" and "),
+                math(3, "z"),
+                text("."),
+            ]
+        );
+        assert_eq!(
+            split_math("So\n\\begin{align*}\n  x &= 1\n\\end{align*}\n"),
+            vec![
+                text("So\n"),
+                MathSegment::Environment("\\begin{align*}\n  x &= 1\n\\end{align*}".to_string()),
+                text("\n"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_split_math_escaped_and_unclosed() {
+        assert_eq!(
+            split_math(r"costs \$5, $\$3 + 1$ and $unclosed"),
+            vec![
+                text(r"costs \$5, "),
+                math(1, r"\$3 + 1"),
+                text(" and $unclosed"),
+            ]
+        );
+        assert_eq!(split_math(r"{$unclosed} $"), vec![text(r"{$unclosed} $")]);
+    }
+}
@@ -0,0 +1,326 @@
+use crate::anki_backend::AnkiBackend;
+use crate::cloze::CLOZE_MARKER;
+use crate::latex_lint::strip_comment;
+use crate::settings::LatexSettings;
+use std::collections::HashSet;
+
+/// Commands of LaTeX, amsmath and amssymb that don't have to be defined in the preamble,
+/// separated by whitespace. The default preamble of Anki loads amsmath and amssymb, so their
+/// commands are always known. Commands of other packages are in [`PACKAGE_COMMANDS`].
+const KNOWN_COMMANDS: &str =
+    "begin end item label ref eqref cite emph textbf textit texttt textrm textsf textsl textsc \
+textup textmd textnormal text underline footnote par newline linebreak newpage noindent \
+hspace vspace smallskip medskip bigskip enspace mbox makebox fbox framebox hbox vbox \
+raisebox parbox centering hline cline multicolumn tiny scriptsize footnotesize small \
+normalsize large Large LARGE huge Huge bfseries itshape em phantom hphantom vphantom \
+smash mathstrut quad qquad ldots cdots vdots ddots dots dotsc dotsm dotsi dotso hdots \
+dotsb tag notag nonumber intertext displaystyle textstyle scriptstyle left right big Big \
+bigg Bigg bigl bigr Bigl Bigr middle mathbb mathcal mathfrak mathscr mathrm mathbf mathit \
+mathsf mathtt boldsymbol operatorname frac dfrac tfrac sqrt binom overline underbrace \
+overbrace hat widehat tilde widetilde bar vec dot ddot prime stackrel overset underset \
+substack limits nolimits mathop mathrel mathbin mathord cfrac genfrac dbinom tbinom boxed \
+acute grave breve check mathring dddot overrightarrow overleftarrow xrightarrow xleftarrow \
+sideset varinjlim varprojlim injlim projlim lim liminf limsup sup inf max min sum prod \
+coprod int iint iiint oint bigcup bigcap bigoplus bigotimes bigodot biguplus bigsqcup \
+bigvee bigwedge det dim ker deg exp log lg ln sin cos tan cot sec csc arcsin arccos arctan \
+arg gcd hom Pr sinh cosh tanh coth mod bmod pmod leq le geq ge neq ne approx equiv sim \
+simeq cong propto asymp doteq bowtie smile frown subset subseteq sqsubseteq sqsupseteq \
+subsetneq supset supseteq in notin ni cup cap setminus times cdot circ oplus otimes pm mp \
+div ast star bullet dagger ddagger amalg uplus sqcup sqcap oslash ominus odot wr diamond \
+bigcirc wedge vee land lor neg lnot colon forall exists nexists implies impliedby iff to \
+mapsto longmapsto rightarrow leftarrow Rightarrow Leftarrow leftrightarrow Leftrightarrow \
+longrightarrow longleftarrow Longrightarrow Longleftarrow longleftrightarrow \
+Longleftrightarrow hookrightarrow hookleftarrow twoheadrightarrow twoheadleftarrow \
+rightsquigarrow leadsto uparrow downarrow updownarrow Uparrow Downarrow Updownarrow \
+nearrow searrow swarrow nwarrow rightleftharpoons leftharpoonup rightharpoonup \
+nrightarrow nleftarrow nRightarrow nLeftarrow nleftrightarrow nLeftrightarrow mid nmid \
+parallel nparallel perp vdash dashv models ll gg lll ggg prec succ preceq succeq lhd rhd \
+unlhd unrhd triangleleft triangleright vartriangleleft vartriangleright trianglelefteq \
+trianglerighteq triangle vartriangle triangledown blacktriangle bigtriangleup \
+bigtriangledown leqq geqq leqslant geqslant lesssim gtrsim nleq ngeq nless ngtr \
+subsetneqq supsetneq supsetneqq nsubseteq nsupseteq varsubsetneq ncong nsim approxeq \
+thicksim thickapprox therefore because square blacksquare Box Diamond lozenge \
+blacklozenge checkmark complement circledast circledcirc boxplus boxminus boxtimes boxdot \
+ltimes rtimes smallsetminus measuredangle sphericalangle ulcorner urcorner llcorner \
+lrcorner top bot not infty emptyset varnothing partial nabla ell hbar hslash wp imath \
+jmath Re Im aleph beth gimel mho angle surd flat natural sharp clubsuit diamondsuit \
+heartsuit spadesuit backslash langle rangle lbrace rbrace lbrack rbrack lvert rvert lVert \
+rVert lfloor rfloor lceil rceil vert Vert alpha varkappa digamma \
+beta gamma delta epsilon varepsilon zeta eta theta vartheta iota kappa lambda mu nu xi pi \
+varpi rho varrho sigma varsigma tau upsilon phi varphi chi psi omega Gamma Delta Theta \
+Lambda Xi Pi Sigma Upsilon Phi Psi Omega";
+
+/// Commands of the packages that the lint knows, besides the ones in [`KNOWN_COMMANDS`].
+/// If a preamble loads another package, its commands can't be told apart from undefined ones.
+const PACKAGE_COMMANDS: &[(&str, &str)] = &[
+    ("amsmath", ""),
+    ("amssymb", ""),
+    ("amsfonts", ""),
+    ("latexsym", "Join sqsubset sqsupset"),
+    ("inputenc", ""),
+    ("fontenc", ""),
+    ("lmodern", ""),
+    (
+        "amsthm",
+        "newtheorem newtheoremstyle theoremstyle qed qedhere qedsymbol proofname swapnumbers",
+    ),
+    (
+        "mathtools",
+        "coloneqq Coloneqq coloneq eqqcolon eqcolon vcentcolon dblcolon mathclap mathllap \
+mathrlap clap llap rlap xleftrightarrow xLeftarrow xRightarrow xLeftrightarrow xmapsto \
+xhookrightarrow xhookleftarrow prescript DeclarePairedDelimiter DeclarePairedDelimiterX \
+shortintertext adjustlimits splitfrac splitdfrac cramped underbracket overbracket lparen \
+rparen",
+    ),
+    ("xcolor", COLOR_COMMANDS),
+    ("color", COLOR_COMMANDS),
+    (
+        "graphicx",
+        "includegraphics rotatebox scalebox resizebox reflectbox",
+    ),
+    ("bm", "bm"),
+    ("cancel", "cancel bcancel xcancel cancelto"),
+];
+
+const COLOR_COMMANDS: &str = "color textcolor colorbox fcolorbox definecolor pagecolor";
+
+/// Commands that define a new command, which is the next command after them
+const DEFINING_COMMANDS: &[&str] = &[
+    "newcommand",
+    "renewcommand",
+    "providecommand",
+    "DeclareMathOperator",
+    "DeclareRobustCommand",
+    "DeclarePairedDelimiter",
+    "def",
+    "let",
+];
+
+/// The preamble that Anki renders the LaTeX of `note_type` with, or the local preamble file
+/// if Anki can't be asked or the note type isn't chosen yet. The lint and the preview use it,
+/// so that they check against the same preamble.
+pub fn note_type_preamble(
+    anki: &dyn AnkiBackend,
+    note_type: Option<&str>,
+    latex: &LatexSettings,
+) -> Option<String> {
+    note_type
+        .and_then(|note_type| anki.get_latex_preamble(note_type).ok())
+        .or_else(|| latex.read_preamble())
+}
+
+/// Returns the names (without backslash) of all commands used in `latex`, in order of first use
+pub fn find_used_commands(latex: &str) -> Vec<String> {
+    let mut commands: Vec<String> = Vec::new();
+    for command in commands_in(latex) {
+        if !commands.contains(&command) {
+            commands.push(command);
+        }
+    }
+    commands
+}
+
+/// Returns the names of the commands defined in `preamble` with `\newcommand` and similar
+pub fn find_defined_commands(preamble: &str) -> HashSet<String> {
+    let mut defined = HashSet::new();
+    let mut commands = commands_in(preamble);
+    while let Some(command) = commands.next() {
+        if DEFINING_COMMANDS.contains(&command.as_str()) {
+            if let Some(defined_command) = commands.next() {
+                defined.insert(defined_command);
+            }
+        }
+    }
+    defined
+}
+
+/// Returns the names of the packages that `preamble` loads with `\usepackage`
+pub fn find_loaded_packages(preamble: &str) -> Vec<String> {
+    let mut packages = Vec::new();
+    for line in preamble.lines().map(strip_comment) {
+        for (index, _) in line.match_indices("\\usepackage") {
+            let arguments = line[index + "\\usepackage".len()..].trim_start();
+            // Skip the options, like `[utf8]`
+            let arguments = match arguments.strip_prefix('[') {
+                Some(options) => options.split_once(']').map_or("", |(_, rest)| rest),
+                None => arguments,
+            };
+            let Some((names, _)) = arguments
+                .trim_start()
+                .strip_prefix('{')
+                .and_then(|names| names.split_once('}'))
+            else {
+                continue;
+            };
+            packages.extend(names.split(',').map(|name| name.trim().to_string()));
+        }
+    }
+    packages
+}
+
+/// Returns the commands used in `latex` that are neither defined in `preamble` nor part of
+/// LaTeX, amsmath or the packages that `preamble` loads. If it loads a package that the
+/// lint doesn't know, nothing is returned, because that package may define any command.
+pub fn find_undefined_commands(latex: &str, preamble: &str) -> Vec<String> {
+    let package_commands: Option<Vec<&str>> = find_loaded_packages(preamble)
+        .iter()
+        .map(|package| {
+            PACKAGE_COMMANDS
+                .iter()
+                .find(|(name, _)| name == package)
+                .map(|(_, commands)| *commands)
+        })
+        .collect();
+    let Some(package_commands) = package_commands else {
+        return Vec::new();
+    };
+
+    let defined = find_defined_commands(preamble);
+    find_used_commands(latex)
+        .into_iter()
+        .filter(|command| !defined.contains(command) && !is_known_command(command))
+        .filter(|command| {
+            !package_commands
+                .iter()
+                .any(|commands| commands.split_whitespace().any(|known| known == command))
+        })
+        .collect()
+}
+
+fn is_known_command(command: &str) -> bool {
+    // The cloze marker is replaced by bookminer before sending
+    if CLOZE_MARKER.strip_prefix('\\') == Some(command) {
+        return true;
+    }
+    // Control symbols like `\{` or `\,` are all part of LaTeX
+    !command.starts_with(|c: char| c.is_ascii_alphabetic())
+        || KNOWN_COMMANDS
+            .split_whitespace()
+            .any(|known| known == command)
+}
+
+fn commands_in(latex: &str) -> impl Iterator<Item = String> + '_ {
+    let mut chars = latex.chars().peekable();
+    std::iter::from_fn(move || {
+        while let Some(c) = chars.next() {
+            match c {
+                // Skip comments
+                '%' => {
+                    for c in chars.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                }
+                '\\' => {
+                    let mut name = String::new();
+                    while let Some(&next) = chars.peek() {
+                        if !next.is_ascii_alphabetic() {
+                            break;
+                        }
+                        name.push(next);
+                        chars.next();
+                    }
+                    if name.is_empty() {
+                        // Control symbols like `\{` or `\,` consist of a single character
+                        name.extend(chars.next());
+                    }
+                    return Some(name);
+                }
+                _ => {}
+            }
+        }
+        None
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DiffLine<'a> {
+    Unchanged(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Computes a line diff from `old` to `new`, based on the longest common subsequence
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old: Vec<&str> = old.lines().collect();
+    let new: Vec<&str> = new.lines().collect();
+
+    // common[i][j] is the length of the longest common subsequence of old[i..] and new[j..]
+    let mut common = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i][j] = if old[i] == new[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = Vec::with_capacity(old.len().max(new.len()));
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            diff.push(DiffLine::Unchanged(old[i]));
+            i += 1;
+            j += 1;
+        } else if common[i + 1][j] >= common[i][j + 1] {
+            diff.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[i..].iter().map(|line| DiffLine::Removed(line)));
+    diff.extend(new[j..].iter().map(|line| DiffLine::Added(line)));
+    diff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_undefined_commands() {
+        let preamble = r"\usepackage{amsmath}
+\newcommand{\R}{\mathbb{R}}
+\DeclareMathOperator{\Hom}{Hom}
+% \newcommand{\N}{\mathbb{N}}";
+        let latex = r"$f\colon \R \to \N$, $\Hom(V, W) \cong \mathbb{C}$, $\Z \subset \R$";
+
+        assert_eq!(find_undefined_commands(latex, preamble), vec!["N", "Z"]);
+    }
+
+    #[test]
+    fn test_commands_of_loaded_packages() {
+        let preamble = crate::note_type::BOOKMINER_LATEX_PREAMBLE;
+        let latex = r"$A \coloneqq \{x \leqslant \arctan y\} \bullet \N$ \qedhere";
+
+        assert_eq!(
+            find_loaded_packages(preamble),
+            vec!["inputenc", "amssymb", "amsmath", "amsthm", "mathtools"]
+        );
+        assert_eq!(find_undefined_commands(latex, preamble), vec!["N"]);
+        assert_eq!(
+            find_undefined_commands(latex, "\\usepackage{tikz-cd}"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        let diff = diff_lines("a\nb\nc", "a\nc\nd");
+
+        assert_eq!(
+            diff,
+            vec![
+                DiffLine::Unchanged("a"),
+                DiffLine::Removed("b"),
+                DiffLine::Unchanged("c"),
+                DiffLine::Added("d"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use screenshots::image::{self, Rgba, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Resolution of the rendered PNG, the same as Anki uses
+const PREVIEW_DPI: u32 = 200;
+
+/// Size of the base64 chunks of the kitty graphics protocol
+const KITTY_CHUNK_SIZE: usize = 4096;
+
+/// Lines of the log shown when compiling fails
+const LOG_EXCERPT_LINES: usize = 30;
+
+/// How the preview is shown
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PreviewProtocol {
+    /// The graphics protocol of the terminal if it is known, otherwise the viewer command
+    #[default]
+    Auto,
+    Kitty,
+    Sixel,
+    /// The configured `preview_viewer` command
+    Viewer,
+}
+
+impl PreviewProtocol {
+    /// The graphics protocol of the terminal, `None` for [`PreviewProtocol::Viewer`] and an
+    /// undetected [`PreviewProtocol::Auto`]
+    pub fn terminal_protocol(self) -> Option<TerminalProtocol> {
+        match self {
+            PreviewProtocol::Kitty => Some(TerminalProtocol::Kitty),
+            PreviewProtocol::Sixel => Some(TerminalProtocol::Sixel),
+            PreviewProtocol::Auto | PreviewProtocol::Viewer => None,
+        }
+    }
+}
+
+/// Graphics protocols that show images in the terminal
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TerminalProtocol {
+    Kitty,
+    Sixel,
+}
+
+/// Local programs that convert the DVI of `latex` into an image, in the order they are tried.
+/// Only PNG images can be shown in the terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DviConverter {
+    Dvipng,
+    Dvisvgm,
+}
+
+const CONVERTERS: [DviConverter; 2] = [DviConverter::Dvipng, DviConverter::Dvisvgm];
+
+impl DviConverter {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DviConverter::Dvipng => "png",
+            DviConverter::Dvisvgm => "svg",
+        }
+    }
+
+    /// Creates the command with the arguments of Anki, except for a white background,
+    /// so that the black text can be read in dark terminals
+    pub fn command(&self, dvi: &Path, output: &Path) -> Command {
+        match self {
+            DviConverter::Dvipng => {
+                let mut command = Command::new("dvipng");
+                command
+                    .args(["-bg", "White"])
+                    .args(["-D", &PREVIEW_DPI.to_string()])
+                    .args(["-T", "tight"])
+                    .arg(dvi)
+                    .arg("-o")
+                    .arg(output);
+                command
+            }
+            DviConverter::Dvisvgm => {
+                let mut command = Command::new("dvisvgm");
+                command
+                    .args(["--no-fonts", "--exact", "-Z", "2"])
+                    .arg(dvi)
+                    .arg("-o")
+                    .arg(output);
+                command
+            }
+        }
+    }
+}
+
+pub enum RenderResult {
+    Image(PathBuf),
+    /// `latex` failed, with the part of the log that shows the error
+    Failed {
+        log: String,
+    },
+}
+
+/// Compiles `body` with `preamble` like Anki does and converts it into an image in `dir`,
+/// named after `name`. The preamble has to contain `\begin{document}`.
+pub fn render_latex(preamble: &str, body: &str, dir: &Path, name: &str) -> Result<RenderResult> {
+    let tex_file = dir.join(format!("{}.tex", name));
+    let document = format!("{}\n{}\n\\end{{document}}\n", preamble.trim_end(), body);
+    fs::write(&tex_file, document).context("Writing the LaTeX document")?;
+
+    let result = match Command::new("latex")
+        .arg("-interaction=nonstopmode")
+        .arg("-halt-on-error")
+        .arg(&tex_file)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .output()
+    {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(anyhow!("The preview needs latex (e.g. TeX Live)"));
+        }
+        result => result.context("Running latex")?,
+    };
+    if !result.status.success() {
+        let log = fs::read_to_string(dir.join(format!("{}.log", name)))
+            .unwrap_or_else(|_| String::from_utf8_lossy(&result.stdout).to_string());
+        return Ok(RenderResult::Failed {
+            log: log_excerpt(&log),
+        });
+    }
+
+    let dvi = dir.join(format!("{}.dvi", name));
+    for converter in CONVERTERS {
+        let output = dir.join(format!("{}.{}", name, converter.extension()));
+        let result = match converter.command(&dvi, &output).output() {
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            result => result.with_context(|| format!("Running {:?}", converter))?,
+        };
+        if !result.status.success() {
+            return Err(anyhow!(
+                "{:?} failed: {}",
+                converter,
+                String::from_utf8_lossy(&result.stderr).trim()
+            ));
+        }
+        return Ok(RenderResult::Image(output));
+    }
+
+    Err(anyhow!("The preview needs dvipng or dvisvgm"))
+}
+
+/// Returns the log from the first error, which TeX marks with `!`, or its end without errors
+pub fn log_excerpt(log: &str) -> String {
+    let lines: Vec<&str> = log.lines().collect();
+    let start = lines
+        .iter()
+        .position(|line| line.starts_with('!'))
+        .unwrap_or_else(|| lines.len().saturating_sub(LOG_EXCERPT_LINES));
+    lines[start..]
+        .iter()
+        .take(LOG_EXCERPT_LINES)
+        .copied()
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Resolves [`PreviewProtocol::Auto`] with the variables that terminals set
+pub fn detect_protocol(protocol: PreviewProtocol) -> PreviewProtocol {
+    if protocol != PreviewProtocol::Auto {
+        return protocol;
+    }
+
+    let term = env::var("TERM").unwrap_or_default();
+    let term_program = env::var("TERM_PROGRAM").unwrap_or_default();
+    if env::var_os("KITTY_WINDOW_ID").is_some()
+        || term.contains("kitty")
+        || matches!(term_program.as_str(), "WezTerm" | "ghostty")
+    {
+        PreviewProtocol::Kitty
+    } else if term.contains("sixel")
+        || term.starts_with("foot")
+        || term.starts_with("mlterm")
+        || term_program == "contour"
+    {
+        PreviewProtocol::Sixel
+    } else {
+        PreviewProtocol::Viewer
+    }
+}
+
+/// Encodes a PNG file for the kitty graphics protocol
+pub fn encode_kitty(png: &[u8]) -> String {
+    let data = STANDARD.encode(png);
+    let chunks: Vec<&str> = data
+        .as_bytes()
+        .chunks(KITTY_CHUNK_SIZE)
+        .map(|chunk| std::str::from_utf8(chunk).expect("base64 is ASCII"))
+        .collect();
+
+    let mut output = String::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let more = u8::from(index + 1 < chunks.len());
+        if index == 0 {
+            output.push_str(&format!("\x1b_Ga=T,f=100,m={};{}\x1b\\", more, chunk));
+        } else {
+            output.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk));
+        }
+    }
+    output
+}
+
+/// Encodes the image as sixels, with the colours reduced to 6 levels per channel
+/// and transparent pixels drawn white
+pub fn encode_sixel(image: &RgbaImage) -> String {
+    let (width, height) = image.dimensions();
+    let mut palette: HashMap<[u8; 3], usize> = HashMap::new();
+    let colors: Vec<usize> = image
+        .pixels()
+        .map(|pixel| {
+            let color = quantize(pixel);
+            let next_index = palette.len();
+            *palette.entry(color).or_insert(next_index)
+        })
+        .collect();
+
+    let mut output = format!("\x1bPq\"1;1;{};{}", width, height);
+    let mut sorted_palette: Vec<(&[u8; 3], &usize)> = palette.iter().collect();
+    sorted_palette.sort_by_key(|(_, index)| **index);
+    for (color, index) in sorted_palette {
+        let [r, g, b] = color.map(|level| level as u32 * 20);
+        output.push_str(&format!("#{};2;{};{};{}", index, r, g, b));
+    }
+
+    for band_top in (0..height).step_by(6) {
+        let band_rows = (height - band_top).min(6);
+        let mut band_colors: Vec<usize> = (0..band_rows)
+            .flat_map(|row| {
+                let start = ((band_top + row) * width) as usize;
+                colors[start..start + width as usize].iter().copied()
+            })
+            .collect();
+        band_colors.sort_unstable();
+        band_colors.dedup();
+
+        for (color_number, color) in band_colors.iter().enumerate() {
+            output.push_str(&format!("#{}", color));
+            let sixels: Vec<u8> = (0..width)
+                .map(|x| {
+                    let bits = (0..band_rows)
+                        .filter(|row| colors[((band_top + row) * width + x) as usize] == *color)
+                        .fold(0, |bits, row| bits | 1 << row);
+                    63 + bits as u8
+                })
+                .collect();
+            push_run_length_encoded(&mut output, &sixels);
+            // Return to the start of the band for the next colour, or go to the next band
+            output.push(if color_number + 1 < band_colors.len() {
+                '$'
+            } else {
+                '-'
+            });
+        }
+    }
+
+    output.push_str("\x1b\\");
+    output
+}
+
+/// Reduces a pixel to 6 levels per channel, drawn over white
+fn quantize(pixel: &Rgba<u8>) -> [u8; 3] {
+    let alpha = pixel[3] as u32;
+    [pixel[0], pixel[1], pixel[2]].map(|channel| {
+        let over_white = (channel as u32 * alpha + 255 * (255 - alpha)) / 255;
+        ((over_white * 5 + 127) / 255) as u8
+    })
+}
+
+fn push_run_length_encoded(output: &mut String, sixels: &[u8]) {
+    let mut index = 0;
+    while index < sixels.len() {
+        let sixel = sixels[index];
+        let run = sixels[index..].iter().take_while(|&&s| s == sixel).count();
+        if run > 3 {
+            output.push_str(&format!("!{}{}", run, sixel as char));
+        } else {
+            output.extend(std::iter::repeat_n(sixel as char, run));
+        }
+        index += run;
+    }
+}
+
+/// Creates the output that shows the PNG images in the terminal
+pub fn terminal_images(images: &[PathBuf], protocol: TerminalProtocol) -> Result<String> {
+    let mut output = String::new();
+    for path in images {
+        match protocol {
+            TerminalProtocol::Kitty => {
+                let png = fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+                output.push_str(&encode_kitty(&png));
+            }
+            TerminalProtocol::Sixel => {
+                let image = image::open(path)
+                    .with_context(|| format!("Reading {}", path.display()))?
+                    .to_rgba8();
+                output.push_str(&encode_sixel(&image));
+            }
+        }
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Opens the images with the viewer command. `{path}` is replaced by the quoted paths,
+/// otherwise they are appended.
+pub fn open_in_viewer(viewer: &str, images: &[PathBuf]) -> Result<()> {
+    let paths = images
+        .iter()
+        .map(|path| format!("'{}'", path.display().to_string().replace('\'', r"'\''")))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let command = if viewer.contains("{path}") {
+        viewer.replace("{path}", &paths)
+    } else {
+        format!("{} {}", viewer, paths)
+    };
+
+    let output = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .stdin(Stdio::null())
+        .output()
+        .context("Running the preview viewer")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "The preview viewer failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_excerpt_starts_at_error() {
+        let log = "This is pdfTeX\n(./front.tex\n! Undefined control sequence.\nl.3 \\R\n\n";
+        let clean_log = (1..=40).map(|i| format!("line {}", i)).collect::<Vec<_>>();
+
+        assert_eq!(log_excerpt(log), "! Undefined control sequence.\nl.3 \\R\n");
+        assert_eq!(
+            log_excerpt(&clean_log.join("\n")),
+            clean_log[10..].join("\n")
+        );
+    }
+
+    #[test]
+    fn test_encode_kitty_in_chunks() {
+        let png = vec![0; KITTY_CHUNK_SIZE];
+
+        let output = encode_kitty(&png);
+
+        assert!(output.starts_with("\x1b_Ga=T,f=100,m=1;AAAA"));
+        assert_eq!(output.matches("\x1b_Gm=0;").count(), 1);
+        assert!(output.ends_with("\x1b\\"));
+    }
+
+    #[test]
+    fn test_encode_sixel() {
+        // A black pixel above a white one, and a transparent column
+        let mut image = RgbaImage::from_pixel(2, 2, Rgba([0, 0, 0, 0]));
+        image.put_pixel(0, 0, Rgba([0, 0, 0, 255]));
+        image.put_pixel(0, 1, Rgba([255, 255, 255, 255]));
+
+        assert_eq!(
+            encode_sixel(&image),
+            "\x1bPq\"1;1;2;2#0;2;0;0;0#1;2;100;100;100#0@?$#1AB-\x1b\\"
+        );
+    }
+}
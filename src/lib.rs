@@ -0,0 +1,33 @@
+pub mod anki_backend;
+pub mod anki_config;
+pub mod anki_error_handling;
+pub mod anki_search;
+pub mod ankiconnect;
+pub mod cloze;
+pub mod config_validation;
+pub mod duplicates;
+pub mod env_variables;
+pub mod image_processing;
+pub mod latex_lint;
+pub mod latex_math;
+pub mod latex_preamble;
+pub mod latex_preview;
+pub mod main_application;
+pub mod math_format;
+pub mod media_encoding;
+pub mod menu_actions;
+pub mod note_send;
+pub mod note_type;
+pub mod note_update;
+pub mod outbox;
+pub mod paths;
+pub mod pdf_render;
+pub mod possible_entries;
+pub mod screenshot;
+pub mod screenshot_backend;
+pub mod sections;
+pub mod settings;
+pub mod tui_windows;
+pub mod ui;
+#[cfg(target_os = "linux")]
+pub mod x11;
@@ -1,20 +1,19 @@
-mod anki_config;
-mod anki_error_handling;
-mod ankiconnect;
-mod env_variables;
-mod main_application;
-mod menu_actions;
-mod paths;
-mod possible_entries;
-mod screenshot;
-mod tui_windows;
-mod ui;
-
-use crate::env_variables::{get_terminal_args, get_terminal_binary_name};
-use crate::main_application::run_terminal_application;
-use crate::screenshot::{capture_screenshot, create_unique_screenshot_filename, save_image};
 use anyhow::{anyhow, Context, Result};
-use clap::Parser;
+use bookminer::anki_backend::AnkiBackend;
+use bookminer::anki_config::load_anki_config;
+use bookminer::ankiconnect::AnkiConnectClient;
+use bookminer::env_variables::{get_terminal_args, get_terminal_binary_name};
+use bookminer::image_processing::parse_operations;
+use bookminer::latex_preamble::{diff_lines, DiffLine};
+use bookminer::main_application::{run_terminal_application, ImageCapture};
+use bookminer::outbox::flush_outbox;
+use bookminer::paths::get_outbox_dir;
+use bookminer::pdf_render::{PdfPage, PdfRect};
+use bookminer::screenshot::CaptureMode;
+use bookminer::screenshot_backend::{create_backends, take_screenshot};
+use bookminer::settings::{load_saved_api_key, load_settings, Settings};
+use clap::{Parser, Subcommand};
+use std::fs;
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, Stdio};
 use tempfile::TempDir;
@@ -36,31 +35,148 @@ struct Args {
 
     #[arg(long)]
     book_filename: Option<String>,
+
+    /// Path of the book for rendering the page, defaults to --book-filename
+    #[arg(long)]
+    book_path: Option<PathBuf>,
+
+    /// Only render this region of a page, as page,x0,y0,x1,y1 in points from the top left corner,
+    /// with the page counted from 0. The page replaces --page-number.
+    #[arg(long, allow_hyphen_values = true, conflicts_with = "image")]
+    rect: Option<PdfRect>,
+
+    /// Update a note mined from the same page instead of adding a new one
+    #[arg(long)]
+    update: bool,
+
+    /// Part of the screen to capture: cursor, focused-monitor, window or monitor:<index or name>
+    #[arg(long)]
+    capture: Option<CaptureMode>,
+
+    /// Comma separated operations applied to the screenshot, e.g. trim,invert,grayscale,max-width=800.
+    /// Replaces the operations in the config file, an empty string disables them
+    #[arg(long)]
+    process: Option<String>,
+
+    /// Use this image instead of taking a screenshot, `-` reads it from stdin
+    #[arg(long)]
+    image: Option<PathBuf>,
+
+    /// URL of the AnkiConnect server, e.g. http://localhost:8765
+    #[arg(long, global = true)]
+    anki_url: Option<String>,
+
+    /// API key, if AnkiConnect is configured to require one
+    #[arg(long, global = true)]
+    anki_api_key: Option<String>,
+
+    /// Timeout for requests to AnkiConnect in seconds
+    #[arg(long, global = true)]
+    anki_timeout: Option<u64>,
+
+    #[command(subcommand)]
+    command: Option<Commands>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Send the notes that were queued while Anki was not running
+    Flush,
+
+    /// Compare or sync the LaTeX preamble of the note type with the local preamble file
+    Preamble {
+        #[command(subcommand)]
+        action: PreambleAction,
+
+        /// Note type to use instead of the last selected one
+        #[arg(long)]
+        note_type: Option<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PreambleAction {
+    /// Show the differences and how to copy the local preamble file into the note type
+    ///
+    /// AnkiConnect has no action to change the preamble, so this can't be done automatically.
+    Instructions,
+    /// Write the preamble of the note type to the local preamble file
+    Pull,
+    /// Show how the local preamble file differs from the preamble of the note type
+    Diff,
 }
 
 fn main() -> Result<()> {
-    let args = Args::parse();
+    let mut args = Args::parse();
+    if let Some(rect) = &args.rect {
+        args.page_number = Some(rect.page_number());
+    }
+
+    match &args.command {
+        Some(Commands::Flush) => {
+            let anki = create_anki_client(&load_settings_with_overrides(&args)?)?;
+            return flush_queued_notes(&anki);
+        }
+        Some(Commands::Preamble { action, note_type }) => {
+            let settings = load_settings_with_overrides(&args)?;
+            let anki = create_anki_client(&settings)?;
+            return sync_preamble(&anki, &settings, action, note_type.clone());
+        }
+        None => {}
+    }
+
+    let settings = load_settings_with_overrides(&args)?;
+    let book_path = args
+        .book_path
+        .clone()
+        .or_else(|| args.book_filename.as_ref().map(PathBuf::from));
 
     if args.main {
+        let anki: Box<dyn AnkiBackend> = Box::new(create_anki_client(&settings)?);
+
         let tmp_dir = args
             .tmp_dir
             .ok_or_else(|| anyhow!("Missing tmp_dir argument"))?;
+        let image_capture = ImageCapture {
+            settings: settings.screenshot,
+            book_path,
+        };
         run_terminal_application(
+            anki,
             tmp_dir,
             args.screenshot_path,
             args.page_number,
             args.book_filename,
+            args.update,
+            image_capture,
+            settings.latex,
         )?;
     } else {
-        let screenshot = capture_screenshot()?;
+        let page_number = args.page_number;
+        let pdf_page = book_path.zip(page_number).map(|(path, page)| PdfPage {
+            path,
+            page,
+            rect: args.rect,
+        });
+        if args.rect.is_some() && pdf_page.is_none() {
+            return Err(anyhow!(
+                "--rect needs the book from --book-path or --book-filename"
+            ));
+        }
+        let backends = create_backends(&settings.screenshot, args.image.as_deref(), pdf_page)?;
+        let screenshot = take_screenshot(
+            &settings.screenshot,
+            &backends,
+            args.book_filename.as_deref(),
+            page_number,
+        )?;
 
         let tmp_dir = create_tmp_dir()?;
-        let screenshot_fn = create_unique_screenshot_filename();
-        let screenshot_path = tmp_dir.as_ref().join(screenshot_fn);
+        let screenshot_path = tmp_dir.as_ref().join(&screenshot.filename);
 
         let mut main_application =
             spawn_terminal_with_main_process(tmp_dir.as_ref(), &screenshot_path, args)?;
-        save_image(&screenshot, &screenshot_path)?;
+        fs::write(&screenshot_path, &screenshot.data).context("Saving screenshot")?;
 
         main_application.wait()?; // Must wait so that tmp_dir isn't cleaned up
     }
@@ -68,6 +184,112 @@ fn main() -> Result<()> {
     Ok(())
 }
 
+fn load_settings_with_overrides(args: &Args) -> Result<Settings> {
+    let mut settings = load_settings()?;
+    if let Some(api_key) = load_saved_api_key() {
+        settings.anki_connect.api_key = Some(api_key);
+    }
+    apply_cli_overrides(&mut settings, args)?;
+    Ok(settings)
+}
+
+fn create_anki_client(settings: &Settings) -> Result<AnkiConnectClient> {
+    AnkiConnectClient::new(&settings.anki_connect).context("Creating AnkiConnect client")
+}
+
+fn flush_queued_notes(anki: &AnkiConnectClient) -> Result<()> {
+    let results = flush_outbox(anki, &get_outbox_dir()?)?;
+
+    if results.is_empty() {
+        println!("No queued notes");
+    }
+    for result in &results {
+        println!("{}", result.describe());
+    }
+
+    if results.iter().any(|r| r.result.is_err()) {
+        Err(anyhow!("Not all queued notes could be sent"))
+    } else {
+        Ok(())
+    }
+}
+
+fn sync_preamble(
+    anki: &AnkiConnectClient,
+    settings: &Settings,
+    action: &PreambleAction,
+    note_type: Option<String>,
+) -> Result<()> {
+    let note_type = match note_type {
+        Some(note_type) => note_type,
+        None => load_anki_config()?
+            .map(|config| config.note_type)
+            .ok_or_else(|| anyhow!("No note type selected yet, pass --note-type"))?,
+    };
+    let preamble_file = settings.latex.preamble_file()?;
+    let anki_preamble = anki
+        .get_latex_preamble(&note_type)
+        .with_context(|| format!("Getting the LaTeX preamble of {}", note_type))?;
+
+    if let PreambleAction::Pull = action {
+        if let Some(parent) = preamble_file.parent() {
+            fs::create_dir_all(parent).context("Creating preamble directory")?;
+        }
+        fs::write(&preamble_file, &anki_preamble).context("Writing preamble file")?;
+        println!(
+            "Wrote the preamble of {} to {}",
+            note_type,
+            preamble_file.display()
+        );
+        return Ok(());
+    }
+
+    let local_preamble = fs::read_to_string(&preamble_file)
+        .with_context(|| format!("Reading {}", preamble_file.display()))?;
+    if local_preamble.trim_end() == anki_preamble.trim_end() {
+        println!("The preamble of {} is up to date", note_type);
+        return Ok(());
+    }
+
+    println!("--- {} (Anki)", note_type);
+    println!("+++ {}", preamble_file.display());
+    for line in diff_lines(&anki_preamble, &local_preamble) {
+        match line {
+            DiffLine::Unchanged(line) => println!(" {}", line),
+            DiffLine::Removed(line) => println!("-{}", line),
+            DiffLine::Added(line) => println!("+{}", line),
+        }
+    }
+
+    if let PreambleAction::Instructions = action {
+        println!();
+        println!(
+            "Copy {} into the note type options in Anki (Tools > Manage Note Types > Options)",
+            preamble_file.display()
+        );
+    }
+    Ok(())
+}
+
+fn apply_cli_overrides(settings: &mut Settings, args: &Args) -> Result<()> {
+    if let Some(url) = &args.anki_url {
+        settings.anki_connect.url = url.clone();
+    }
+    if let Some(api_key) = &args.anki_api_key {
+        settings.anki_connect.api_key = Some(api_key.clone());
+    }
+    if let Some(timeout) = args.anki_timeout {
+        settings.anki_connect.timeout_secs = timeout;
+    }
+    if let Some(capture) = &args.capture {
+        settings.screenshot.capture = capture.clone();
+    }
+    if let Some(process) = &args.process {
+        settings.screenshot.processing = parse_operations(process)?;
+    }
+    Ok(())
+}
+
 fn create_tmp_dir() -> std::io::Result<TempDir> {
     tempfile::Builder::new().prefix("bookmining").tempdir()
 }
@@ -103,6 +325,35 @@ fn spawn_terminal_with_main_process(
         command.arg("--book-filename").arg(pdf_name);
     }
 
+    if args.update {
+        command.arg("--update");
+    }
+
+    // Needed to capture more images from the final menu
+    if let Some(book_path) = args.book_path {
+        command.arg("--book-path").arg(book_path);
+    }
+
+    if let Some(capture) = args.capture {
+        command.arg("--capture").arg(capture.to_string());
+    }
+
+    if let Some(process) = args.process {
+        command.arg("--process").arg(process);
+    }
+
+    if let Some(anki_url) = args.anki_url {
+        command.arg("--anki-url").arg(anki_url);
+    }
+
+    if let Some(anki_api_key) = args.anki_api_key {
+        command.arg("--anki-api-key").arg(anki_api_key);
+    }
+
+    if let Some(anki_timeout) = args.anki_timeout {
+        command.arg("--anki-timeout").arg(anki_timeout.to_string());
+    }
+
     command.stdout(Stdio::piped()).stderr(Stdio::piped());
 
     command
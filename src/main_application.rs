@@ -1,13 +1,21 @@
+use crate::anki_backend::AnkiBackend;
 use crate::anki_config::{load_anki_config, save_anki_config, AnkiConfig};
-use crate::paths::get_tags_file;
+use crate::config_validation::{find_stale_settings, update_field_mapping};
+use crate::latex_preamble::note_type_preamble;
+use crate::menu_actions::flush_queued_notes;
+use crate::note_update::load_note_into_files;
+use crate::outbox::queued_entries;
+use crate::paths::{get_outbox_dir, get_tags_file};
+use crate::possible_entries::find_section_names;
+use crate::settings::{LatexSettings, ScreenshotSettings};
 use crate::tui_windows::{
-    edit_back, edit_front, select_anki_deck, select_anki_note_type,
-    select_field_mapping_for_note_type, show_final_menu,
+    choose_note_to_update, edit_back, edit_front, select_anki_deck, select_anki_note_type,
+    select_field_mapping_for_note_type, select_from_possible_content, show_final_menu,
 };
 use crate::ui::tui::Tui;
 use anyhow::{Context, Result};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 fn save_tags(tags: &[String]) -> Result<()> {
     let tags_file = get_tags_file()?;
@@ -29,60 +37,174 @@ fn load_tags() -> Result<Vec<String>> {
     Ok(tags)
 }
 
-fn ask_for_anki_config(tui: &mut Tui) -> Result<AnkiConfig> {
-    let deck_name = select_anki_deck(tui)?;
-    let note_type = select_anki_note_type(tui)?;
-    let field_mapping = select_field_mapping_for_note_type(tui, &note_type)?;
+fn ask_for_anki_config(
+    tui: &mut Tui,
+    anki: &dyn AnkiBackend,
+    tmp_dir: &Path,
+) -> Result<AnkiConfig> {
+    let deck_name = select_anki_deck(tui, anki)?;
+    let note_type = select_anki_note_type(tui, anki)?;
+    let section_names = find_section_names(tmp_dir);
+    let field_mapping = select_field_mapping_for_note_type(tui, anki, &note_type, &section_names)?;
 
-    let anki_config = AnkiConfig {
-        deck_name,
-        note_type,
-        field_mapping,
+    let anki_config = AnkiConfig::new(&deck_name, &note_type, field_mapping);
+
+    save_anki_config(&anki_config)?;
+    Ok(anki_config)
+}
+
+/// Checks the saved settings against the Anki collection and lets the user fix
+/// the deck, note type or fields that don't exist anymore.
+/// If Anki can't be reached, the settings are used as they are.
+fn fix_stale_anki_config(
+    tui: &mut Tui,
+    anki: &dyn AnkiBackend,
+    mut anki_config: AnkiConfig,
+    tmp_dir: &Path,
+) -> Result<AnkiConfig> {
+    let Ok(stale_settings) = find_stale_settings(anki, &anki_config) else {
+        return Ok(anki_config);
     };
+    if stale_settings.is_empty() {
+        return Ok(anki_config);
+    }
+
+    let mut menu_items = stale_settings.describe(&anki_config);
+    menu_items.push("Fix settings".to_string());
+    tui.show_single_selection_menu("The saved Anki settings are out of date", &menu_items)?;
+
+    let section_names = find_section_names(tmp_dir);
+    if stale_settings.deck_missing {
+        anki_config.deck_name = select_anki_deck(tui, anki)?;
+    }
+
+    if stale_settings.note_type_missing {
+        anki_config.note_type = select_anki_note_type(tui, anki)?;
+        anki_config.set_field_mapping(select_field_mapping_for_note_type(
+            tui,
+            anki,
+            &anki_config.note_type,
+            &section_names,
+        )?);
+    } else {
+        update_field_mapping(&mut anki_config, &stale_settings.field_names);
+        for (field_name, content) in anki_config.field_mapping.iter_mut() {
+            if stale_settings.new_fields.contains(field_name) {
+                *content = select_from_possible_content(tui, field_name, &section_names)?;
+            }
+        }
+    }
 
     save_anki_config(&anki_config)?;
     Ok(anki_config)
 }
 
+/// What is needed to capture more images from the final menu
+pub struct ImageCapture {
+    pub settings: ScreenshotSettings,
+    /// Path of the book for rendering pages
+    pub book_path: Option<PathBuf>,
+}
+
 pub struct ApplicationState {
     pub(crate) tui: Tui,
+    pub(crate) anki: Box<dyn AnkiBackend>,
     pub(crate) selected_tags: Vec<String>,
     pub(crate) anki_config: AnkiConfig,
-    pub(crate) screenshot_path: Option<PathBuf>,
+    /// The images of the note, in the order they appear in the Screenshot field
+    pub(crate) screenshot_paths: Vec<PathBuf>,
+    pub(crate) image_capture: ImageCapture,
+    /// The local preamble file for the lint and the preview, and how the preview is shown
+    pub(crate) latex: LatexSettings,
     pub(crate) tmp_dir: PathBuf,
     pub(crate) page_number: Option<u32>,
     pub(crate) book_filename: Option<String>,
+    /// If set, sending updates this note instead of adding a new one
+    pub(crate) note_to_update: Option<u64>,
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn run_terminal_application(
+    anki: Box<dyn AnkiBackend>,
     tmp_dir: PathBuf,
     screenshot_path: Option<PathBuf>,
     page_number: Option<u32>,
     book_filename: Option<String>,
+    update: bool,
+    image_capture: ImageCapture,
+    latex: LatexSettings,
 ) -> Result<()> {
     let mut tui = Tui::new()?;
 
-    edit_front(&mut tui, &tmp_dir)?;
-    edit_back(&mut tui, &tmp_dir)?;
+    let mut anki_config = match load_anki_config()? {
+        Some(ac) => Some(fix_stale_anki_config(
+            &mut tui,
+            anki.as_ref(),
+            ac,
+            &tmp_dir,
+        )?),
+        None => None,
+    };
+    let mut note_to_update = None;
+    let mut preselected_tags = Vec::new();
+
+    // Notes that were queued while Anki was not running are sent once it's reachable again
+    let has_queued_notes = get_outbox_dir()
+        .and_then(|dir| queued_entries(&dir))
+        .is_ok_and(|entries| !entries.is_empty());
+    if has_queued_notes && anki.get_deck_names().is_ok() {
+        flush_queued_notes(&mut tui, anki.as_ref())?;
+    }
+
+    if update {
+        // The field mapping is needed to find the notes and to load their fields
+        let config = match anki_config {
+            Some(ac) => ac,
+            None => ask_for_anki_config(&mut tui, anki.as_ref(), &tmp_dir)?,
+        };
+
+        if let Some(note) = choose_note_to_update(
+            &mut tui,
+            anki.as_ref(),
+            &config,
+            book_filename.as_deref(),
+            page_number,
+        )? {
+            load_note_into_files(&note, &config, &tmp_dir)?;
+            preselected_tags = note.tags;
+            note_to_update = Some(note.note_id);
+        }
+
+        anki_config = Some(config);
+    }
+
+    let note_type = anki_config.as_ref().map(|ac| ac.note_type.as_str());
+    let preamble = note_type_preamble(anki.as_ref(), note_type, &latex);
+    edit_front(&mut tui, &tmp_dir, preamble.as_deref())?;
+    edit_back(&mut tui, &tmp_dir, preamble.as_deref())?;
 
     let mut tags = load_tags()?;
-    let selected_tags = tui.show_tag_menu(&mut tags)?;
+    let selected_tags = tui.show_tag_menu(&mut tags, &preselected_tags)?;
     save_tags(&tags)?;
 
-    let anki_config = if let Some(ac) = load_anki_config()? {
+    let anki_config = if let Some(ac) = anki_config {
         ac
     } else {
-        ask_for_anki_config(&mut tui)?
+        ask_for_anki_config(&mut tui, anki.as_ref(), &tmp_dir)?
     };
 
     let mut state = ApplicationState {
         tui,
+        anki,
         selected_tags,
         anki_config,
-        screenshot_path,
+        screenshot_paths: screenshot_path.into_iter().collect(),
+        image_capture,
+        latex,
         tmp_dir,
         page_number,
         book_filename,
+        note_to_update,
     };
 
     loop {
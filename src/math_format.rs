@@ -0,0 +1,29 @@
+use serde::{Deserialize, Serialize};
+
+/// How the LaTeX of `front.tex` and `back.tex` is put into an Anki field
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MathFormat {
+    /// The whole text in `[latex]...[/latex]`, rendered to an image by Anki
+    #[default]
+    Latex,
+    /// The whole text in `[$]...[/$]`, a math environment rendered to an image
+    LatexMath,
+    /// The whole text in `[$$]...[/$$]`, a display math environment rendered to an image
+    LatexDisplayMath,
+    /// The prose as HTML and the math in `\(...\)` and `\[...\]`, rendered by MathJax
+    MathJax,
+    /// The text as it is, so it can contain HTML
+    Raw,
+}
+
+impl MathFormat {
+    /// The tags that enclose the whole text, if any
+    pub fn tags(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            MathFormat::Latex => Some(("[latex]", "[/latex]")),
+            MathFormat::LatexMath => Some(("[$]", "[/$]")),
+            MathFormat::LatexDisplayMath => Some(("[$$]", "[/$$]")),
+            MathFormat::MathJax | MathFormat::Raw => None,
+        }
+    }
+}
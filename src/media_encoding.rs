@@ -0,0 +1,196 @@
+use anyhow::{anyhow, Context, Result};
+use screenshots::image::codecs::jpeg::JpegEncoder;
+use screenshots::image::codecs::png::PngEncoder;
+use screenshots::image::{DynamicImage, ImageEncoder, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::ErrorKind;
+use std::process::Command;
+
+pub const DEFAULT_FILENAME_TEMPLATE: &str = "screenshot_{date}";
+
+/// Format of the screenshots stored in Anki
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaFormat {
+    #[default]
+    Png,
+    Jpeg,
+    /// Encoded with `cwebp`, because the image crate can only write lossless WebP
+    Webp,
+}
+
+impl MediaFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            MediaFormat::Png => "png",
+            MediaFormat::Jpeg => "jpg",
+            MediaFormat::Webp => "webp",
+        }
+    }
+}
+
+/// Values for the placeholders of the filename template
+pub struct FilenameValues<'a> {
+    /// File name or path of the book, only the name without the extension is used
+    pub book: Option<&'a str>,
+    pub page: Option<u32>,
+    /// Time of the capture, formatted like `20240101_120000`
+    pub date: String,
+}
+
+/// Encodes the image. `quality` (1 to 100) is ignored for PNG.
+pub fn encode_image(image: &RgbaImage, format: MediaFormat, quality: u8) -> Result<Vec<u8>> {
+    let quality = quality.clamp(1, 100);
+    let mut bytes = Vec::new();
+
+    match format {
+        MediaFormat::Png => PngEncoder::new(&mut bytes)
+            .write_image(
+                image,
+                image.width(),
+                image.height(),
+                screenshots::image::ColorType::Rgba8,
+            )
+            .context("Encoding PNG")?,
+        MediaFormat::Jpeg => {
+            // JPEG has no alpha channel
+            let rgb = DynamicImage::ImageRgba8(image.clone()).to_rgb8();
+            JpegEncoder::new_with_quality(&mut bytes, quality)
+                .encode_image(&rgb)
+                .context("Encoding JPEG")?;
+        }
+        MediaFormat::Webp => bytes = encode_webp(image, quality)?,
+    }
+    Ok(bytes)
+}
+
+fn encode_webp(image: &RgbaImage, quality: u8) -> Result<Vec<u8>> {
+    let tmp_dir = tempfile::Builder::new()
+        .prefix("bookminer_encode")
+        .tempdir()?;
+    let input = tmp_dir.path().join("image.png");
+    let output = tmp_dir.path().join("image.webp");
+    image.save(&input).context("Saving image for cwebp")?;
+
+    let result = match Command::new("cwebp")
+        .args(["-quiet", "-q", &quality.to_string()])
+        .arg(&input)
+        .arg("-o")
+        .arg(&output)
+        .output()
+    {
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            return Err(anyhow!("Encoding WebP needs cwebp (libwebp)"));
+        }
+        result => result.context("Running cwebp")?,
+    };
+    if !result.status.success() {
+        return Err(anyhow!(
+            "cwebp failed: {}",
+            String::from_utf8_lossy(&result.stderr).trim()
+        ));
+    }
+
+    fs::read(&output).context("Reading the WebP image")
+}
+
+/// Creates the media filename from a template with `{book}`, `{page}`, `{date}` and `{hash}`.
+/// `{hash}` depends only on the encoded image, so identical images get the same name
+/// and Anki stores them once.
+pub fn create_media_filename(
+    template: &str,
+    values: &FilenameValues,
+    data: &[u8],
+    format: MediaFormat,
+) -> String {
+    let book = values
+        .book
+        .map(|book| {
+            let name = book.rsplit('/').next().unwrap_or(book);
+            name.rsplit_once('.').map_or(name, |(stem, _)| stem)
+        })
+        .unwrap_or_default();
+    let page = values.page.map(|page| page.to_string()).unwrap_or_default();
+
+    let name = template
+        .replace("{book}", book)
+        .replace("{page}", &page)
+        .replace("{date}", &values.date)
+        .replace("{hash}", &format!("{:016x}", content_hash(data)));
+    let name = sanitize_filename(&name);
+    let name = if name.is_empty() { "screenshot" } else { &name };
+
+    format!("{}.{}", name, format.extension())
+}
+
+/// Keeps letters, digits, `-`, `_` and `.`, and trims separators that are left
+/// by placeholders without a value
+fn sanitize_filename(name: &str) -> String {
+    let sanitized: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || matches!(c, '-' | '_' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    sanitized
+        .trim_matches(|c| matches!(c, '-' | '_' | '.'))
+        .to_string()
+}
+
+/// 64 bit FNV-1a, which is stable across Rust versions unlike `DefaultHasher`
+fn content_hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screenshots::image::{self, Rgba};
+
+    #[test]
+    fn test_encode_jpeg() {
+        let image = RgbaImage::from_pixel(16, 8, Rgba([200, 30, 30, 255]));
+
+        let jpeg = encode_image(&image, MediaFormat::Jpeg, 80).unwrap();
+
+        let decoded = image::load_from_memory(&jpeg).unwrap();
+        assert_eq!(
+            image::guess_format(&jpeg).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+        assert_eq!((decoded.width(), decoded.height()), (16, 8));
+    }
+
+    #[test]
+    fn test_create_media_filename() {
+        let values = FilenameValues {
+            book: Some("/home/me/books/Linear Algebra.pdf"),
+            page: Some(12),
+            date: "20240101_120000".to_string(),
+        };
+        let no_book = FilenameValues {
+            book: None,
+            page: None,
+            date: "20240101_120000".to_string(),
+        };
+
+        let name = create_media_filename("{book}_p{page}_{hash}", &values, b"a", MediaFormat::Jpeg);
+
+        assert_eq!(name, "Linear_Algebra_p12_af63dc4c8601ec8c.jpg");
+        assert_eq!(
+            create_media_filename(DEFAULT_FILENAME_TEMPLATE, &values, b"a", MediaFormat::Png),
+            "screenshot_20240101_120000.png"
+        );
+        assert_eq!(
+            create_media_filename("{book}_{page}", &no_book, b"a", MediaFormat::Png),
+            "screenshot.png"
+        );
+    }
+}
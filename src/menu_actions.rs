@@ -1,13 +1,38 @@
-use crate::anki_config::save_anki_config;
-use crate::anki_error_handling::check_should_retry;
-use crate::ankiconnect;
+use crate::anki_backend::{AnkiBackend, StoredMedia};
+use crate::anki_config::{save_anki_config, DuplicatePolicy};
+use crate::anki_error_handling::{check_should_retry, resolve_send_error, SendErrorResolution};
+use crate::ankiconnect::AnkiConnectError;
+use crate::cloze::{has_cloze_markers, split_cloze_segments, ClozeSegment, CLOZE_MARKER};
+use crate::duplicates::find_similar_notes;
+use crate::latex_lint::{lint_latex, LintProblem};
+use crate::latex_preamble::note_type_preamble;
+use crate::latex_preview::{
+    detect_protocol, open_in_viewer, render_latex, terminal_images, RenderResult,
+};
 use crate::main_application::ApplicationState;
+use crate::math_format::MathFormat;
+use crate::note_send::{send_prepared_note, NoteDestination};
+use crate::note_type::BOOKMINER_LATEX_PREAMBLE;
+use crate::note_update::{load_note_into_files, select_fields_to_update};
+use crate::outbox::{flush_outbox, name_queued_media, queue_note, FlushResult, QueuedNote};
+use crate::paths::get_outbox_dir;
+use crate::pdf_render::PdfPage;
+use crate::possible_entries::{
+    find_section_names, image_directive_paths, note_images, read_note_sections, PossibleContent,
+};
+use crate::screenshot_backend::{create_backends, save_screenshot, take_screenshot, BackendKind};
+use crate::sections::IMAGE_DIRECTIVE;
 use crate::tui_windows::{
-    edit_back, edit_front, select_anki_deck, select_anki_note_type,
-    select_field_mapping_for_note_type,
+    choose_note_to_update, edit_back, edit_front, select_anki_deck, select_anki_note_type,
+    select_duplicate_policy, select_duplicate_resolution, select_duplicate_scope,
+    select_field_mapping_for_note_type, select_lint_problem, select_math_format, show_message,
+    DuplicateResolution,
 };
+use crate::ui::tui::Tui;
 use anyhow::{Context, Result};
 use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
 
 pub trait MenuAction {
     fn new() -> Self
@@ -37,49 +62,236 @@ impl MenuAction for CancelAction {
 }
 
 pub struct SendCardAction {
-    // should_quit: bool,
+    should_quit: bool,
+    /// The ID of the added or updated note, once it was sent
+    sent_note: Option<u64>,
 }
 
 impl MenuAction for SendCardAction {
     fn new() -> Self {
         Self {
-            // should_quit: false
+            should_quit: true,
+            sent_note: None,
         }
     }
 
     fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
-        let field_content = Self::get_field_contents_for_mapping(state)?;
-
-        let mut files_to_send = Vec::with_capacity(1);
-        if let Some(screenshot_path) = &state.screenshot_path {
-            files_to_send.push(screenshot_path);
+        if !Self::check_cloze_note_type(state)?
+            || !Self::confirm_lint(state)?
+            || !check_sections(state)?
+        {
+            self.should_quit = false;
+            return Ok(());
         }
 
+        let images = note_images(state)?;
+        let files_to_send: Vec<&PathBuf> = images.iter().collect();
+
         loop {
-            match ankiconnect::send_note(
-                &state.anki_config.deck_name,
-                &state.anki_config.note_type,
-                &field_content,
-                &state.selected_tags,
-                &files_to_send,
-            ) {
-                Ok(_) => return Ok(()),
-                Err(e) => check_should_retry(e, &mut state.tui)?,
+            match self.try_send(state, &files_to_send) {
+                Ok(()) => return Ok(()),
+                Err(e) => match e.downcast::<AnkiConnectError>() {
+                    Ok(anki_error) => match resolve_send_error(anki_error, state)? {
+                        SendErrorResolution::Retry => {}
+                        SendErrorResolution::QueueForLater => {
+                            return Self::queue_for_later(state, &files_to_send);
+                        }
+                        SendErrorResolution::Cancel => {
+                            self.should_quit = false;
+                            return Ok(());
+                        }
+                        // Leaves the final menu, so that the terminal and the temporary
+                        // files are cleaned up like after sending
+                        SendErrorResolution::Quit => {
+                            self.should_quit = true;
+                            return Ok(());
+                        }
+                    },
+                    Err(e) => return Err(e),
+                },
             }
         }
     }
     fn should_exit(&self) -> bool {
-        true
+        self.should_quit
     }
 }
 
 impl SendCardAction {
-    fn get_field_contents_for_mapping(state: &ApplicationState) -> Result<HashMap<String, String>> {
+    /// A cloze field needs a cloze note type with at least one deletion,
+    /// and a cloze note type needs a cloze field. Returns whether the card can be sent.
+    fn check_cloze_note_type(state: &mut ApplicationState) -> Result<bool> {
+        let note_type = &state.anki_config.note_type;
+        // If the note type can't be checked, sending reports the problem
+        let Ok(is_cloze_note_type) = state.anki.is_cloze_note_type(note_type) else {
+            return Ok(true);
+        };
+        let uses_cloze = state
+            .anki_config
+            .field_for_content(&PossibleContent::Cloze)
+            .is_some();
+        let front_text = fs::read_to_string(state.tmp_dir.join("front.tex")).unwrap_or_default();
+
+        let msg = if uses_cloze && !is_cloze_note_type {
+            format!(
+                "The note type \"{}\" is not a Cloze note type. Select another one in the Anki settings",
+                note_type
+            )
+        } else if uses_cloze && !has_cloze_markers(&front_text) {
+            format!("The front has no {}{{...}} markers", CLOZE_MARKER)
+        } else if is_cloze_note_type && !uses_cloze {
+            format!(
+                "The note type \"{}\" needs a field mapped to Cloze. Change the field mapping in the Anki settings",
+                note_type
+            )
+        } else {
+            return Ok(true);
+        };
+
+        show_message(&mut state.tui, &msg)?;
+        Ok(false)
+    }
+
+    /// Lints the front and back, because Anki can't render broken LaTeX or commands that the
+    /// preamble of the note type doesn't define. The local preamble file is used if Anki can't
+    /// be reached. Problems can be fixed in the editor. Returns whether the card should be sent.
+    fn confirm_lint(state: &mut ApplicationState) -> Result<bool> {
+        let preamble = state_preamble(state);
+
+        loop {
+            let files = ["front.tex", "back.tex"].map(|filename| {
+                let latex = fs::read_to_string(state.tmp_dir.join(filename)).unwrap_or_default();
+                (filename, latex)
+            });
+            let problems: Vec<(&str, LintProblem)> = files
+                .iter()
+                .flat_map(|(filename, latex)| {
+                    lint_latex(latex, preamble.as_deref())
+                        .into_iter()
+                        .map(move |problem| (*filename, problem))
+                })
+                .collect();
+            if problems.is_empty() {
+                return Ok(true);
+            }
+
+            let selected =
+                select_lint_problem(&mut state.tui, &problems, &["Send anyway", "Cancel"])?;
+            match problems.get(selected) {
+                Some((filename, problem)) => {
+                    let path = state.tmp_dir.join(filename);
+                    state.tui.edit_file_at_line(&path, problem.line)?;
+                }
+                None => return Ok(selected == problems.len()),
+            }
+        }
+    }
+
+    fn try_send(&mut self, state: &mut ApplicationState, files: &[&PathBuf]) -> Result<()> {
+        // The field contents can only be created after the upload,
+        // because Anki may store the media under a different name
+        let stored_media = state.anki.store_files(files)?;
+
+        let prepared = Self::prepare_note(state, &stored_media);
+        let destination = NoteDestination {
+            deck_name: &state.anki_config.deck_name,
+            note_type: &state.anki_config.note_type,
+            note_to_update: state.note_to_update,
+        };
+        let Some(note_id) = send_prepared_note(
+            state.anki.as_ref(),
+            destination,
+            &state.selected_tags,
+            &stored_media,
+            prepared,
+        )?
+        else {
+            self.should_quit = false;
+            return Ok(());
+        };
+        self.sent_note = Some(note_id);
+        self.should_quit = true;
+
+        // Anki is evidently reachable again, so this is a good time to send queued notes
+        flush_queued_notes(&mut state.tui, state.anki.as_ref())
+    }
+
+    fn queue_for_later(state: &mut ApplicationState, files: &[&PathBuf]) -> Result<()> {
+        // Until the note is sent, the media files are referred to by their local names
+        let local_media = name_queued_media(files)?;
+        let mut fields = Self::get_field_contents_for_mapping(state, &local_media)?;
+        if state.note_to_update.is_some() {
+            fields = select_fields_to_update(&state.anki_config, fields);
+        }
+
+        let note = QueuedNote {
+            deck_name: state.anki_config.deck_name.clone(),
+            note_type: state.anki_config.note_type.clone(),
+            fields,
+            tags: state.selected_tags.clone(),
+            media: local_media.values().cloned().collect(),
+            note_to_update: state.note_to_update,
+        };
+        queue_note(&get_outbox_dir()?, &note, &local_media)?;
+        Ok(())
+    }
+
+    /// Returns the fields to send, or `None` if the user cancelled because of similar notes
+    fn prepare_note(
+        state: &mut ApplicationState,
+        stored_media: &StoredMedia,
+    ) -> Result<Option<HashMap<String, String>>> {
+        let field_content = Self::get_field_contents_for_mapping(state, stored_media)?;
+
+        if state.note_to_update.is_some() {
+            return Ok(Some(select_fields_to_update(
+                &state.anki_config,
+                field_content,
+            )));
+        }
+
+        if !Self::resolve_duplicates(state, &field_content)? {
+            return Ok(None);
+        }
+        Ok(Some(field_content))
+    }
+
+    /// Returns whether the note should be added
+    fn resolve_duplicates(
+        state: &mut ApplicationState,
+        field_content: &HashMap<String, String>,
+    ) -> Result<bool> {
+        let policy = state.anki_config.duplicate_policy;
+        if policy == DuplicatePolicy::Allow {
+            return Ok(true);
+        }
+
+        let similar_notes =
+            find_similar_notes(state.anki.as_ref(), &state.anki_config, field_content)?;
+        if similar_notes.is_empty() {
+            return Ok(true);
+        }
+
+        loop {
+            match select_duplicate_resolution(&mut state.tui, &similar_notes, policy)? {
+                DuplicateResolution::AddAnyway => return Ok(true),
+                DuplicateResolution::Cancel => return Ok(false),
+                DuplicateResolution::OpenInBrowser(note_id) => {
+                    browse_note(&mut state.tui, state.anki.as_ref(), note_id)?
+                }
+            }
+        }
+    }
+
+    fn get_field_contents_for_mapping(
+        state: &ApplicationState,
+        stored_media: &StoredMedia,
+    ) -> Result<HashMap<String, String>> {
         let field_mapping = &state.anki_config.field_mapping;
         let mut field_contents = HashMap::with_capacity(field_mapping.len());
 
         for (field_name, content_type) in field_mapping {
-            let content = content_type.get_anki_card_content(state)?;
+            let content = content_type.get_anki_card_content(field_name, state, stored_media)?;
             field_contents.insert(field_name.clone(), content);
         }
 
@@ -87,6 +299,330 @@ impl SendCardAction {
     }
 }
 
+/// Sends the notes in the outbox and shows the results. This is best effort: the note that
+/// was just sent, or the startup, must not fail because of a broken outbox entry.
+pub(crate) fn flush_queued_notes(tui: &mut Tui, anki: &dyn AnkiBackend) -> Result<()> {
+    let lines: Vec<String> = match get_outbox_dir().and_then(|dir| flush_outbox(anki, &dir)) {
+        Ok(results) if results.is_empty() => return Ok(()),
+        Ok(results) => results.iter().map(FlushResult::describe).collect(),
+        Err(e) => vec![format!("Sending the queued notes failed: {:#}", e)],
+    };
+    tui.show_single_selection_menu("Sent queued notes (Enter to continue)", &lines)?;
+    Ok(())
+}
+
+/// Sends the card like [`SendCardAction`] and shows the note in the browser of Anki
+pub struct SendAndBrowseAction {
+    send_action: SendCardAction,
+}
+impl MenuAction for SendAndBrowseAction {
+    fn new() -> Self {
+        Self {
+            send_action: SendCardAction::new(),
+        }
+    }
+
+    fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
+        self.send_action.act(state)?;
+
+        if let Some(note_id) = self.send_action.sent_note {
+            browse_note(&mut state.tui, state.anki.as_ref(), note_id)?;
+        }
+        Ok(())
+    }
+    fn should_exit(&self) -> bool {
+        self.send_action.should_exit()
+    }
+}
+
+/// Opens the note in the browser of Anki. Failing to open it is only reported, together with
+/// the search that finds it, because the note itself was already sent.
+fn browse_note(tui: &mut Tui, anki: &dyn AnkiBackend, note_id: u64) -> Result<()> {
+    let query = format!("nid:{}", note_id);
+    if let Err(e) = anki.gui_browse(&query) {
+        let msg = format!(
+            "Opening the browser failed: {}. Search for {} in Anki",
+            e, query
+        );
+        show_message(tui, &msg)?;
+    }
+    Ok(())
+}
+
+/// Checks that the images of the `!IMAGE!` directives exist and warns about sections
+/// that are mapped to a field but missing in the front and back
+fn check_sections(state: &mut ApplicationState) -> Result<bool> {
+    let sections = read_note_sections(&state.tmp_dir)?;
+    let images = image_directive_paths(state)?;
+    if let Some(missing_image) = images.iter().find(|path| !path.is_file()) {
+        let msg = format!(
+            "The image {} of {} doesn't exist",
+            missing_image.display(),
+            IMAGE_DIRECTIVE
+        );
+        show_message(&mut state.tui, &msg)?;
+        return Ok(false);
+    }
+
+    let missing_sections: Vec<String> = state
+        .anki_config
+        .field_mapping
+        .iter()
+        .filter_map(|(field_name, content)| match content {
+            PossibleContent::Section(name) if sections.section(name).is_none() => {
+                Some(format!("!{}! (field {})", name, field_name))
+            }
+            _ => None,
+        })
+        .collect();
+    if missing_sections.is_empty() {
+        return Ok(true);
+    }
+
+    let msg = format!(
+        "The front and back have no section {}. Send anyway?",
+        missing_sections.join(", ")
+    );
+    state.tui.show_dialog(&msg)
+}
+
+/// Opens the Add dialog of Anki filled with the card instead of adding it directly
+pub struct OpenAddDialogAction {
+    should_quit: bool,
+}
+impl MenuAction for OpenAddDialogAction {
+    fn new() -> Self {
+        Self { should_quit: true }
+    }
+
+    fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
+        if !check_sections(state)? {
+            self.should_quit = false;
+            return Ok(());
+        }
+
+        let images = note_images(state)?;
+        let files: Vec<&PathBuf> = images.iter().collect();
+
+        loop {
+            match Self::open_add_dialog(state, &files) {
+                Ok(()) => return Ok(()),
+                Err(e) => match e.downcast::<AnkiConnectError>() {
+                    Ok(
+                        anki_error @ (AnkiConnectError::NotRunning
+                        | AnkiConnectError::InvalidApiKey),
+                    ) => check_should_retry(anki_error, &mut state.tui, state.anki.as_ref())?,
+                    Ok(anki_error) => {
+                        let msg = format!("Opening the Add dialog failed: {}", anki_error);
+                        show_message(&mut state.tui, &msg)?;
+                        self.should_quit = false;
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e),
+                },
+            }
+        }
+    }
+    fn should_exit(&self) -> bool {
+        self.should_quit
+    }
+}
+
+impl OpenAddDialogAction {
+    /// The media has to be stored first, so that the dialog can show it.
+    /// If the dialog is closed without adding, Anki's "Check Media" removes it.
+    fn open_add_dialog(state: &mut ApplicationState, files: &[&PathBuf]) -> Result<()> {
+        let stored_media = state.anki.store_files(files)?;
+
+        let result = SendCardAction::get_field_contents_for_mapping(state, &stored_media).and_then(
+            |field_content| {
+                state
+                    .anki
+                    .gui_add_cards(
+                        &state.anki_config.deck_name,
+                        &state.anki_config.note_type,
+                        &field_content,
+                        &state.selected_tags,
+                    )
+                    .map_err(Into::into)
+            },
+        );
+        if result.is_err() {
+            let _ = state.anki.delete_media_files(&stored_media);
+        }
+        result
+    }
+}
+
+pub struct AddImageAction {}
+impl MenuAction for AddImageAction {
+    fn new() -> Self {
+        Self {}
+    }
+
+    fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
+        if let Some(path) = capture_image(state)? {
+            state.screenshot_paths.push(path);
+        }
+        Ok(())
+    }
+    fn should_exit(&self) -> bool {
+        false
+    }
+}
+
+pub struct RetakeImageAction {}
+impl MenuAction for RetakeImageAction {
+    fn new() -> Self {
+        Self {}
+    }
+
+    fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
+        let Some(index) = choose_image(state, "Image to retake")? else {
+            return Ok(());
+        };
+        if let Some(path) = capture_image(state)? {
+            state.screenshot_paths[index] = path;
+        }
+        Ok(())
+    }
+    fn should_exit(&self) -> bool {
+        false
+    }
+}
+
+pub struct RemoveImageAction {}
+impl MenuAction for RemoveImageAction {
+    fn new() -> Self {
+        Self {}
+    }
+
+    fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
+        let image_count = state.screenshot_paths.len();
+        let Some(index) = choose_image(state, "Image to remove")? else {
+            return Ok(());
+        };
+        // The only image is chosen without a menu, so ask before removing it
+        if image_count == 1 {
+            let name = state.screenshot_paths[index]
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            let title = format!("Remove {}, the only image of the note?", name);
+            if state
+                .tui
+                .show_single_selection_menu(&title, &["Remove", "Cancel"])?
+                != 0
+            {
+                return Ok(());
+            }
+        }
+        state.screenshot_paths.remove(index);
+        Ok(())
+    }
+    fn should_exit(&self) -> bool {
+        false
+    }
+}
+
+/// Lets the user choose one of the images, without asking if there is only one.
+/// Returns `None` if there are no images or the user cancelled.
+fn choose_image(state: &mut ApplicationState, title: &str) -> Result<Option<usize>> {
+    match state.screenshot_paths.len() {
+        0 => {
+            show_message(&mut state.tui, "The note has no images")?;
+            Ok(None)
+        }
+        1 => Ok(Some(0)),
+        image_count => {
+            let mut menu_items: Vec<String> = state
+                .screenshot_paths
+                .iter()
+                .map(|path| {
+                    path.file_name()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect();
+            menu_items.push("Cancel".to_string());
+
+            let selected = state.tui.show_single_selection_menu(title, &menu_items)?;
+            Ok((selected < image_count).then_some(selected))
+        }
+    }
+}
+
+/// Captures an image with the configured backends and saves it in the temporary directory.
+/// The builtin backend is left out, as it would capture this terminal instead of the book.
+/// If the book can be rendered, the user can choose another page, e.g. for a proof that
+/// continues on the next page. Returns `None` if the user cancelled or capturing failed.
+fn capture_image(state: &mut ApplicationState) -> Result<Option<PathBuf>> {
+    let mut settings = state.image_capture.settings.clone();
+    settings
+        .backends
+        .retain(|backend| *backend != BackendKind::Builtin);
+    let can_render_book = settings.backends.contains(&BackendKind::Pdf)
+        && state.image_capture.book_path.is_some()
+        && state.page_number.is_some();
+    if !can_render_book && !settings.backends.contains(&BackendKind::Command) {
+        show_message(
+            &mut state.tui,
+            "Capturing from here needs the pdf backend with the book and page, or the command \
+             backend. The builtin backend would capture this terminal.",
+        )?;
+        return Ok(None);
+    }
+
+    let mut page_number = state.page_number;
+    if let Some(current_page) = state
+        .page_number
+        .filter(|_| state.image_capture.book_path.is_some())
+    {
+        let title = format!("Page to capture (empty for page {})", current_page);
+        let Some(input) = state.tui.show_text_input(&title, false)? else {
+            return Ok(None);
+        };
+        if !input.trim().is_empty() {
+            match input.trim().parse() {
+                Ok(page) => page_number = Some(page),
+                Err(_) => {
+                    show_message(&mut state.tui, &format!("Invalid page number: {}", input))?;
+                    return Ok(None);
+                }
+            }
+        }
+    }
+
+    let pdf_page = state
+        .image_capture
+        .book_path
+        .clone()
+        .zip(page_number)
+        .map(|(path, page)| PdfPage {
+            path,
+            page,
+            rect: None,
+        });
+    let screenshot = create_backends(&settings, None, pdf_page).and_then(|backends| {
+        take_screenshot(
+            &settings,
+            &backends,
+            state.book_filename.as_deref(),
+            page_number,
+        )
+    });
+
+    match screenshot {
+        Ok(screenshot) => Ok(Some(save_screenshot(&state.tmp_dir, &screenshot)?)),
+        Err(e) => {
+            show_message(&mut state.tui, &format!("Capturing failed: {:#}", e))?;
+            Ok(None)
+        }
+    }
+}
+
 pub struct EditAnkiSettings {}
 impl MenuAction for EditAnkiSettings {
     fn new() -> Self {
@@ -94,7 +630,14 @@ impl MenuAction for EditAnkiSettings {
     }
 
     fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
-        let settings = vec!["Deck", "Note Type", "Field Mapping", "Return"];
+        let settings = vec![
+            "Deck",
+            "Note Type",
+            "Field Mapping",
+            "Duplicate Check",
+            "Math Format",
+            "Return",
+        ];
 
         loop {
             let selection = state
@@ -105,6 +648,8 @@ impl MenuAction for EditAnkiSettings {
                 0 => self.edit_deck(state)?,
                 1 => self.edit_note_type(state)?,
                 2 => self.edit_field_mapping(state)?,
+                3 => self.edit_duplicate_check(state)?,
+                4 => self.edit_math_format(state)?,
                 _ => break,
             }
         }
@@ -119,13 +664,13 @@ impl MenuAction for EditAnkiSettings {
 
 impl EditAnkiSettings {
     fn edit_deck(&self, state: &mut ApplicationState) -> Result<()> {
-        let new_deck = select_anki_deck(&mut state.tui)?;
+        let new_deck = select_anki_deck(&mut state.tui, state.anki.as_ref())?;
         state.anki_config.deck_name = new_deck;
         Ok(())
     }
 
     fn edit_note_type(&self, state: &mut ApplicationState) -> Result<()> {
-        let new_note_type = select_anki_note_type(&mut state.tui)?;
+        let new_note_type = select_anki_note_type(&mut state.tui, state.anki.as_ref())?;
         if new_note_type != state.anki_config.note_type {
             state.anki_config.note_type = new_note_type;
             self.edit_field_mapping(state)?;
@@ -133,12 +678,107 @@ impl EditAnkiSettings {
         Ok(())
     }
 
+    fn edit_duplicate_check(&self, state: &mut ApplicationState) -> Result<()> {
+        state.anki_config.duplicate_scope = select_duplicate_scope(&mut state.tui)?;
+        state.anki_config.duplicate_policy = select_duplicate_policy(&mut state.tui)?;
+        Ok(())
+    }
+
+    /// Lets the user choose a field and how LaTeX is put into it
+    fn edit_math_format(&self, state: &mut ApplicationState) -> Result<()> {
+        let field_names: Vec<String> = state
+            .anki_config
+            .field_mapping
+            .iter()
+            .filter(|(_, content)| {
+                matches!(
+                    content,
+                    PossibleContent::Front
+                        | PossibleContent::Back
+                        | PossibleContent::Section(_)
+                        | PossibleContent::Template(_)
+                )
+            })
+            .map(|(field_name, _)| field_name.clone())
+            .collect();
+        if field_names.is_empty() {
+            return show_message(&mut state.tui, "No field contains the front or the back");
+        }
+
+        let mut menu_items: Vec<String> = field_names
+            .iter()
+            .map(|field_name| {
+                format!(
+                    "{} ({:?})",
+                    field_name,
+                    state.anki_config.math_format(field_name)
+                )
+            })
+            .collect();
+        menu_items.push("Return".to_string());
+
+        let selected = state
+            .tui
+            .show_single_selection_menu("Choose the field", &menu_items)?;
+        if let Some(field_name) = field_names.get(selected) {
+            let format = select_math_format(&mut state.tui, field_name)?;
+            state
+                .anki_config
+                .math_formats
+                .insert(field_name.clone(), format);
+        }
+        Ok(())
+    }
+
     fn edit_field_mapping(&self, state: &mut ApplicationState) -> Result<()> {
-        let new_field_mapping =
-            select_field_mapping_for_note_type(&mut state.tui, &state.anki_config.note_type)?;
-        state.anki_config.field_mapping = new_field_mapping;
+        let new_field_mapping = select_field_mapping_for_note_type(
+            &mut state.tui,
+            state.anki.as_ref(),
+            &state.anki_config.note_type,
+            &find_section_names(&state.tmp_dir),
+        )?;
+        state.anki_config.set_field_mapping(new_field_mapping);
+        Ok(())
+    }
+}
+
+pub struct UpdateExistingNoteAction {}
+impl MenuAction for UpdateExistingNoteAction {
+    fn new() -> Self {
+        Self {}
+    }
+
+    fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
+        let Some(note) = choose_note_to_update(
+            &mut state.tui,
+            state.anki.as_ref(),
+            &state.anki_config,
+            state.book_filename.as_deref(),
+            state.page_number,
+        )?
+        else {
+            return Ok(());
+        };
+
+        if !state
+            .tui
+            .show_dialog("Replace the current front and back with this note?")?
+        {
+            return Ok(());
+        }
+
+        load_note_into_files(&note, &state.anki_config, &state.tmp_dir)?;
+        let preamble = state_preamble(state);
+        edit_front(&mut state.tui, &state.tmp_dir, preamble.as_deref())?;
+        edit_back(&mut state.tui, &state.tmp_dir, preamble.as_deref())?;
+
+        state.selected_tags = note.tags;
+        state.note_to_update = Some(note.note_id);
         Ok(())
     }
+    fn should_exit(&self) -> bool {
+        false
+    }
 }
 
 pub struct EditFrontAction {}
@@ -148,7 +788,8 @@ impl MenuAction for EditFrontAction {
     }
 
     fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
-        edit_front(&mut state.tui, &state.tmp_dir)
+        let preamble = state_preamble(state);
+        edit_front(&mut state.tui, &state.tmp_dir, preamble.as_deref())
     }
     fn should_exit(&self) -> bool {
         false
@@ -162,9 +803,127 @@ impl MenuAction for EditBackAction {
     }
 
     fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
-        edit_back(&mut state.tui, &state.tmp_dir)
+        let preamble = state_preamble(state);
+        edit_back(&mut state.tui, &state.tmp_dir, preamble.as_deref())
     }
     fn should_exit(&self) -> bool {
         false
     }
 }
+
+pub struct PreviewAction {}
+impl MenuAction for PreviewAction {
+    fn new() -> Self {
+        Self {}
+    }
+
+    fn act(&mut self, state: &mut ApplicationState) -> Result<()> {
+        if let Err(e) = Self::preview(state) {
+            show_message(&mut state.tui, &format!("Preview failed: {:#}", e))?;
+        }
+        Ok(())
+    }
+    fn should_exit(&self) -> bool {
+        false
+    }
+}
+
+impl PreviewAction {
+    /// Renders the fields that Anki renders as LaTeX images with the local toolchain,
+    /// the way Anki renders them
+    fn preview(state: &mut ApplicationState) -> Result<()> {
+        let bodies = Self::latex_fields(state)?;
+        if bodies.is_empty() {
+            return show_message(&mut state.tui, "No field with LaTeX has any text");
+        }
+
+        let mut preamble =
+            state_preamble(state).unwrap_or_else(|| BOOKMINER_LATEX_PREAMBLE.to_string());
+        if !preamble.contains(r"\begin{document}") {
+            preamble.push_str("\n\\begin{document}\n");
+        }
+
+        let dir = state.tmp_dir.join("preview");
+        fs::create_dir_all(&dir).context("Creating preview directory")?;
+        let mut images = Vec::new();
+        for (index, (field_name, body)) in bodies.iter().enumerate() {
+            // Field names can contain characters that latex doesn't accept in file names
+            match render_latex(&preamble, body, &dir, &format!("field{}", index))? {
+                RenderResult::Image(path) => images.push(path),
+                RenderResult::Failed { log } => {
+                    let output = format!("Compiling the field {} failed:\n\n{}", field_name, log);
+                    return state.tui.show_terminal_output(&output);
+                }
+            }
+        }
+
+        let protocol = detect_protocol(state.latex.preview_protocol).terminal_protocol();
+        let all_png = images
+            .iter()
+            .all(|path| path.extension().is_some_and(|extension| extension == "png"));
+        match (&state.latex.preview_viewer, protocol) {
+            (_, Some(protocol)) if all_png => {
+                let output = terminal_images(&images, protocol)?;
+                state.tui.show_terminal_output(&output)
+            }
+            (Some(viewer), _) => open_in_viewer(viewer, &images),
+            (None, _) => show_message(
+                &mut state.tui,
+                "Set preview_viewer in the [latex] settings to show the preview \
+                 outside of kitty or sixel terminals",
+            ),
+        }
+    }
+
+    /// The LaTeX document bodies of the mapped fields that aren't empty, wrapped like Anki
+    /// wraps them for their math format. MathJax fields are rendered by LaTeX as well, which
+    /// is close to what MathJax shows, and raw fields are HTML, so they are left out.
+    fn latex_fields(state: &ApplicationState) -> Result<Vec<(String, String)>> {
+        let sections = read_note_sections(&state.tmp_dir)?;
+        let mut bodies = Vec::new();
+        for (field_name, content) in &state.anki_config.field_mapping {
+            let (source, format) = match content {
+                PossibleContent::Front => (sections.front.clone(), None),
+                PossibleContent::Back => (sections.back.clone(), None),
+                PossibleContent::Section(name) => {
+                    (sections.section(name).unwrap_or_default().to_string(), None)
+                }
+                // Cloze fields are always put into [latex] blocks
+                PossibleContent::Cloze => {
+                    let text = split_cloze_segments(&sections.front)?
+                        .into_iter()
+                        .map(|segment| match segment {
+                            ClozeSegment::Text(text) | ClozeSegment::Deletion { text, .. } => text,
+                        })
+                        .collect();
+                    (text, Some(MathFormat::Latex))
+                }
+                _ => continue,
+            };
+            if source.trim().is_empty() {
+                continue;
+            }
+
+            let format = format.unwrap_or_else(|| state.anki_config.math_format(field_name));
+            let body = match format {
+                MathFormat::Latex | MathFormat::MathJax => source,
+                MathFormat::LatexMath => format!("${}$", source),
+                MathFormat::LatexDisplayMath => {
+                    format!("\\begin{{displaymath}}{}\\end{{displaymath}}", source)
+                }
+                MathFormat::Raw => continue,
+            };
+            bodies.push((field_name.clone(), body));
+        }
+        Ok(bodies)
+    }
+}
+
+/// The preamble of the selected note type, see [`note_type_preamble`]
+fn state_preamble(state: &ApplicationState) -> Option<String> {
+    note_type_preamble(
+        state.anki.as_ref(),
+        Some(&state.anki_config.note_type),
+        &state.latex,
+    )
+}
@@ -0,0 +1,59 @@
+use crate::anki_backend::{AnkiBackend, StoredMedia};
+use crate::ankiconnect::AnkiConnectError;
+use anyhow::Result;
+use std::collections::HashMap;
+
+/// Where a finished note goes
+#[derive(Debug, Clone, Copy)]
+pub struct NoteDestination<'a> {
+    pub deck_name: &'a str,
+    pub note_type: &'a str,
+    /// If set, the note updates this existing note instead of adding a new one
+    pub note_to_update: Option<u64>,
+}
+
+/// Adds the note, or updates the existing one, and returns its ID.
+/// Both roll back the media themselves if Anki rejects the note.
+pub fn send_or_update(
+    anki: &dyn AnkiBackend,
+    destination: NoteDestination,
+    fields: &HashMap<String, String>,
+    tags: &[String],
+    stored_media: &StoredMedia,
+) -> Result<u64, AnkiConnectError> {
+    match destination.note_to_update {
+        Some(note_id) => anki
+            .update_note(note_id, fields, tags, stored_media)
+            .map(|()| note_id),
+        None => anki.send_note(
+            destination.deck_name,
+            destination.note_type,
+            fields,
+            tags,
+            stored_media,
+        ),
+    }
+}
+
+/// Sends a note whose media is already stored. `prepared` are its fields, or `None` if the
+/// user cancelled. If it was cancelled or preparing it failed, the media is deleted, so that
+/// no orphaned media is left behind. Returns the ID of the note, or `None` if cancelled.
+pub fn send_prepared_note(
+    anki: &dyn AnkiBackend,
+    destination: NoteDestination,
+    tags: &[String],
+    stored_media: &StoredMedia,
+    prepared: Result<Option<HashMap<String, String>>>,
+) -> Result<Option<u64>> {
+    let Ok(Some(fields)) = prepared else {
+        let _ = anki.delete_media_files(stored_media);
+        return prepared.map(|_| None);
+    };
+    Ok(Some(send_or_update(
+        anki,
+        destination,
+        &fields,
+        tags,
+        stored_media,
+    )?))
+}
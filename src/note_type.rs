@@ -0,0 +1,122 @@
+use crate::possible_entries::PossibleContent;
+
+pub const BOOKMINER_NOTE_TYPE: &str = "bookminer";
+
+/// Suggested LaTeX preamble for the bookminer note type.
+/// `createModel` can't set the preamble, so it has to be pasted into the note type options.
+pub const BOOKMINER_LATEX_PREAMBLE: &str = r"\documentclass[12pt]{article}
+\special{papersize=3in,5in}
+\usepackage[utf8]{inputenc}
+\usepackage{amssymb,amsmath,amsthm,mathtools}
+\pagestyle{empty}
+\setlength{\parindent}{0in}
+\begin{document}
+";
+
+const BOOKMINER_CSS: &str = ".card {
+    font-family: arial;
+    font-size: 20px;
+    text-align: center;
+    color: black;
+    background-color: white;
+}
+
+.source {
+    font-size: 14px;
+    color: gray;
+}
+";
+
+pub struct CardTemplate {
+    pub name: String,
+    pub front: String,
+    pub back: String,
+}
+
+/// A note type that bookminer can create with `createModel`
+pub struct NewNoteType {
+    pub name: String,
+    pub fields: Vec<String>,
+    pub css: String,
+    pub card_templates: Vec<CardTemplate>,
+}
+
+pub fn bookminer_note_type() -> NewNoteType {
+    let front = "{{Front}}".to_string();
+    let back = "{{FrontSide}}\n\n<hr id=answer>\n\n{{Back}}\n\n\
+                {{#Screenshot}}<div>{{Screenshot}}</div>{{/Screenshot}}\n\
+                <div class=\"source\">{{Source}}{{#Page}}, p. {{Page}}{{/Page}}</div>"
+        .to_string();
+
+    NewNoteType {
+        name: BOOKMINER_NOTE_TYPE.to_string(),
+        fields: bookminer_field_mapping()
+            .into_iter()
+            .map(|(field_name, _)| field_name)
+            .collect(),
+        css: BOOKMINER_CSS.to_string(),
+        card_templates: vec![CardTemplate {
+            name: "Card 1".to_string(),
+            front,
+            back,
+        }],
+    }
+}
+
+fn bookminer_field_mapping() -> Vec<(String, PossibleContent)> {
+    vec![
+        ("Front".to_string(), PossibleContent::Front),
+        ("Back".to_string(), PossibleContent::Back),
+        ("Screenshot".to_string(), PossibleContent::Screenshot),
+        ("Source".to_string(), PossibleContent::FileName),
+        ("Page".to_string(), PossibleContent::PageNumber),
+    ]
+}
+
+/// Returns the field mapping for note types created by bookminer,
+/// so that the user doesn't have to map the fields by hand.
+pub fn known_field_mapping(
+    note_type: &str,
+    field_names: &[String],
+) -> Option<Vec<(String, PossibleContent)>> {
+    let field_mapping = bookminer_field_mapping();
+    let same_fields = field_mapping
+        .iter()
+        .map(|(field_name, _)| field_name)
+        .eq(field_names.iter());
+
+    (note_type == BOOKMINER_NOTE_TYPE && same_fields).then_some(field_mapping)
+}
+
+/// Trims every part of a `Parent::Child` deck name.
+/// Returns `None` if the name or one of its parts is empty.
+pub fn normalize_deck_name(name: &str) -> Option<String> {
+    let parts: Vec<&str> = name.split("::").map(str::trim).collect();
+    if parts.iter().any(|part| part.is_empty()) {
+        return None;
+    }
+    Some(parts.join("::"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_deck_name() {
+        assert_eq!(
+            normalize_deck_name(" Analysis :: Measure Theory"),
+            Some("Analysis::Measure Theory".to_string())
+        );
+        assert_eq!(normalize_deck_name("Analysis::"), None);
+        assert_eq!(normalize_deck_name("  "), None);
+    }
+
+    #[test]
+    fn test_known_field_mapping_requires_unchanged_fields() {
+        let fields = bookminer_note_type().fields;
+        assert!(known_field_mapping(BOOKMINER_NOTE_TYPE, &fields).is_some());
+        assert!(known_field_mapping("Basic", &fields).is_none());
+        assert!(known_field_mapping(BOOKMINER_NOTE_TYPE, &fields[..2]).is_none());
+    }
+}
@@ -0,0 +1,151 @@
+use crate::anki_backend::{AnkiBackend, NoteInfo};
+use crate::anki_config::AnkiConfig;
+use crate::anki_search::search_term;
+use crate::ankiconnect::AnkiConnectError;
+use crate::possible_entries::{extract_cloze_field_source, extract_latex_source, PossibleContent};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// Creates the query for notes of the configured note type that were mined from the given page.
+/// Returns `None` if the file name and page number are unknown or not mapped to any field.
+pub fn create_source_query(
+    config: &AnkiConfig,
+    book_filename: Option<&str>,
+    page_number: Option<u32>,
+) -> Option<String> {
+    let file_field = config.field_for_content(&PossibleContent::FileName)?;
+    let page_field = config.field_for_content(&PossibleContent::PageNumber)?;
+
+    Some(
+        [
+            search_term("note", &config.note_type),
+            search_term(file_field, book_filename?),
+            search_term(page_field, &page_number?.to_string()),
+        ]
+        .join(" "),
+    )
+}
+
+pub fn find_notes_from_page(
+    anki: &dyn AnkiBackend,
+    query: &str,
+) -> Result<Vec<NoteInfo>, AnkiConnectError> {
+    let note_ids = anki.find_notes(query)?;
+    if note_ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    anki.notes_info(&note_ids)
+}
+
+/// Writes the front and back fields of `note` into `front.tex` and `back.tex`.
+/// Without a front field, the cloze field is loaded into `front.tex`.
+/// Fields mapped to sections are appended to `back.tex` after their markers.
+pub fn load_note_into_files(note: &NoteInfo, config: &AnkiConfig, tmp_dir: &Path) -> Result<()> {
+    let field_value = |content: &PossibleContent| {
+        config
+            .field_for_content(content)
+            .and_then(|field_name| note.field(field_name))
+    };
+
+    let latex_source = |content: &PossibleContent| {
+        config.field_for_content(content).and_then(|field_name| {
+            let format = config.math_format(field_name);
+            note.field(field_name)
+                .map(|value| extract_latex_source(value, format))
+        })
+    };
+
+    let front = match latex_source(&PossibleContent::Front) {
+        Some(front) => front,
+        None => field_value(&PossibleContent::Cloze)
+            .map(extract_cloze_field_source)
+            .unwrap_or_default(),
+    };
+    let mut back = latex_source(&PossibleContent::Back).unwrap_or_default();
+
+    for (field_name, content) in &config.field_mapping {
+        let PossibleContent::Section(name) = content else {
+            continue;
+        };
+        let section = note
+            .field(field_name)
+            .map(|value| extract_latex_source(value, config.math_format(field_name)))
+            .unwrap_or_default();
+        if !section.trim().is_empty() {
+            back.push_str(&format!("\n\n!{}!\n{}", name, section));
+        }
+    }
+
+    for (text, filename) in [(front, "front.tex"), (back, "back.tex")] {
+        fs::write(tmp_dir.join(filename), text).with_context(|| format!("Writing {}", filename))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the fields that should be overwritten when updating a note.
+/// Fields mapped to [`PossibleContent::Empty`] are left alone, just like unmapped fields.
+pub fn select_fields_to_update(
+    config: &AnkiConfig,
+    field_contents: HashMap<String, String>,
+) -> HashMap<String, String> {
+    field_contents
+        .into_iter()
+        .filter(|(field_name, _)| {
+            config
+                .field_mapping
+                .iter()
+                .any(|(name, content)| name == field_name && *content != PossibleContent::Empty)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_config() -> AnkiConfig {
+        let field_mapping = vec![
+            ("Front".to_string(), PossibleContent::Front),
+            ("Notes".to_string(), PossibleContent::Empty),
+            ("File".to_string(), PossibleContent::FileName),
+            ("Page".to_string(), PossibleContent::PageNumber),
+        ];
+        AnkiConfig::new("Default", "Book", field_mapping)
+    }
+
+    #[test]
+    fn test_source_query() {
+        let query = create_source_query(&create_config(), Some("my_book.pdf"), Some(7));
+
+        assert_eq!(
+            query.as_deref(),
+            Some(r#""note:Book" "File:my\_book.pdf" "Page:7""#)
+        );
+    }
+
+    #[test]
+    fn test_source_query_without_page() {
+        assert_eq!(
+            create_source_query(&create_config(), Some("book.pdf"), None),
+            None
+        );
+    }
+
+    #[test]
+    fn test_empty_fields_are_not_updated() {
+        let field_contents = HashMap::from([
+            ("Front".to_string(), "new front".to_string()),
+            ("Notes".to_string(), "".to_string()),
+        ]);
+
+        let fields = select_fields_to_update(&create_config(), field_contents);
+
+        assert_eq!(
+            fields,
+            HashMap::from([("Front".to_string(), "new front".to_string())])
+        );
+    }
+}
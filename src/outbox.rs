@@ -0,0 +1,183 @@
+use crate::anki_backend::{AnkiBackend, StoredMedia};
+use crate::ankiconnect::AnkiConnectError;
+use crate::note_send::{send_or_update, NoteDestination};
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const NOTE_FILE: &str = "note.json";
+
+/// A finished note that couldn't be sent because Anki wasn't running.
+///
+/// Media files are referenced by their local names. They are stored in the same
+/// directory as the note and renamed in the fields if Anki renames them when flushing.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct QueuedNote {
+    pub deck_name: String,
+    pub note_type: String,
+    pub fields: HashMap<String, String>,
+    pub tags: Vec<String>,
+    pub media: Vec<String>,
+    /// If set, the note updates this existing note instead of adding a new one
+    pub note_to_update: Option<u64>,
+}
+
+pub struct FlushResult {
+    /// Name of the outbox entry
+    pub entry: String,
+    pub deck_name: String,
+    pub result: Result<(), AnkiConnectError>,
+}
+
+impl FlushResult {
+    pub fn describe(&self) -> String {
+        match &self.result {
+            Ok(()) => format!("{} ({}): sent", self.entry, self.deck_name),
+            Err(e) => format!("{} ({}): failed: {}", self.entry, self.deck_name, e),
+        }
+    }
+}
+
+/// Names the media files of a note in its outbox entry. A file with the same name as an
+/// earlier one from another directory gets a number appended, so that it doesn't overwrite it.
+pub fn name_queued_media(media_files: &[&PathBuf]) -> Result<StoredMedia> {
+    let mut media = StoredMedia::new();
+    for file in media_files {
+        if media.contains_key(*file) {
+            continue;
+        }
+        let filename = file
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("Invalid media filename {}", file.display()))?;
+        let (stem, extension) = match filename.rsplit_once('.') {
+            Some((stem, extension)) => (stem, format!(".{}", extension)),
+            None => (filename, String::new()),
+        };
+
+        let mut name = filename.to_string();
+        let mut counter = 0;
+        while media.values().any(|taken| *taken == name) {
+            counter += 1;
+            name = format!("{}-{}{}", stem, counter, extension);
+        }
+        media.insert(file.to_path_buf(), name);
+    }
+    Ok(media)
+}
+
+/// Saves a note together with copies of its media files in a new outbox entry.
+/// The files are copied under the names of `media`, see [`name_queued_media`].
+pub fn queue_note(outbox_dir: &Path, note: &QueuedNote, media: &StoredMedia) -> Result<PathBuf> {
+    let entry_name = Local::now().format("%Y%m%d_%H%M%S_%9f").to_string();
+    let entry_dir = outbox_dir.join(entry_name);
+    fs::create_dir_all(&entry_dir).context("Creating outbox entry")?;
+
+    for (file, name) in media {
+        fs::copy(file, entry_dir.join(name)).context("Copying media into outbox")?;
+    }
+
+    let note_json = serde_json::to_string_pretty(note).context("Converting queued note")?;
+    fs::write(entry_dir.join(NOTE_FILE), note_json).context("Writing queued note")?;
+
+    Ok(entry_dir)
+}
+
+/// Returns the outbox entries, oldest first
+pub fn queued_entries(outbox_dir: &Path) -> Result<Vec<PathBuf>> {
+    if !outbox_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut entries = fs::read_dir(outbox_dir)
+        .context("Reading outbox")?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| path.join(NOTE_FILE).exists())
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    Ok(entries)
+}
+
+fn load_queued_note(entry_dir: &Path) -> Result<QueuedNote> {
+    let note_json = fs::read_to_string(entry_dir.join(NOTE_FILE)).context("Reading queued note")?;
+    serde_json::from_str(&note_json).context("Parsing queued note")
+}
+
+/// Sends all queued notes. Successfully sent notes are removed from the outbox.
+/// Stops early if Anki is not running, leaving the remaining notes in the outbox.
+pub fn flush_outbox(anki: &dyn AnkiBackend, outbox_dir: &Path) -> Result<Vec<FlushResult>> {
+    let mut results = Vec::new();
+
+    for entry_dir in queued_entries(outbox_dir)? {
+        let note = load_queued_note(&entry_dir)?;
+        let result = send_queued_note(anki, &note, &entry_dir);
+
+        if result.is_ok() {
+            fs::remove_dir_all(&entry_dir).context("Removing sent note from outbox")?;
+        }
+
+        let not_running = matches!(result, Err(AnkiConnectError::NotRunning));
+        results.push(FlushResult {
+            entry: entry_dir
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            deck_name: note.deck_name,
+            result,
+        });
+
+        if not_running {
+            break;
+        }
+    }
+
+    Ok(results)
+}
+
+fn send_queued_note(
+    anki: &dyn AnkiBackend,
+    note: &QueuedNote,
+    entry_dir: &Path,
+) -> Result<(), AnkiConnectError> {
+    let media_files: Vec<PathBuf> = note.media.iter().map(|name| entry_dir.join(name)).collect();
+    let media_files: Vec<&PathBuf> = media_files.iter().collect();
+    let stored_media = anki.store_files(&media_files)?;
+    let fields = rename_media_in_fields(&note.fields, &stored_media);
+
+    let destination = NoteDestination {
+        deck_name: &note.deck_name,
+        note_type: &note.note_type,
+        note_to_update: note.note_to_update,
+    };
+    send_or_update(anki, destination, &fields, &note.tags, &stored_media).map(|_| ())
+}
+
+/// Replaces the local media names in the fields with the names Anki stored the files under
+fn rename_media_in_fields(
+    fields: &HashMap<String, String>,
+    stored_media: &StoredMedia,
+) -> HashMap<String, String> {
+    fields
+        .iter()
+        .map(|(field_name, value)| {
+            let mut value = value.clone();
+            for (path, stored_name) in stored_media {
+                let local_name = path
+                    .file_name()
+                    .map(|name| name.to_string_lossy())
+                    .unwrap_or_default();
+                if local_name != *stored_name {
+                    value = value.replace(
+                        &format!("src=\"{}\"", local_name),
+                        &format!("src=\"{}\"", stored_name),
+                    );
+                }
+            }
+            (field_name.clone(), value)
+        })
+        .collect()
+}
@@ -19,3 +19,24 @@ pub fn get_tags_file() -> Result<PathBuf> {
 pub fn get_anki_config_cache_file() -> Result<PathBuf> {
     Ok(get_project_data_dir()?.join("last_selection"))
 }
+
+pub fn get_outbox_dir() -> Result<PathBuf> {
+    Ok(get_project_data_dir()?.join("outbox"))
+}
+
+pub fn get_api_key_file() -> Result<PathBuf> {
+    Ok(get_project_data_dir()?.join("api_key"))
+}
+
+pub fn get_latex_preamble_file() -> Result<PathBuf> {
+    Ok(get_project_data_dir()?.join("preamble.tex"))
+}
+
+pub fn get_config_file() -> Result<PathBuf> {
+    let mut path = dirs::config_dir().ok_or_else(|| anyhow!("Getting config directory"))?;
+
+    path.push("bookminer");
+    path.push("config.toml");
+
+    Ok(path)
+}
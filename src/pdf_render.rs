@@ -0,0 +1,244 @@
+use anyhow::{anyhow, Context, Result};
+use screenshots::image::{self, RgbaImage};
+use std::fs::File;
+use std::io::{ErrorKind, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+/// Points per inch of PDF coordinates
+const POINTS_PER_INCH: f64 = 72.0;
+
+/// A page of a PDF file, with the page number as passed by the viewer (starting at 1)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PdfPage {
+    pub path: PathBuf,
+    pub page: u32,
+    /// Region of the page that the image is cropped to
+    pub rect: Option<PdfRect>,
+}
+
+/// A rectangle on a page in document coordinates: points (1/72 inch) from the top left corner.
+/// Parsed from `page,x0,y0,x1,y1`, as passed by Sioyek's `%{selected_rect}`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PdfRect {
+    /// Index of the page, starting at 0
+    pub page: u32,
+    pub x0: f64,
+    pub y0: f64,
+    pub x1: f64,
+    pub y1: f64,
+}
+
+impl FromStr for PdfRect {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+        let [page, x0, y0, x1, y1] = parts[..] else {
+            return Err(anyhow!("Expected page,x0,y0,x1,y1 but got \"{}\"", s));
+        };
+        let coordinate = |value: &str| -> Result<f64> {
+            value
+                .parse()
+                .map_err(|_| anyhow!("Invalid coordinate \"{}\" in \"{}\"", value, s))
+        };
+
+        let (x0, x1) = (coordinate(x0)?, coordinate(x1)?);
+        let (y0, y1) = (coordinate(y0)?, coordinate(y1)?);
+        Ok(PdfRect {
+            page: page
+                .parse()
+                .map_err(|_| anyhow!("Invalid page \"{}\" in \"{}\"", page, s))?,
+            x0: x0.min(x1),
+            y0: y0.min(y1),
+            x1: x0.max(x1),
+            y1: y0.max(y1),
+        })
+    }
+}
+
+impl PdfRect {
+    /// The number of the page, starting at 1 like [`PdfPage::page`]
+    pub fn page_number(&self) -> u32 {
+        self.page + 1
+    }
+
+    /// Converts the rectangle to pixels of a page rendered at `dpi` and clamps it to the image.
+    /// Returns `None` if nothing of the rectangle is on the image.
+    pub fn to_pixels(&self, dpi: u32, width: u32, height: u32) -> Option<(u32, u32, u32, u32)> {
+        let scale = dpi as f64 / POINTS_PER_INCH;
+        let to_pixel = |points: f64, max: u32| ((points * scale).round().max(0.0) as u32).min(max);
+
+        let (x0, x1) = (to_pixel(self.x0, width), to_pixel(self.x1, width));
+        let (y0, y1) = (to_pixel(self.y0, height), to_pixel(self.y1, height));
+        (x0 < x1 && y0 < y1).then_some((x0, y0, x1 - x0, y1 - y0))
+    }
+}
+
+/// Local programs that can render a PDF page, in the order they are tried
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PdfRenderer {
+    Pdftoppm,
+    Mutool,
+}
+
+const RENDERERS: [PdfRenderer; 2] = [PdfRenderer::Pdftoppm, PdfRenderer::Mutool];
+
+impl PdfRenderer {
+    /// Creates the command that renders `page` to the PNG file `output`
+    pub fn command(&self, page: &PdfPage, dpi: u32, output: &Path) -> Command {
+        match self {
+            PdfRenderer::Pdftoppm => {
+                // pdftoppm appends the extension to the output prefix itself
+                let mut command = Command::new("pdftoppm");
+                command
+                    .arg("-png")
+                    .arg("-singlefile")
+                    .args(["-r", &dpi.to_string()])
+                    .args(["-f", &page.page.to_string()])
+                    .args(["-l", &page.page.to_string()])
+                    .arg(&page.path)
+                    .arg(output.with_extension(""));
+                command
+            }
+            PdfRenderer::Mutool => {
+                let mut command = Command::new("mutool");
+                command
+                    .arg("draw")
+                    .args(["-r", &dpi.to_string()])
+                    .arg("-o")
+                    .arg(output)
+                    .arg(&page.path)
+                    .arg(page.page.to_string());
+                command
+            }
+        }
+    }
+}
+
+/// Checks the `%PDF-` header, the file name may not end with `.pdf`
+pub fn is_pdf(path: &Path) -> bool {
+    let mut header = [0; 5];
+    File::open(path)
+        .and_then(|mut file| file.read_exact(&mut header))
+        .is_ok_and(|_| &header == b"%PDF-")
+}
+
+/// Renders the page with the first renderer that is installed
+pub fn render_pdf_page(page: &PdfPage, dpi: u32) -> Result<RgbaImage> {
+    if !is_pdf(&page.path) {
+        return Err(anyhow!("{} is not a PDF file", page.path.display()));
+    }
+
+    let tmp_dir = tempfile::Builder::new()
+        .prefix("bookminer_render")
+        .tempdir()?;
+    let output = tmp_dir.path().join("page.png");
+
+    for renderer in RENDERERS {
+        let result = match renderer.command(page, dpi, &output).output() {
+            Err(e) if e.kind() == ErrorKind::NotFound => continue,
+            result => result.with_context(|| format!("Running {:?}", renderer))?,
+        };
+        if !result.status.success() {
+            return Err(anyhow!(
+                "{:?} failed to render page {} of {}: {}",
+                renderer,
+                page.page,
+                page.path.display(),
+                String::from_utf8_lossy(&result.stderr).trim()
+            ));
+        }
+
+        let image = image::open(&output)
+            .context("Reading the rendered page")?
+            .to_rgba8();
+        return match page.rect {
+            Some(rect) => crop_to_rect(&image, &rect, dpi),
+            None => Ok(image),
+        };
+    }
+
+    Err(anyhow!(
+        "Rendering PDF pages needs pdftoppm (poppler) or mutool (MuPDF)"
+    ))
+}
+
+fn crop_to_rect(image: &RgbaImage, rect: &PdfRect, dpi: u32) -> Result<RgbaImage> {
+    let (x, y, width, height) = rect
+        .to_pixels(dpi, image.width(), image.height())
+        .ok_or_else(|| anyhow!("The rectangle {:?} is outside of the page", rect))?;
+    Ok(image::imageops::crop_imm(image, x, y, width, height).to_image())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renderer_commands() {
+        let page = PdfPage {
+            path: PathBuf::from("/books/algebra.pdf"),
+            page: 12,
+            rect: None,
+        };
+        let output = Path::new("/tmp/render/page.png");
+
+        let pdftoppm = PdfRenderer::Pdftoppm.command(&page, 300, output);
+        let mutool = PdfRenderer::Mutool.command(&page, 300, output);
+
+        assert_eq!(
+            pdftoppm.get_args().collect::<Vec<_>>(),
+            [
+                "-png",
+                "-singlefile",
+                "-r",
+                "300",
+                "-f",
+                "12",
+                "-l",
+                "12",
+                "/books/algebra.pdf",
+                "/tmp/render/page"
+            ]
+        );
+        assert_eq!(
+            mutool.get_args().collect::<Vec<_>>(),
+            [
+                "draw",
+                "-r",
+                "300",
+                "-o",
+                "/tmp/render/page.png",
+                "/books/algebra.pdf",
+                "12"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rect_to_pixels() {
+        let rect: PdfRect = "3, 72, 144, 36, 720".parse().unwrap();
+
+        assert_eq!(rect.page, 3);
+        assert_eq!(rect.page_number(), 4);
+        assert_eq!(rect.x0, 36.0);
+        assert_eq!(rect.to_pixels(144, 1224, 1584), Some((72, 288, 72, 1152)));
+        assert_eq!(rect.to_pixels(144, 1224, 1000), Some((72, 288, 72, 712)));
+        assert!("3,72,144,36".parse::<PdfRect>().is_err());
+    }
+
+    #[test]
+    fn test_is_pdf() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let pdf = tmp_dir.path().join("book");
+        let djvu = tmp_dir.path().join("book.pdf");
+        std::fs::write(&pdf, "%PDF-1.7\n").unwrap();
+        std::fs::write(&djvu, "AT&TFORM").unwrap();
+
+        assert!(is_pdf(&pdf));
+        assert!(!is_pdf(&djvu));
+        assert!(!is_pdf(&tmp_dir.path().join("missing.pdf")));
+    }
+}
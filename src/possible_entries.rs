@@ -1,10 +1,16 @@
+use crate::anki_backend::StoredMedia;
+use crate::cloze::{create_cloze_text, extract_cloze_source, split_cloze_segments};
+use crate::latex_math::{split_math, tokenize, MathSegment};
 use crate::main_application::ApplicationState;
-use anyhow::{Context, Result};
+use crate::math_format::MathFormat;
+use crate::sections::NoteSections;
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum PossibleContent {
     Empty,
     Front,
@@ -12,37 +18,73 @@ pub enum PossibleContent {
     Screenshot,
     PageNumber,
     FileName,
+    /// The front, with its cloze markers turned into cloze deletions
+    Cloze,
+    /// Text with placeholders like `{{file}}, p. {{page}}`, see [`TEMPLATE_PLACEHOLDERS`]
+    Template(String),
+    /// The text after a section marker like `!PROOF!` in the front or back
+    Section(String),
 }
 
+/// Placeholders that can be used in a [`PossibleContent::Template`]
+pub const TEMPLATE_PLACEHOLDERS: [&str; 8] = [
+    "front",
+    "back",
+    "cloze",
+    "screenshot",
+    "page",
+    "file",
+    "date",
+    "tags",
+];
+
 impl PossibleContent {
-    pub fn get_anki_card_content(&self, state: &ApplicationState) -> Result<String> {
+    /// Creates the contents of the Anki field `field_name`. `stored_media` contains the names
+    /// under which the media files were stored in Anki, so it has to be created before the contents.
+    pub fn get_anki_card_content(
+        &self,
+        field_name: &str,
+        state: &ApplicationState,
+        stored_media: &StoredMedia,
+    ) -> Result<String> {
         match self {
             PossibleContent::Empty => Ok("".to_string()),
             PossibleContent::Front => {
-                let front_text = get_front_text(&state.tmp_dir)?;
-                let latex_wrapped_text = format!("[latex]{}[/latex]", front_text);
-                Ok(anki_escape_string(&latex_wrapped_text))
+                let front_text = read_note_sections(&state.tmp_dir)?.front;
+                Ok(format_latex(
+                    &front_text,
+                    state.anki_config.math_format(field_name),
+                ))
             }
             PossibleContent::Back => {
-                let back_text = get_back_text(&state.tmp_dir)?;
-                let latex_wrapped_text = format!("[latex]{}[/latex]", back_text);
-                Ok(anki_escape_string(&latex_wrapped_text))
+                let back_text = read_note_sections(&state.tmp_dir)?.back;
+                Ok(format_latex(
+                    &back_text,
+                    state.anki_config.math_format(field_name),
+                ))
+            }
+            PossibleContent::Cloze => {
+                let front_text = read_note_sections(&state.tmp_dir)?.front;
+                let segments = split_cloze_segments(&front_text).context("Parsing front.tex")?;
+                Ok(anki_escape_string(&create_cloze_text(&segments)))
             }
             PossibleContent::Screenshot => {
-                if let Some(screenshot_path) = &state.screenshot_path {
-                    // ankiconnect::store_file(screenshot_path)?;
-
-                    Ok(format!(
-                        "<img src=\"{}\">",
-                        screenshot_path
+                let mut images = String::new();
+                for screenshot_path in &note_images(state)? {
+                    // Not stored if Anki already had the same file under the same name
+                    let stored_name = match stored_media.get(screenshot_path) {
+                        Some(stored_name) => stored_name.clone(),
+                        None => screenshot_path
                             .file_name()
-                            .ok_or_else(|| anyhow::anyhow!("Invalid screenshot filename"))?
-                            .to_str()
-                            .ok_or_else(|| anyhow::anyhow!("Non-UTF-8 screenshot filename"))?
-                    ))
-                } else {
-                    Ok("".to_string())
+                            .map(|name| name.to_string_lossy().to_string())
+                            .ok_or_else(|| anyhow::anyhow!("Screenshot has no file name"))?,
+                    };
+                    images.push_str(&format!(
+                        "<img src=\"{}\">",
+                        anki_escape_string(&stored_name)
+                    ));
                 }
+                Ok(images)
             }
             PossibleContent::PageNumber => {
                 if let Some(page_number) = state.page_number {
@@ -58,16 +100,198 @@ impl PossibleContent {
                     Ok(String::new())
                 }
             }
+            PossibleContent::Section(name) => {
+                let sections = read_note_sections(&state.tmp_dir)?;
+                Ok(sections
+                    .section(name)
+                    .map(|text| format_latex(text, state.anki_config.math_format(field_name)))
+                    .unwrap_or_default())
+            }
+            PossibleContent::Template(template) => fill_template(template, |placeholder| {
+                let content = match placeholder {
+                    "front" => PossibleContent::Front,
+                    "back" => PossibleContent::Back,
+                    "cloze" => PossibleContent::Cloze,
+                    "screenshot" => PossibleContent::Screenshot,
+                    "page" => PossibleContent::PageNumber,
+                    "file" => PossibleContent::FileName,
+                    "date" => return Ok(Local::now().format("%Y-%m-%d").to_string()),
+                    "tags" => return Ok(anki_escape_string(&state.selected_tags.join(", "))),
+                    _ => unreachable!(),
+                };
+                content.get_anki_card_content(field_name, state, stored_media)
+            }),
         }
     }
 }
 
-fn get_front_text(tmp_dir: &Path) -> Result<String> {
-    fs::read_to_string(tmp_dir.join("front.tex")).context("Reading front.tex")
+/// Replaces the placeholders of a template with the values from `value`, which is only called
+/// for placeholders that occur. The text around them is kept as it is, so it may contain HTML.
+/// Placeholders are in double braces like `{{page}}`, so that LaTeX like `\textbf{proof}` or
+/// `\frac{1}{2}` in the template is kept. A lowercase word in double braces that isn't a
+/// placeholder is an error.
+pub fn fill_template(
+    template: &str,
+    mut value: impl FnMut(&str) -> Result<String>,
+) -> Result<String> {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+
+    while let Some(start) = rest.find("{{") {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let placeholder = rest[2..]
+            .split_once("}}")
+            .map(|(name, _)| name)
+            .filter(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_lowercase()));
+        match placeholder {
+            Some(name) if TEMPLATE_PLACEHOLDERS.contains(&name) => {
+                result.push_str(&value(name)?);
+                rest = &rest[name.len() + 4..];
+            }
+            Some(name) => {
+                return Err(anyhow!(
+                    "Unknown placeholder {{{{{}}}}}, expected one of {}",
+                    name,
+                    TEMPLATE_PLACEHOLDERS
+                        .map(|placeholder| format!("{{{{{}}}}}", placeholder))
+                        .join(", ")
+                ))
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Recovers the text of `front.tex` from a field that was created by [`PossibleContent::Cloze`]
+pub fn extract_cloze_field_source(field_value: &str) -> String {
+    extract_cloze_source(&anki_unescape_string(field_value))
+}
+
+/// Puts the text of `front.tex` or `back.tex` into a field in the given format
+pub fn format_latex(source: &str, format: MathFormat) -> String {
+    match (format, format.tags()) {
+        (MathFormat::Raw, _) => source.to_string(),
+        (_, Some((open, close))) => anki_escape_string(&format!("{}{}{}", open, source, close)),
+        (_, None) => split_math(source)
+            .into_iter()
+            .map(|segment| match segment {
+                MathSegment::Text(text) => {
+                    anki_escape_string(&unescape_dollars(&text)).replace('\n', "<br>")
+                }
+                MathSegment::Math { delimiter, content } if delimiter.display => {
+                    format!("\\[{}\\]", anki_escape_string(&content))
+                }
+                MathSegment::Math { content, .. } => {
+                    format!("\\({}\\)", anki_escape_string(&content))
+                }
+                MathSegment::Environment(environment) => {
+                    format!("\\[{}\\]", anki_escape_string(&environment))
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Turns the escaped dollars of LaTeX prose into the plain dollars of HTML,
+/// as MathJax in Anki only takes `\(...\)` and `\[...\]` as math
+fn unescape_dollars(text: &str) -> String {
+    tokenize(text)
+        .iter()
+        .map(|token| match token.text {
+            r"\$" => "$",
+            text => text,
+        })
+        .collect()
+}
+
+/// Escapes the dollars of the prose of a MathJax field again, where only the dollars in
+/// `\(...\)` and `\[...\]` are math
+fn escape_dollars(text: &str) -> String {
+    let mut in_math = false;
+    tokenize(text)
+        .iter()
+        .map(|token| match token.text {
+            r"\(" | r"\[" => {
+                in_math = true;
+                token.text.to_string()
+            }
+            r"\)" | r"\]" => {
+                in_math = false;
+                token.text.to_string()
+            }
+            "$" | "$$" if !in_math => token.text.replace('$', r"\$"),
+            text => text.to_string(),
+        })
+        .collect()
+}
+
+/// Recovers the text of `front.tex` or `back.tex` from a field that was created by
+/// [`format_latex`]. The tags of every format are removed, so that the format of a field
+/// can be changed. MathJax math keeps the `\(...\)` and `\[...\]` delimiters, and the
+/// dollars of the prose are escaped again.
+pub fn extract_latex_source(field_value: &str, format: MathFormat) -> String {
+    if format == MathFormat::Raw {
+        return field_value.to_string();
+    }
+    let text = anki_unescape_string(field_value);
+
+    [
+        MathFormat::Latex,
+        MathFormat::LatexMath,
+        MathFormat::LatexDisplayMath,
+    ]
+    .iter()
+    .filter_map(|format| format.tags())
+    .find_map(|(open, close)| {
+        text.trim()
+            .strip_prefix(open)
+            .and_then(|inner| inner.strip_suffix(close))
+    })
+    .map(String::from)
+    .unwrap_or_else(|| match format {
+        MathFormat::MathJax => escape_dollars(&text),
+        _ => text,
+    })
+}
+
+/// Reads `front.tex` and `back.tex` and splits them into sections
+pub fn read_note_sections(tmp_dir: &Path) -> Result<NoteSections> {
+    let front = fs::read_to_string(tmp_dir.join("front.tex")).context("Reading front.tex")?;
+    let back = fs::read_to_string(tmp_dir.join("back.tex")).context("Reading back.tex")?;
+    Ok(NoteSections::parse(&front, &back))
 }
 
-fn get_back_text(tmp_dir: &Path) -> Result<String> {
-    fs::read_to_string(tmp_dir.join("back.tex")).context("Reading back.tex")
+/// Names of the sections in the front and back, empty if they can't be read
+pub fn find_section_names(tmp_dir: &Path) -> Vec<String> {
+    read_note_sections(tmp_dir)
+        .map(|sections| sections.section_names())
+        .unwrap_or_default()
+}
+
+/// The screenshots followed by the images of the `!IMAGE!` directives
+pub fn note_images(state: &ApplicationState) -> Result<Vec<PathBuf>> {
+    let mut images = state.screenshot_paths.clone();
+    images.extend(image_directive_paths(state)?);
+    Ok(images)
+}
+
+/// The images of the `!IMAGE!` directives. Relative paths are relative to the directory
+/// of the book, as the working directory depends on the viewer that started bookminer.
+pub fn image_directive_paths(state: &ApplicationState) -> Result<Vec<PathBuf>> {
+    let book_dir = state
+        .image_capture
+        .book_path
+        .as_deref()
+        .and_then(Path::parent);
+    Ok(read_note_sections(&state.tmp_dir)?.image_paths(book_dir))
 }
 
 fn anki_escape_string(string: &str) -> String {
@@ -78,3 +302,89 @@ fn anki_escape_string(string: &str) -> String {
         .replace('"', "&quot;")
         .replace('\t', "&Tab;")
 }
+
+/// Reverses [`anki_escape_string`]. Line breaks and spaces inserted by
+/// the Anki editor are converted back as well.
+fn anki_unescape_string(string: &str) -> String {
+    string
+        .replace("<br>", "\n")
+        .replace("&nbsp;", " ")
+        .replace("&Tab;", "\t")
+        .replace("&quot;", "\"")
+        .replace("&gt;", ">")
+        .replace("&lt;", "<")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_latex_source_reverses_escaping() {
+        let source = "Let $a < b$ & \"c\"\n\\[ x > 0 \\]";
+        let field_value = anki_escape_string(&format!("[latex]{}[/latex]", source));
+
+        assert_eq!(
+            extract_latex_source(&field_value, MathFormat::Latex),
+            source
+        );
+    }
+
+    #[test]
+    fn test_format_latex_for_mathjax() {
+        let source = "Let $a < b$.\n\\[ x > 0 \\]";
+
+        let field_value = format_latex(source, MathFormat::MathJax);
+
+        assert_eq!(field_value, "Let \\(a &lt; b\\).<br>\\[ x &gt; 0 \\]");
+        assert_eq!(
+            format_latex("It costs \\$5 and $x = \\$3$", MathFormat::MathJax),
+            "It costs $5 and \\(x = \\$3\\)"
+        );
+        assert_eq!(
+            extract_latex_source("It costs $5 and \\(x = \\$3\\)", MathFormat::MathJax),
+            "It costs \\$5 and \\(x = \\$3\\)"
+        );
+        assert_eq!(
+            format_latex("\\begin{align}x\\end{align}", MathFormat::MathJax),
+            "\\[\\begin{align}x\\end{align}\\]"
+        );
+        assert_eq!(
+            extract_latex_source(&field_value, MathFormat::MathJax),
+            "Let \\(a < b\\).\n\\[ x > 0 \\]"
+        );
+        assert_eq!(
+            extract_latex_source(
+                &format_latex(source, MathFormat::LatexDisplayMath),
+                MathFormat::Latex
+            ),
+            source
+        );
+    }
+
+    #[test]
+    fn test_fill_template() {
+        let value = |placeholder: &str| Ok(format!("<{}>", placeholder));
+
+        assert_eq!(
+            fill_template("{{file}}, p. {{page}}", value).unwrap(),
+            "<file>, p. <page>"
+        );
+        assert_eq!(
+            fill_template("\\textbf{front} \\frac{1}{2} {{front}} {{", value).unwrap(),
+            "\\textbf{front} \\frac{1}{2} <front> {{"
+        );
+        assert!(fill_template("{{file}}, {{chapter}}", value).is_err());
+    }
+
+    #[test]
+    fn test_extract_latex_source_edited_in_anki() {
+        let field_value = "[latex]first line<br>second&nbsp;line[/latex]";
+
+        assert_eq!(
+            extract_latex_source(field_value, MathFormat::Latex),
+            "first line\nsecond line"
+        );
+    }
+}
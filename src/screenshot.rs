@@ -1,23 +1,304 @@
 use anyhow::Context;
-use anyhow::Result;
-use chrono::Local;
+use anyhow::{anyhow, Result};
 use screenshots::image::RgbaImage;
 use screenshots::Screen;
-use std::path::Path;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
 
-pub fn create_unique_screenshot_filename() -> String {
-    let timestamp = Local::now().format("%Y%m%d_%H%M%S");
-    format!("screenshot_{}.png", timestamp)
+#[cfg(target_os = "linux")]
+use crate::x11;
+
+/// Which part of the screen is captured
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(try_from = "String", into = "String")]
+pub enum CaptureMode {
+    /// The monitor under the mouse cursor
+    Cursor,
+    /// The monitor that contains the focused window
+    FocusedMonitor,
+    /// Only the focused window
+    Window,
+    /// A monitor chosen by its index or its name, e.g. `monitor:1` or `monitor:DP-1`
+    Monitor(MonitorSelector),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MonitorSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl Default for CaptureMode {
+    fn default() -> Self {
+        CaptureMode::Monitor(MonitorSelector::Index(0))
+    }
+}
+
+impl FromStr for CaptureMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "cursor" => Ok(CaptureMode::Cursor),
+            "focused-monitor" => Ok(CaptureMode::FocusedMonitor),
+            "window" => Ok(CaptureMode::Window),
+            _ => {
+                let monitor = s
+                    .strip_prefix("monitor:")
+                    .filter(|monitor| !monitor.is_empty())
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Invalid capture mode \"{}\", expected cursor, focused-monitor, \
+                             window or monitor:<index or name>",
+                            s
+                        )
+                    })?;
+                let selector = match monitor.parse() {
+                    Ok(index) => MonitorSelector::Index(index),
+                    Err(_) => MonitorSelector::Name(monitor.to_string()),
+                };
+                Ok(CaptureMode::Monitor(selector))
+            }
+        }
+    }
+}
+
+impl fmt::Display for CaptureMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureMode::Cursor => write!(f, "cursor"),
+            CaptureMode::FocusedMonitor => write!(f, "focused-monitor"),
+            CaptureMode::Window => write!(f, "window"),
+            CaptureMode::Monitor(MonitorSelector::Index(index)) => write!(f, "monitor:{}", index),
+            CaptureMode::Monitor(MonitorSelector::Name(name)) => write!(f, "monitor:{}", name),
+        }
+    }
 }
 
-pub(crate) fn capture_screenshot() -> Result<RgbaImage> {
+impl TryFrom<String> for CaptureMode {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<CaptureMode> for String {
+    fn from(mode: CaptureMode) -> Self {
+        mode.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    fn of_screen(screen: &Screen) -> Self {
+        let info = screen.display_info;
+        Rect {
+            x: info.x,
+            y: info.y,
+            width: info.width,
+            height: info.height,
+        }
+    }
+
+    pub fn contains(&self, x: i32, y: i32) -> bool {
+        x >= self.x
+            && x < self.x + self.width as i32
+            && y >= self.y
+            && y < self.y + self.height as i32
+    }
+
+    pub fn center(&self) -> (i32, i32) {
+        (
+            self.x + self.width as i32 / 2,
+            self.y + self.height as i32 / 2,
+        )
+    }
+
+    pub fn intersection(&self, other: &Rect) -> Option<Rect> {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = (self.x + self.width as i32).min(other.x + other.width as i32);
+        let bottom = (self.y + self.height as i32).min(other.y + other.height as i32);
+
+        (x < right && y < bottom).then_some(Rect {
+            x,
+            y,
+            width: (right - x) as u32,
+            height: (bottom - y) as u32,
+        })
+    }
+
+    /// Converts X11 (physical) coordinates to the logical coordinates of the screens
+    fn unscaled(&self, scale_factor: f32) -> Rect {
+        Rect {
+            x: (self.x as f32 / scale_factor) as i32,
+            y: (self.y as f32 / scale_factor) as i32,
+            width: (self.width as f32 / scale_factor) as u32,
+            height: (self.height as f32 / scale_factor) as u32,
+        }
+    }
+}
+
+/// Returns the index of the screen that contains the point
+pub fn find_screen_at(screens: &[Rect], x: i32, y: i32) -> Option<usize> {
+    screens.iter().position(|screen| screen.contains(x, y))
+}
+
+pub fn capture_screenshot(mode: &CaptureMode) -> Result<RgbaImage> {
     let screens = Screen::all()?;
-    let screen = screens.first().context("No screen found")?;
+    let screen_rects: Vec<Rect> = screens.iter().map(Rect::of_screen).collect();
+    // All screens of an X11 display share the scale factor
+    let scale_factor = screens
+        .first()
+        .context("No screen found")?
+        .display_info
+        .scale_factor;
+
+    let screen_at = |(x, y): (i32, i32)| {
+        let (x, y) = (
+            (x as f32 / scale_factor) as i32,
+            (y as f32 / scale_factor) as i32,
+        );
+        find_screen_at(&screen_rects, x, y)
+            .map(|index| &screens[index])
+            .ok_or_else(|| anyhow!("No screen found at {}, {}", x, y))
+    };
+
+    let image = match mode {
+        CaptureMode::Monitor(selector) => select_monitor(&screens, selector)?.capture(),
+        CaptureMode::Cursor => screen_at(cursor_position()?)?.capture(),
+        CaptureMode::FocusedMonitor => screen_at(active_window_geometry()?.center())?.capture(),
+        CaptureMode::Window => {
+            let window = active_window_geometry()?;
+            let screen = screen_at(window.center())?;
+            let screen_rect = Rect::of_screen(screen);
+            // Parts of the window on other monitors are cut off
+            let area = window
+                .unscaled(scale_factor)
+                .intersection(&screen_rect)
+                .context("The focused window is not visible")?;
+            screen.capture_area(
+                area.x - screen_rect.x,
+                area.y - screen_rect.y,
+                area.width,
+                area.height,
+            )
+        }
+    };
+    image.context("Capturing screen")
+}
+
+fn select_monitor<'a>(screens: &'a [Screen], selector: &MonitorSelector) -> Result<&'a Screen> {
+    match selector {
+        MonitorSelector::Index(index) => screens
+            .get(*index)
+            .ok_or_else(|| anyhow!("No monitor {}, there are {}", index, screens.len())),
+        MonitorSelector::Name(name) => {
+            for screen in screens {
+                if monitor_name(screen)? == *name {
+                    return Ok(screen);
+                }
+            }
+            Err(anyhow!("No monitor named {}", name))
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn monitor_name(screen: &Screen) -> Result<String> {
+    x11::output_name(screen.display_info.raw_handle)
+}
+
+#[cfg(target_os = "linux")]
+fn cursor_position() -> Result<(i32, i32)> {
+    x11::cursor_position()
+}
+
+#[cfg(target_os = "linux")]
+fn active_window_geometry() -> Result<Rect> {
+    x11::active_window_geometry()
+}
 
-    let image = screen.capture().context("Capturing screen")?;
-    Ok(image)
+#[cfg(not(target_os = "linux"))]
+fn monitor_name(_screen: &Screen) -> Result<String> {
+    Err(anyhow!("Monitor names are only supported on X11"))
 }
 
-pub fn save_image(image: &RgbaImage, path: &Path) -> Result<()> {
-    image.save(&path).context("Saving screenshot")
+#[cfg(not(target_os = "linux"))]
+fn cursor_position() -> Result<(i32, i32)> {
+    Err(anyhow!("The cursor position is only supported on X11"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn active_window_geometry() -> Result<Rect> {
+    Err(anyhow!("The focused window is only supported on X11"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_capture_mode() {
+        assert_eq!(
+            "cursor".parse::<CaptureMode>().unwrap(),
+            CaptureMode::Cursor
+        );
+        assert_eq!(
+            "monitor:1".parse::<CaptureMode>().unwrap(),
+            CaptureMode::Monitor(MonitorSelector::Index(1))
+        );
+        assert_eq!(
+            "monitor:DP-1".parse::<CaptureMode>().unwrap(),
+            CaptureMode::Monitor(MonitorSelector::Name("DP-1".to_string()))
+        );
+        assert!("monitor:".parse::<CaptureMode>().is_err());
+        assert!("everything".parse::<CaptureMode>().is_err());
+    }
+
+    #[test]
+    fn test_window_on_second_screen() {
+        let screens = [
+            Rect {
+                x: 0,
+                y: 0,
+                width: 1920,
+                height: 1080,
+            },
+            Rect {
+                x: 1920,
+                y: 0,
+                width: 2560,
+                height: 1440,
+            },
+        ];
+        let window = Rect {
+            x: 1800,
+            y: 100,
+            width: 1000,
+            height: 2000,
+        };
+
+        let index = find_screen_at(&screens, window.center().0, window.center().1);
+
+        assert_eq!(index, Some(1));
+        assert_eq!(
+            window.intersection(&screens[1]),
+            Some(Rect {
+                x: 1920,
+                y: 100,
+                width: 880,
+                height: 1340,
+            })
+        );
+    }
 }
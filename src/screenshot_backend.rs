@@ -0,0 +1,352 @@
+use crate::image_processing::process_image;
+use crate::media_encoding::{create_media_filename, encode_image, FilenameValues};
+use crate::pdf_render::{render_pdf_page, PdfPage};
+use crate::screenshot::{capture_screenshot, CaptureMode};
+use crate::settings::ScreenshotSettings;
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use screenshots::image::{self, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Placeholder in the screenshot command for the file that the PNG is written to
+pub const PATH_PLACEHOLDER: &str = "{path}";
+
+/// Backends that can be listed in the `backends` setting
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BackendKind {
+    /// The `screenshots` crate with the configured capture mode
+    Builtin,
+    /// The configured external command, e.g. `grim -g "$(slurp)" -` or `maim -s {path}`
+    Command,
+    /// The page of the book rendered from the PDF file
+    Pdf,
+}
+
+pub trait ScreenshotBackend {
+    /// Name of the backend for error messages
+    fn name(&self) -> String;
+
+    fn capture(&self) -> Result<RgbaImage>;
+}
+
+pub struct BuiltinBackend {
+    pub mode: CaptureMode,
+}
+
+impl ScreenshotBackend for BuiltinBackend {
+    fn name(&self) -> String {
+        format!("builtin ({})", self.mode)
+    }
+
+    fn capture(&self) -> Result<RgbaImage> {
+        capture_screenshot(&self.mode)
+    }
+}
+
+/// Runs a shell command that writes a PNG to [`PATH_PLACEHOLDER`] or, without the placeholder,
+/// to stdout
+pub struct CommandBackend {
+    pub command: String,
+}
+
+impl ScreenshotBackend for CommandBackend {
+    fn name(&self) -> String {
+        format!("command ({})", self.command)
+    }
+
+    fn capture(&self) -> Result<RgbaImage> {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix("bookminer_capture")
+            .tempdir()?;
+        let image_path = tmp_dir.path().join("screenshot.png");
+        let writes_to_path = self.command.contains(PATH_PLACEHOLDER);
+        let command = self
+            .command
+            .replace(PATH_PLACEHOLDER, &shell_quote(&image_path));
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(Stdio::null())
+            .output()
+            .context("Running the screenshot command")?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "The screenshot command failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        if writes_to_path {
+            load_image(&image_path)
+        } else {
+            decode_image(&output.stdout)
+        }
+    }
+}
+
+/// Renders the page straight from the PDF file, at a higher resolution than the screen
+/// and without the user interface of the viewer
+pub struct PdfBackend {
+    /// `None` if the viewer didn't pass the book and the page number
+    pub page: Option<PdfPage>,
+    pub dpi: u32,
+}
+
+impl ScreenshotBackend for PdfBackend {
+    fn name(&self) -> String {
+        "pdf".to_string()
+    }
+
+    fn capture(&self) -> Result<RgbaImage> {
+        let page = self
+            .page
+            .as_ref()
+            .context("Rendering the page needs --book-filename and --page-number")?;
+        render_pdf_page(page, self.dpi)
+    }
+}
+
+/// Reads an image that was captured elsewhere, from a file or from stdin (`-`)
+pub struct ImageBackend {
+    pub path: PathBuf,
+}
+
+impl ScreenshotBackend for ImageBackend {
+    fn name(&self) -> String {
+        format!("image ({})", self.path.display())
+    }
+
+    fn capture(&self) -> Result<RgbaImage> {
+        if self.path == Path::new("-") {
+            let mut bytes = Vec::new();
+            std::io::stdin()
+                .read_to_end(&mut bytes)
+                .context("Reading the image from stdin")?;
+            decode_image(&bytes)
+        } else {
+            load_image(&self.path)
+        }
+    }
+}
+
+/// Creates the backends in the order they should be tried.
+/// An image passed with `--image` replaces the configured backends, and so does the pdf
+/// backend for a page with a rectangle, which the other backends can't capture.
+pub fn create_backends(
+    settings: &ScreenshotSettings,
+    image: Option<&Path>,
+    pdf_page: Option<PdfPage>,
+) -> Result<Vec<Box<dyn ScreenshotBackend>>> {
+    if let Some(path) = image {
+        return Ok(vec![Box::new(ImageBackend {
+            path: path.to_path_buf(),
+        })]);
+    }
+    if let Some(page) = pdf_page.clone().filter(|page| page.rect.is_some()) {
+        return Ok(vec![Box::new(PdfBackend {
+            page: Some(page),
+            dpi: settings.dpi,
+        })]);
+    }
+
+    settings
+        .backends
+        .iter()
+        .map(|kind| -> Result<Box<dyn ScreenshotBackend>> {
+            match kind {
+                BackendKind::Builtin => Ok(Box::new(BuiltinBackend {
+                    mode: settings.capture.clone(),
+                })),
+                BackendKind::Command => {
+                    let command = settings
+                        .command
+                        .clone()
+                        .context("The command backend needs a command in [screenshot]")?;
+                    Ok(Box::new(CommandBackend { command }))
+                }
+                BackendKind::Pdf => Ok(Box::new(PdfBackend {
+                    page: pdf_page.clone(),
+                    dpi: settings.dpi,
+                })),
+            }
+        })
+        .collect()
+}
+
+/// Tries the backends in order and returns the first image.
+/// If all of them fail, the error lists the error of every backend.
+pub fn capture_with_fallback(backends: &[Box<dyn ScreenshotBackend>]) -> Result<RgbaImage> {
+    let mut errors = Vec::new();
+    for backend in backends {
+        match backend.capture() {
+            Ok(image) => return Ok(image),
+            Err(e) => errors.push(format!("{}: {:#}", backend.name(), e)),
+        }
+    }
+
+    if errors.is_empty() {
+        return Err(anyhow!("No screenshot backend configured"));
+    }
+    Err(anyhow!(
+        "Capturing the screenshot failed\n{}",
+        errors.join("\n")
+    ))
+}
+
+/// A processed and encoded screenshot with its file name
+pub struct EncodedScreenshot {
+    pub filename: String,
+    pub data: Vec<u8>,
+}
+
+/// Captures with the first working backend, then applies the processing and the encoding
+/// of the settings
+pub fn take_screenshot(
+    settings: &ScreenshotSettings,
+    backends: &[Box<dyn ScreenshotBackend>],
+    book: Option<&str>,
+    page: Option<u32>,
+) -> Result<EncodedScreenshot> {
+    let image = process_image(capture_with_fallback(backends)?, &settings.processing);
+    let data = encode_image(&image, settings.format, settings.quality)?;
+
+    let filename_values = FilenameValues {
+        book,
+        page,
+        date: Local::now().format("%Y%m%d_%H%M%S").to_string(),
+    };
+    let filename =
+        create_media_filename(&settings.filename, &filename_values, &data, settings.format);
+    Ok(EncodedScreenshot { filename, data })
+}
+
+/// Writes the screenshot to `dir`. If a different file with the same name exists,
+/// a number is appended, as several screenshots can be taken in the same second.
+pub fn save_screenshot(dir: &Path, screenshot: &EncodedScreenshot) -> Result<PathBuf> {
+    let (stem, extension) = screenshot
+        .filename
+        .rsplit_once('.')
+        .unwrap_or((&screenshot.filename, ""));
+    let mut path = dir.join(&screenshot.filename);
+    let mut counter = 1;
+    while path.exists() {
+        if fs::read(&path).is_ok_and(|existing| existing == screenshot.data) {
+            return Ok(path);
+        }
+        counter += 1;
+        path = dir.join(format!("{}-{}.{}", stem, counter, extension));
+    }
+
+    fs::write(&path, &screenshot.data).context("Saving screenshot")?;
+    Ok(path)
+}
+
+fn load_image(path: &Path) -> Result<RgbaImage> {
+    let image = image::open(path).with_context(|| format!("Reading {}", path.display()))?;
+    Ok(image.to_rgba8())
+}
+
+fn decode_image(bytes: &[u8]) -> Result<RgbaImage> {
+    let image = image::load_from_memory(bytes).context("Decoding the image")?;
+    Ok(image.to_rgba8())
+}
+
+fn shell_quote(path: &Path) -> String {
+    format!("'{}'", path.display().to_string().replace('\'', r"'\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screenshots::image::Rgba;
+
+    fn write_test_image(dir: &Path) -> PathBuf {
+        let path = dir.join("test's image.png");
+        RgbaImage::from_pixel(3, 2, Rgba([255, 0, 0, 255]))
+            .save(&path)
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn test_command_writing_to_stdout_or_path() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let source = shell_quote(&write_test_image(tmp_dir.path()));
+
+        let stdout = CommandBackend {
+            command: format!("cat {}", source),
+        };
+        let path = CommandBackend {
+            command: format!("cp {} {}", source, PATH_PLACEHOLDER),
+        };
+
+        assert_eq!(stdout.capture().unwrap().dimensions(), (3, 2));
+        assert_eq!(path.capture().unwrap().dimensions(), (3, 2));
+    }
+
+    #[test]
+    fn test_fallback_to_next_backend() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let backends: Vec<Box<dyn ScreenshotBackend>> = vec![
+            Box::new(CommandBackend {
+                command: "exit 1".to_string(),
+            }),
+            Box::new(ImageBackend {
+                path: write_test_image(tmp_dir.path()),
+            }),
+        ];
+
+        assert_eq!(
+            capture_with_fallback(&backends).unwrap().dimensions(),
+            (3, 2)
+        );
+        assert!(capture_with_fallback(&backends[..1]).is_err());
+    }
+
+    #[test]
+    fn test_rect_is_only_rendered_from_the_pdf() {
+        let page = |rect| PdfPage {
+            path: PathBuf::from("/books/algebra.pdf"),
+            page: 4,
+            rect,
+        };
+        let settings = ScreenshotSettings::default();
+        let names = |page| -> Vec<String> {
+            create_backends(&settings, None, Some(page))
+                .unwrap()
+                .iter()
+                .map(|backend| backend.name())
+                .collect()
+        };
+
+        assert!(names(page(None)).len() > 1);
+        assert_eq!(names(page(Some("3,0,0,72,72".parse().unwrap()))), ["pdf"]);
+    }
+
+    #[test]
+    fn test_save_screenshot_with_same_name() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let screenshot = |data: &[u8]| EncodedScreenshot {
+            filename: "screenshot_20240101_120000.png".to_string(),
+            data: data.to_vec(),
+        };
+
+        let first = save_screenshot(tmp_dir.path(), &screenshot(b"first")).unwrap();
+        let second = save_screenshot(tmp_dir.path(), &screenshot(b"second")).unwrap();
+        let again = save_screenshot(tmp_dir.path(), &screenshot(b"first")).unwrap();
+
+        assert_eq!(first.file_name().unwrap(), "screenshot_20240101_120000.png");
+        assert_eq!(
+            second.file_name().unwrap(),
+            "screenshot_20240101_120000-2.png"
+        );
+        assert_eq!(again, first);
+    }
+}
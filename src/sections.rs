@@ -0,0 +1,181 @@
+use std::path::{Path, PathBuf};
+
+/// Attaches an image file to the note: `!IMAGE! path`
+pub const IMAGE_DIRECTIVE: &str = "!IMAGE!";
+
+/// The texts of `front.tex` and `back.tex`, split at the section markers.
+/// A line starting with a marker like `!PROOF!` starts a section that lasts until
+/// the next marker or the end of the file.
+#[derive(Debug, Default, PartialEq)]
+pub struct NoteSections {
+    /// The text of `front.tex` before the first marker
+    pub front: String,
+    /// The text of `back.tex` before the first marker
+    pub back: String,
+    /// The sections in order of appearance. A section that appears several times is joined.
+    pub sections: Vec<(String, String)>,
+    /// Files of the `!IMAGE!` directives
+    pub images: Vec<PathBuf>,
+}
+
+impl NoteSections {
+    pub fn parse(front: &str, back: &str) -> Self {
+        let mut note_sections = NoteSections::default();
+        note_sections.front = note_sections.split_sections(front);
+        note_sections.back = note_sections.split_sections(back);
+        note_sections
+    }
+
+    pub fn section(&self, name: &str) -> Option<&str> {
+        self.sections
+            .iter()
+            .find(|(section_name, _)| section_name == name)
+            .map(|(_, text)| text.as_str())
+    }
+
+    pub fn section_names(&self) -> Vec<String> {
+        self.sections.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    /// The files of the `!IMAGE!` directives, with relative paths resolved against `base_dir`
+    pub fn image_paths(&self, base_dir: Option<&Path>) -> Vec<PathBuf> {
+        self.images
+            .iter()
+            .map(|path| match base_dir {
+                Some(base_dir) if path.is_relative() => base_dir.join(path),
+                _ => path.clone(),
+            })
+            .collect()
+    }
+
+    /// Moves the sections and images of `text` into `self` and returns the text before
+    /// the first marker. Without markers the text is returned unchanged.
+    fn split_sections(&mut self, text: &str) -> String {
+        let mut main_text = String::new();
+        let mut current_section: Option<(String, String)> = None;
+        let mut has_markers = false;
+
+        for line in text.split_inclusive('\n') {
+            if let Some(path) = parse_image_directive(line) {
+                self.images.push(path);
+                has_markers = true;
+                continue;
+            }
+            if let Some((name, rest)) = parse_section_marker(line) {
+                if let Some(section) = current_section.take() {
+                    self.add_section(section);
+                }
+                current_section = Some((name.to_string(), rest.to_string()));
+                has_markers = true;
+                continue;
+            }
+
+            match &mut current_section {
+                Some((_, section_text)) => section_text.push_str(line),
+                None => main_text.push_str(line),
+            }
+        }
+        if let Some(section) = current_section {
+            self.add_section(section);
+        }
+
+        if has_markers {
+            main_text.trim_end().to_string()
+        } else {
+            main_text
+        }
+    }
+
+    fn add_section(&mut self, (name, text): (String, String)) {
+        let text = text.trim_start_matches(['\n', '\r']).trim_end();
+        match self
+            .sections
+            .iter_mut()
+            .find(|(existing, _)| *existing == name)
+        {
+            Some((_, existing_text)) => {
+                existing_text.push_str("\n\n");
+                existing_text.push_str(text);
+            }
+            None => self.sections.push((name, text.to_string())),
+        }
+    }
+}
+
+/// Section names are upper case, like `PROOF` or `REMARK_2`
+pub fn is_section_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_uppercase())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+        && format!("!{}!", name) != IMAGE_DIRECTIVE
+}
+
+/// Returns the name of the section and the text after the marker on the same line
+fn parse_section_marker(line: &str) -> Option<(&str, &str)> {
+    let after_open = line.trim_start().strip_prefix('!')?;
+    let (name, rest) = after_open.split_once('!')?;
+    is_section_name(name).then_some((name, rest.trim_start_matches([' ', '\t'])))
+}
+
+fn parse_image_directive(line: &str) -> Option<PathBuf> {
+    let path = line.trim().strip_prefix(IMAGE_DIRECTIVE)?.trim();
+    if path.is_empty() {
+        return None;
+    }
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(relative), Some(home)) => Some(home.join(relative)),
+        _ => Some(PathBuf::from(path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sections() {
+        let front = "Theorem: $a = b$.\n\n!PROOF!\nTrivial.\n!IMAGE! /tmp/figure.png\n";
+        let back = "Definition\n!REMARK! Also holds for $c$.\n!PROOF!\nSee above.\n";
+
+        let sections = NoteSections::parse(front, back);
+
+        assert_eq!(sections.front, "Theorem: $a = b$.");
+        assert_eq!(sections.back, "Definition");
+        assert_eq!(sections.section("PROOF"), Some("Trivial.\n\nSee above."));
+        assert_eq!(sections.section("REMARK"), Some("Also holds for $c$."));
+        assert_eq!(sections.section_names(), ["PROOF", "REMARK"]);
+        assert_eq!(sections.images, [PathBuf::from("/tmp/figure.png")]);
+    }
+
+    #[test]
+    fn test_relative_image_paths() {
+        let sections = NoteSections::parse(
+            "!IMAGE! figures/a.png
+!IMAGE! /tmp/b.png
+",
+            "",
+        );
+
+        assert_eq!(
+            sections.image_paths(Some(Path::new("/books"))),
+            [
+                PathBuf::from("/books/figures/a.png"),
+                PathBuf::from("/tmp/b.png")
+            ]
+        );
+        assert_eq!(sections.image_paths(None), sections.images);
+    }
+
+    #[test]
+    fn test_text_without_markers_is_unchanged() {
+        let front = "Why is $x! = 1$ for x = 0? !not a marker!\n";
+
+        let sections = NoteSections::parse(front, "");
+
+        assert_eq!(sections.front, front);
+        assert!(sections.sections.is_empty());
+        assert!(!is_section_name("Proof"));
+        assert!(!is_section_name("IMAGE"));
+    }
+}
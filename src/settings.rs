@@ -0,0 +1,161 @@
+use crate::image_processing::ImageOperation;
+use crate::latex_preview::PreviewProtocol;
+use crate::media_encoding::{MediaFormat, DEFAULT_FILENAME_TEMPLATE};
+use crate::paths::{get_api_key_file, get_config_file, get_latex_preamble_file};
+use crate::screenshot::CaptureMode;
+use crate::screenshot_backend::BackendKind;
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub anki_connect: AnkiConnectSettings,
+    pub latex: LatexSettings,
+    pub screenshot: ScreenshotSettings,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct AnkiConnectSettings {
+    pub url: String,
+    pub api_key: Option<String>,
+    pub timeout_secs: u64,
+}
+
+impl Default for AnkiConnectSettings {
+    fn default() -> Self {
+        Self {
+            url: "http://localhost:8765".to_string(),
+            api_key: None,
+            timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct LatexSettings {
+    /// Local copy of the LaTeX preamble of the note type
+    pub preamble_file: Option<PathBuf>,
+    /// How the preview of the rendered LaTeX is shown
+    pub preview_protocol: PreviewProtocol,
+    /// Command that opens the preview images, e.g. `feh {path}`
+    pub preview_viewer: Option<String>,
+}
+
+impl LatexSettings {
+    pub fn preamble_file(&self) -> Result<PathBuf> {
+        match &self.preamble_file {
+            Some(path) => Ok(path.clone()),
+            None => get_latex_preamble_file(),
+        }
+    }
+
+    /// Returns the content of the preamble file, or `None` if it can't be read
+    pub fn read_preamble(&self) -> Option<String> {
+        fs::read_to_string(self.preamble_file().ok()?).ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ScreenshotSettings {
+    /// Which part of the screen is captured by the builtin backend
+    pub capture: CaptureMode,
+    /// Backends to try in order until one of them succeeds
+    pub backends: Vec<BackendKind>,
+    /// Shell command of the command backend
+    pub command: Option<String>,
+    /// Resolution of the pages rendered by the pdf backend
+    pub dpi: u32,
+    /// Operations applied to every screenshot before it is saved
+    pub processing: Vec<ImageOperation>,
+    pub format: MediaFormat,
+    /// Quality of JPEG and WebP images, from 1 to 100
+    pub quality: u8,
+    /// Name of the stored image without extension, with `{book}`, `{page}`, `{date}` and `{hash}`
+    pub filename: String,
+}
+
+impl Default for ScreenshotSettings {
+    fn default() -> Self {
+        Self {
+            capture: CaptureMode::default(),
+            // The builtin backend stays first, so rendering from the PDF is opt-in. The final
+            // menu skips it and renders from the PDF instead.
+            backends: vec![BackendKind::Builtin, BackendKind::Pdf],
+            command: None,
+            dpi: 200,
+            processing: Vec::new(),
+            format: MediaFormat::default(),
+            quality: 85,
+            filename: DEFAULT_FILENAME_TEMPLATE.to_string(),
+        }
+    }
+}
+
+/// Loads the user settings from `config.toml`, falling back to the defaults
+/// if the file does not exist.
+pub fn load_settings() -> Result<Settings> {
+    let config_file = get_config_file()?;
+
+    if !config_file.exists() {
+        return Ok(Settings::default());
+    }
+
+    let config_toml =
+        fs::read_to_string(&config_file).with_context(|| anyhow!("Reading config file"))?;
+
+    toml::from_str(&config_toml)
+        .with_context(|| anyhow!("Parsing config file {}", config_file.display()))
+}
+
+/// Returns the API key that was entered after AnkiConnect rejected the configured one.
+/// It takes precedence over the key in `config.toml`, which was wrong when it was entered.
+pub fn load_saved_api_key() -> Option<String> {
+    let api_key = fs::read_to_string(get_api_key_file().ok()?).ok()?;
+    Some(api_key.trim().to_string()).filter(|key| !key.is_empty())
+}
+
+pub fn save_api_key(api_key: &str) -> Result<()> {
+    let api_key_file = get_api_key_file()?;
+    fs::write(&api_key_file, api_key).context("Writing API key")?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&api_key_file, fs::Permissions::from_mode(0o600))
+            .context("Restricting the permissions of the API key file")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_partial_settings() {
+        let settings: Settings = toml::from_str(
+            r#"
+            [anki_connect]
+            url = "http://127.0.0.1:9000"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(settings.anki_connect.url, "http://127.0.0.1:9000");
+        assert_eq!(settings.anki_connect.api_key, None);
+        assert_eq!(settings.anki_connect.timeout_secs, 30);
+    }
+
+    #[test]
+    fn test_parse_empty_settings() {
+        let settings: Settings = toml::from_str("").unwrap();
+
+        assert_eq!(settings.anki_connect.url, "http://localhost:8765");
+        assert_eq!(settings.screenshot.capture, CaptureMode::default());
+    }
+}
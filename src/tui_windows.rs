@@ -1,103 +1,420 @@
-use crate::anki_error_handling::check_should_retry;
-use crate::ankiconnect::{get_deck_names, get_field_names, get_model_names};
+use crate::anki_backend::{AnkiBackend, NoteInfo};
+use crate::anki_config::{AnkiConfig, DuplicatePolicy, DuplicateScope};
+use crate::anki_error_handling::request_with_retry;
+use crate::duplicates::SimilarNotes;
+use crate::latex_lint::{lint_latex, LintProblem};
 use crate::main_application::ApplicationState;
+use crate::math_format::MathFormat;
 use crate::menu_actions::{
-    CancelAction, EditAnkiSettings, EditBackAction, EditFrontAction, MenuAction, SendCardAction,
+    AddImageAction, CancelAction, EditAnkiSettings, EditBackAction, EditFrontAction, MenuAction,
+    OpenAddDialogAction, PreviewAction, RemoveImageAction, RetakeImageAction, SendAndBrowseAction,
+    SendCardAction, UpdateExistingNoteAction,
 };
-use crate::possible_entries::PossibleContent;
+use crate::note_type::{
+    bookminer_note_type, known_field_mapping, normalize_deck_name, BOOKMINER_LATEX_PREAMBLE,
+    BOOKMINER_NOTE_TYPE,
+};
+use crate::note_update::{create_source_query, find_notes_from_page};
+use crate::paths::get_latex_preamble_file;
+use crate::possible_entries::{fill_template, PossibleContent, TEMPLATE_PLACEHOLDERS};
+use crate::sections::is_section_name;
 use crate::ui::tui::Tui;
 use anyhow::{Context, Result};
+use std::fs;
 use std::path::Path;
 
-pub fn edit_front(tui: &mut Tui, tmp_dir: &Path) -> Result<()> {
+pub fn edit_front(tui: &mut Tui, tmp_dir: &Path, preamble: Option<&str>) -> Result<()> {
     let path = tmp_dir.join("front.tex");
-    tui.edit_file(&path).context("Editing front file")
+    tui.edit_file(&path).context("Editing front file")?;
+    lint_after_editing(tui, &path, preamble)
 }
 
-pub fn edit_back(tui: &mut Tui, tmp_dir: &Path) -> Result<()> {
+pub fn edit_back(tui: &mut Tui, tmp_dir: &Path, preamble: Option<&str>) -> Result<()> {
     let path = tmp_dir.join("back.tex");
-    tui.edit_file(&path).context("Editing front file")
+    tui.edit_file(&path).context("Editing back file")?;
+    lint_after_editing(tui, &path, preamble)
 }
 
-pub fn select_anki_deck(tui: &mut Tui) -> Result<String> {
+/// Lists the LaTeX problems of the file until the user continues without fixing them.
+/// Choosing a problem opens the editor at its line.
+fn lint_after_editing(tui: &mut Tui, path: &Path, preamble: Option<&str>) -> Result<()> {
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
     loop {
-        match get_deck_names() {
-            Ok(decks) => {
-                let index = tui.show_single_selection_menu("Select Anki Deck", &decks)?;
-                return Ok(decks[index].clone()); // TODO: Is cloning necessary?
+        let latex = fs::read_to_string(path).with_context(|| format!("Reading {}", filename))?;
+        let problems: Vec<(&str, LintProblem)> = lint_latex(&latex, preamble)
+            .into_iter()
+            .map(|problem| (filename.as_ref(), problem))
+            .collect();
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        match select_lint_problem(tui, &problems, &["Continue anyway"])? {
+            index if index < problems.len() => {
+                tui.edit_file_at_line(path, problems[index].1.line)?
             }
-            Err(e) => check_should_retry(e, tui)?,
+            _ => return Ok(()),
         }
     }
 }
 
-pub fn select_anki_note_type(tui: &mut Tui) -> Result<String> {
+/// Shows the problems with their file and line, followed by `other_items`.
+/// Returns the index of the chosen problem, or of the other item after the problems.
+pub fn select_lint_problem(
+    tui: &mut Tui,
+    problems: &[(&str, LintProblem)],
+    other_items: &[&str],
+) -> Result<usize> {
+    let mut menu_items: Vec<String> = problems
+        .iter()
+        .map(|(filename, problem)| {
+            format!("{}, line {}: {}", filename, problem.line, problem.message)
+        })
+        .collect();
+    menu_items.extend(other_items.iter().map(|item| item.to_string()));
+
+    tui.show_single_selection_menu(
+        "The LaTeX has problems, choose one to fix it in the editor",
+        &menu_items,
+    )
+}
+
+pub fn select_anki_deck(tui: &mut Tui, anki: &dyn AnkiBackend) -> Result<String> {
     loop {
-        match get_model_names() {
-            Ok(note_types) => {
-                let index = tui.show_single_selection_menu("Select Anki Note Type", &note_types)?;
-                return Ok(note_types[index].clone()); // TODO: Is cloning necessary?
-            }
-            Err(e) => check_should_retry(e, tui)?,
+        let mut decks = request_with_retry(tui, anki, |anki| anki.get_deck_names())?;
+        decks.push("Create new deck".to_string());
+        let index = tui.show_single_selection_menu("Select Anki Deck", &decks)?;
+        if index + 1 < decks.len() {
+            return Ok(decks.swap_remove(index));
+        }
+        if let Some(deck) = create_anki_deck(tui, anki)? {
+            return Ok(deck);
         }
     }
 }
 
-pub fn select_from_possible_content(tui: &mut Tui, field_name: &str) -> Result<PossibleContent> {
-    let options = vec![
+/// Returns `None` if the user cancelled or entered an invalid name
+fn create_anki_deck(tui: &mut Tui, anki: &dyn AnkiBackend) -> Result<Option<String>> {
+    let Some(input) =
+        tui.show_text_input("Name of the new deck (Parent::Child for a subdeck)", false)?
+    else {
+        return Ok(None);
+    };
+    let Some(deck) = normalize_deck_name(&input) else {
+        show_message(tui, "Deck names and their parts must not be empty")?;
+        return Ok(None);
+    };
+
+    request_with_retry(tui, anki, |anki| anki.create_deck(&deck))?;
+    Ok(Some(deck))
+}
+
+pub fn select_anki_note_type(tui: &mut Tui, anki: &dyn AnkiBackend) -> Result<String> {
+    let mut note_types = request_with_retry(tui, anki, |anki| anki.get_model_names())?;
+    let can_create = !note_types.iter().any(|n| n == BOOKMINER_NOTE_TYPE);
+    if can_create {
+        note_types.push("Create bookminer note type".to_string());
+    }
+
+    let index = tui.show_single_selection_menu("Select Anki Note Type", &note_types)?;
+    if can_create && index + 1 == note_types.len() {
+        create_bookminer_note_type(tui, anki)?;
+        return Ok(BOOKMINER_NOTE_TYPE.to_string());
+    }
+    Ok(note_types.swap_remove(index))
+}
+
+fn create_bookminer_note_type(tui: &mut Tui, anki: &dyn AnkiBackend) -> Result<()> {
+    request_with_retry(tui, anki, |anki| {
+        anki.create_note_type(&bookminer_note_type())
+    })?;
+
+    let preamble_file = get_latex_preamble_file()?;
+    if !preamble_file.exists() {
+        fs::write(&preamble_file, BOOKMINER_LATEX_PREAMBLE).context("Writing LaTeX preamble")?;
+    }
+    let msg = format!(
+        "AnkiConnect can't set the LaTeX preamble. Copy {} into the note type options in Anki",
+        preamble_file.display()
+    );
+    show_message(tui, &msg)
+}
+
+/// `section_names` are the sections found in the front and back, which are offered as options.
+/// Other sections can be entered by name.
+pub fn select_from_possible_content(
+    tui: &mut Tui,
+    field_name: &str,
+    section_names: &[String],
+) -> Result<PossibleContent> {
+    let mut options: Vec<String> = [
         "Empty",
         "Front",
         "Back",
         "Screenshot",
         "Page Number",
         "File Name",
-    ];
+        "Cloze (Front with \\cloze{...} markers)",
+        "Template (e.g. {{file}}, p. {{page}})",
+    ]
+    .map(String::from)
+    .to_vec();
+    let fixed_options = options.len();
+    options.extend(
+        section_names
+            .iter()
+            .map(|name| format!("Section !{}!", name)),
+    );
+    options.push("Other Section".to_string());
 
     let title = format!("Choose the contents for the field {}", field_name);
-    let selected = tui.show_single_selection_menu(&title, &options)?;
+    loop {
+        let selected = tui.show_single_selection_menu(&title, &options)?;
 
-    Ok(match selected {
-        0 => PossibleContent::Empty,
-        1 => PossibleContent::Front,
-        2 => PossibleContent::Back,
-        3 => PossibleContent::Screenshot,
-        4 => PossibleContent::PageNumber,
-        5 => PossibleContent::FileName,
-        _ => unreachable!(),
-    })
+        return Ok(match selected {
+            0 => PossibleContent::Empty,
+            1 => PossibleContent::Front,
+            2 => PossibleContent::Back,
+            3 => PossibleContent::Screenshot,
+            4 => PossibleContent::PageNumber,
+            5 => PossibleContent::FileName,
+            6 => PossibleContent::Cloze,
+            7 => match input_template(tui, field_name)? {
+                Some(template) => PossibleContent::Template(template),
+                None => continue,
+            },
+            i if i < fixed_options + section_names.len() => {
+                PossibleContent::Section(section_names[i - fixed_options].clone())
+            }
+            _ => match input_section_name(tui, field_name)? {
+                Some(name) => PossibleContent::Section(name),
+                None => continue,
+            },
+        });
+    }
+}
+
+/// Asks for the name of a section until it is valid. Returns `None` if the user cancelled.
+fn input_section_name(tui: &mut Tui, field_name: &str) -> Result<Option<String>> {
+    let title = format!(
+        "Section for the field {} (e.g. PROOF for lines starting with !PROOF!)",
+        field_name
+    );
+
+    loop {
+        let Some(input) = tui.show_text_input(&title, false)? else {
+            return Ok(None);
+        };
+        let name = input.trim().trim_matches('!').to_uppercase();
+        if is_section_name(&name) {
+            return Ok(Some(name));
+        }
+        let msg = format!(
+            "\"{}\" is not a section name, use letters, digits and _",
+            input
+        );
+        show_message(tui, &msg)?;
+    }
+}
+
+/// Asks for a template until it only contains known placeholders.
+/// Returns `None` if the user cancelled.
+fn input_template(tui: &mut Tui, field_name: &str) -> Result<Option<String>> {
+    let placeholders = TEMPLATE_PLACEHOLDERS
+        .map(|placeholder| format!("{{{{{}}}}}", placeholder))
+        .join(" ");
+    let title = format!("Template for the field {} ({})", field_name, placeholders);
+
+    loop {
+        let Some(template) = tui.show_text_input(&title, false)? else {
+            return Ok(None);
+        };
+        match fill_template(&template, |_| Ok(String::new())) {
+            Ok(_) => return Ok(Some(template)),
+            Err(e) => show_message(tui, &e.to_string())?,
+        }
+    }
 }
 
 pub fn select_field_mapping_for_note_type(
     tui: &mut Tui,
+    anki: &dyn AnkiBackend,
     note_type: &str,
+    section_names: &[String],
 ) -> Result<Vec<(String, PossibleContent)>> {
-    let field_names;
-    loop {
-        match get_field_names(note_type) {
-            Ok(result) => {
-                field_names = result;
-                break;
-            }
-            Err(e) => check_should_retry(e, tui)?,
-        }
+    let field_names = request_with_retry(tui, anki, |anki| anki.get_field_names(note_type))?;
+
+    if let Some(field_mapping) = known_field_mapping(note_type, &field_names) {
+        return Ok(field_mapping);
     }
 
     let mut field_mapping: Vec<(String, PossibleContent)> = Vec::with_capacity(field_names.len());
     for field_name in field_names {
-        let selection: PossibleContent = select_from_possible_content(tui, &field_name)?;
+        let selection: PossibleContent =
+            select_from_possible_content(tui, &field_name, section_names)?;
         field_mapping.push((field_name, selection));
     }
 
     Ok(field_mapping)
 }
 
+pub enum DuplicateResolution {
+    AddAnyway,
+    Cancel,
+    OpenInBrowser(u64),
+}
+
+pub fn select_duplicate_resolution(
+    tui: &mut Tui,
+    similar_notes: &SimilarNotes,
+    policy: DuplicatePolicy,
+) -> Result<DuplicateResolution> {
+    let mut resolutions = Vec::with_capacity(similar_notes.notes.len() + 2);
+    let mut menu_items = Vec::with_capacity(similar_notes.notes.len() + 2);
+
+    if policy != DuplicatePolicy::Reject {
+        resolutions.push(DuplicateResolution::AddAnyway);
+        menu_items.push("Add anyway".to_string());
+    }
+    resolutions.push(DuplicateResolution::Cancel);
+    menu_items.push("Cancel".to_string());
+
+    for note in &similar_notes.notes {
+        resolutions.push(DuplicateResolution::OpenInBrowser(note.note_id));
+        menu_items.push(format!("Open: {}", create_note_preview(note)));
+    }
+
+    let title = if similar_notes.notes.is_empty() {
+        "A note with the same first field already exists"
+    } else {
+        "Similar notes already exist"
+    };
+
+    let index = tui.show_single_selection_menu(title, &menu_items)?;
+    Ok(resolutions.swap_remove(index))
+}
+
+/// Lets the user pick one of the notes that were mined from the current page
+pub fn choose_note_to_update(
+    tui: &mut Tui,
+    anki: &dyn AnkiBackend,
+    config: &AnkiConfig,
+    book_filename: Option<&str>,
+    page_number: Option<u32>,
+) -> Result<Option<NoteInfo>> {
+    let Some(query) = create_source_query(config, book_filename, page_number) else {
+        show_message(
+            tui,
+            "Finding notes requires the file name and page number, mapped to fields",
+        )?;
+        return Ok(None);
+    };
+
+    let notes = request_with_retry(tui, anki, |anki| find_notes_from_page(anki, &query))?;
+
+    if notes.is_empty() {
+        show_message(tui, "No notes found for this page")?;
+        return Ok(None);
+    }
+
+    let mut menu_items: Vec<String> = notes.iter().map(create_note_preview).collect();
+    menu_items.push("Cancel".to_string());
+
+    let index = tui.show_single_selection_menu("Select the note to update", &menu_items)?;
+    Ok(notes.into_iter().nth(index))
+}
+
+pub fn show_message(tui: &mut Tui, msg: &str) -> Result<()> {
+    tui.show_single_selection_menu(msg, &["Return"])?;
+    Ok(())
+}
+
+fn create_note_preview(note: &NoteInfo) -> String {
+    const MAX_PREVIEW_LENGTH: usize = 60;
+
+    let first_field = note
+        .fields
+        .first()
+        .map(|(_, value)| value.as_str())
+        .unwrap_or_default();
+    let single_line = first_field.split_whitespace().collect::<Vec<_>>().join(" ");
+
+    if single_line.chars().count() > MAX_PREVIEW_LENGTH {
+        let truncated: String = single_line.chars().take(MAX_PREVIEW_LENGTH).collect();
+        format!("{}...", truncated)
+    } else {
+        single_line
+    }
+}
+
+pub fn select_duplicate_scope(tui: &mut Tui) -> Result<DuplicateScope> {
+    let options = vec!["Deck", "Collection"];
+    let selected = tui.show_single_selection_menu("Where to look for similar notes", &options)?;
+
+    Ok(match selected {
+        0 => DuplicateScope::Deck,
+        1 => DuplicateScope::Collection,
+        _ => unreachable!(),
+    })
+}
+
+pub fn select_duplicate_policy(tui: &mut Tui) -> Result<DuplicatePolicy> {
+    let options = vec![
+        "Ask what to do",
+        "Always add (don't check)",
+        "Never add duplicates",
+    ];
+    let selected =
+        tui.show_single_selection_menu("What to do when similar notes exist", &options)?;
+
+    Ok(match selected {
+        0 => DuplicatePolicy::Ask,
+        1 => DuplicatePolicy::Allow,
+        2 => DuplicatePolicy::Reject,
+        _ => unreachable!(),
+    })
+}
+
+pub fn select_math_format(tui: &mut Tui, field_name: &str) -> Result<MathFormat> {
+    let options = vec![
+        "[latex] (rendered to an image by Anki)",
+        "[$] (inline math rendered to an image)",
+        "[$$] (display math rendered to an image)",
+        "MathJax (rendered when reviewing)",
+        "Raw HTML",
+    ];
+    let title = format!("How to put LaTeX into the field {}", field_name);
+    let selected = tui.show_single_selection_menu(&title, &options)?;
+
+    Ok(match selected {
+        0 => MathFormat::Latex,
+        1 => MathFormat::LatexMath,
+        2 => MathFormat::LatexDisplayMath,
+        3 => MathFormat::MathJax,
+        4 => MathFormat::Raw,
+        _ => unreachable!(),
+    })
+}
+
 pub fn show_final_menu(state: &mut ApplicationState) -> Result<Box<dyn MenuAction>> {
+    let (send_item, send_and_browse_item) = if state.note_to_update.is_some() {
+        ("Update Note", "Update Note and Open in Browser")
+    } else {
+        ("Send Card", "Send Card and Open in Browser")
+    };
     let menu_items = vec![
-        "Send Card",
+        send_item,
+        send_and_browse_item,
+        "Open in Anki's Add Dialog",
         "Edit Front",
         "Edit Back",
+        "Preview",
+        "Add Image",
+        "Retake Image",
+        "Remove Image",
         "Edit Anki Settings",
         "Edit Tags",
+        "Update Existing Note",
         "Cancel",
     ];
 
@@ -105,10 +422,17 @@ pub fn show_final_menu(state: &mut ApplicationState) -> Result<Box<dyn MenuActio
 
     Ok(match selected {
         0 => Box::new(SendCardAction::new()),
-        1 => Box::new(EditFrontAction::new()),
-        2 => Box::new(EditBackAction::new()),
-        3 => Box::new(EditAnkiSettings::new()),
-        4 => unimplemented!(),
+        1 => Box::new(SendAndBrowseAction::new()),
+        2 => Box::new(OpenAddDialogAction::new()),
+        3 => Box::new(EditFrontAction::new()),
+        4 => Box::new(EditBackAction::new()),
+        5 => Box::new(PreviewAction::new()),
+        6 => Box::new(AddImageAction::new()),
+        7 => Box::new(RetakeImageAction::new()),
+        8 => Box::new(RemoveImageAction::new()),
+        9 => Box::new(EditAnkiSettings::new()),
+        10 => unimplemented!(),
+        11 => Box::new(UpdateExistingNoteAction::new()),
         _ => Box::new(CancelAction::new()),
     })
 }
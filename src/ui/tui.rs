@@ -8,12 +8,13 @@ use ratatui::crossterm::{
     event::{DisableMouseCapture, EnableMouseCapture},
     terminal::{EnterAlternateScreen, LeaveAlternateScreen},
 };
-use ratatui::prelude::{Alignment, Color, Constraint, Direction, Layout, Line, Modifier, Rect, Span, Style, Stylize, Text};
+use ratatui::prelude::{Alignment, Color, Constraint, Direction, Layout, Line, Modifier, Rect, Span, Style, Stylize};
 use ratatui::widgets::{Block, Borders, Clear, List, ListItem, ListState, Paragraph, Wrap};
 use std::path::Path;
 use std::process::Command;
 use std::{
-    fs, io,
+    fs,
+    io::{self, Write},
     ops::{Deref, DerefMut},
 };
 
@@ -74,7 +75,28 @@ impl Tui {
 
     pub(crate) fn edit_file(&mut self, file_path: &Path) -> Result<()> {
         self.suspend()?;
-        edit_file(file_path)?;
+        edit_file(file_path, None)?;
+        self.resume()?;
+        Ok(())
+    }
+
+    /// Opens the editor with the cursor at `line`, using the `+line` argument that
+    /// vi, Emacs, nano and most other editors understand
+    pub(crate) fn edit_file_at_line(&mut self, file_path: &Path, line: usize) -> Result<()> {
+        self.suspend()?;
+        edit_file(file_path, Some(line))?;
+        self.resume()?;
+        Ok(())
+    }
+
+    /// Leaves the TUI to print `output`, which can contain escape sequences like images,
+    /// and returns when Enter is pressed
+    pub(crate) fn show_terminal_output(&mut self, output: &str) -> Result<()> {
+        self.suspend()?;
+        let mut stdout = io::stdout();
+        write!(stdout, "{}\n\nPress Enter to return", output)?;
+        stdout.flush()?;
+        io::stdin().read_line(&mut String::new())?;
         self.resume()?;
         Ok(())
     }
@@ -216,10 +238,71 @@ impl Tui {
         }
     }
 
-    pub fn show_tag_menu(&mut self, tags: &mut Vec<String>) -> anyhow::Result<Vec<String>> {
+    /// Asks for a single line of text. Returns `None` if the input was cancelled.
+    pub fn show_text_input(&mut self, title: &str, masked: bool) -> anyhow::Result<Option<String>> {
+        let mut input = String::new();
+
+        loop {
+            self.terminal.draw(|f| {
+                let chunks = Layout::default()
+                    .direction(Direction::Vertical)
+                    .margin(1)
+                    .constraints([
+                        Constraint::Length(1),
+                        Constraint::Length(3),
+                        Constraint::Min(0),
+                    ])
+                    .split(f.area());
+
+                let help_paragraph = Paragraph::new("Enter: confirm, Esc: cancel")
+                    .style(Style::default().fg(Color::Gray))
+                    .alignment(Alignment::Center);
+                f.render_widget(help_paragraph, chunks[0]);
+
+                let shown_input = if masked {
+                    "*".repeat(input.chars().count())
+                } else {
+                    input.clone()
+                };
+                let input_paragraph = Paragraph::new(shown_input)
+                    .style(Style::default().fg(Color::Yellow))
+                    .block(Block::default().borders(Borders::ALL).title(title));
+                f.render_widget(input_paragraph, chunks[1]);
+            })?;
+
+            if let Event::Key(key) = event::read()? {
+                match key.code {
+                    KeyCode::Char(c) => {
+                        input.push(c);
+                    }
+                    KeyCode::Backspace => {
+                        input.pop();
+                    }
+                    KeyCode::Enter => {
+                        return Ok(Some(input));
+                    }
+                    KeyCode::Esc => {
+                        return Ok(None);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    pub fn show_tag_menu(
+        &mut self,
+        tags: &mut Vec<String>,
+        preselected: &[String],
+    ) -> anyhow::Result<Vec<String>> {
         let mut list_state = ListState::default();
         list_state.select(Some(0));
-        let mut selected_tags: Vec<bool> = vec![false; tags.len()];
+        for tag in preselected {
+            if !tags.contains(tag) {
+                tags.push(tag.clone());
+            }
+        }
+        let mut selected_tags: Vec<bool> = tags.iter().map(|t| preselected.contains(t)).collect();
         let mut new_tag = String::new();
         let mut input_mode = false;
 
@@ -327,15 +410,11 @@ impl Tui {
                         KeyCode::Char('i') => {
                             input_mode = true;
                         }
-                        KeyCode::Char('g') => {
-                            if !tags.is_empty() {
-                                list_state.select(Some(0)); // Jump to first
-                            }
+                        KeyCode::Char('g') if !tags.is_empty() => {
+                            list_state.select(Some(0)); // Jump to first
                         }
-                        KeyCode::Char('G') => {
-                            if !tags.is_empty() {
-                                list_state.select(Some(tags.len() - 1)); // Jump to last
-                            }
+                        KeyCode::Char('G') if !tags.is_empty() => {
+                            list_state.select(Some(tags.len() - 1)); // Jump to last
                         }
                         KeyCode::Char('d') => {
                             if let Some(selected_idx) = list_state.selected() {
@@ -353,35 +432,31 @@ impl Tui {
                                 }
                             }
                         }
-                        KeyCode::Down | KeyCode::Char('j') => {
-                            if !tags.is_empty() {
-                                let i = match list_state.selected() {
-                                    Some(i) => {
-                                        if i >= tags.len() - 1 {
-                                            0
-                                        } else {
-                                            i + 1
-                                        }
+                        KeyCode::Down | KeyCode::Char('j') if !tags.is_empty() => {
+                            let i = match list_state.selected() {
+                                Some(i) => {
+                                    if i >= tags.len() - 1 {
+                                        0
+                                    } else {
+                                        i + 1
                                     }
-                                    None => 0,
-                                };
-                                list_state.select(Some(i));
-                            }
+                                }
+                                None => 0,
+                            };
+                            list_state.select(Some(i));
                         }
-                        KeyCode::Up | KeyCode::Char('k') => {
-                            if !tags.is_empty() {
-                                let i = match list_state.selected() {
-                                    Some(i) => {
-                                        if i == 0 {
-                                            tags.len() - 1
-                                        } else {
-                                            i - 1
-                                        }
+                        KeyCode::Up | KeyCode::Char('k') if !tags.is_empty() => {
+                            let i = match list_state.selected() {
+                                Some(i) => {
+                                    if i == 0 {
+                                        tags.len() - 1
+                                    } else {
+                                        i - 1
                                     }
-                                    None => 0,
-                                };
-                                list_state.select(Some(i));
-                            }
+                                }
+                                None => 0,
+                            };
+                            list_state.select(Some(i));
                         }
                         KeyCode::Char(' ') => {
                             if let Some(i) = list_state.selected() {
@@ -431,12 +506,16 @@ fn centered_content_rect(width: u16, height: u16, container: Rect) -> Rect {
     }
 }
 
-fn edit_file(file_path: &Path) -> io::Result<()> {
+fn edit_file(file_path: &Path, line: Option<usize>) -> io::Result<()> {
     if !file_path.exists() {
         fs::File::create(file_path)?;
     }
 
     let editor_name = get_editor_binary_name();
-    Command::new(editor_name).arg(file_path).status()?;
+    let mut command = Command::new(editor_name);
+    if let Some(line) = line {
+        command.arg(format!("+{}", line));
+    }
+    command.arg(file_path).status()?;
     Ok(())
 }
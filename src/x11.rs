@@ -0,0 +1,109 @@
+use crate::screenshot::Rect;
+use anyhow::{anyhow, Context, Result};
+use xcb::randr::{GetOutputInfo, Output};
+use xcb::x::{
+    Drawable, GetGeometry, GetProperty, InternAtom, QueryPointer, TranslateCoordinates, Window,
+    ATOM_WINDOW,
+};
+use xcb::{Connection, Xid};
+
+/// Connects to the X server and returns the connection and its root window
+fn connect() -> Result<(Connection, Window)> {
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        return Err(anyhow!(
+            "The cursor and the focused window can only be found on X11, \
+             use --capture monitor:<index or name> on Wayland"
+        ));
+    }
+
+    let (connection, screen_index) = Connection::connect(None).context("Connecting to X11")?;
+    let root = connection
+        .get_setup()
+        .roots()
+        .nth(screen_index as usize)
+        .context("No X11 screen found")?
+        .root();
+    Ok((connection, root))
+}
+
+/// Returns the position of the mouse cursor in X11 (physical) coordinates
+pub fn cursor_position() -> Result<(i32, i32)> {
+    let (connection, root) = connect()?;
+
+    let cookie = connection.send_request(&QueryPointer { window: root });
+    let pointer = connection
+        .wait_for_reply(cookie)
+        .context("Querying the cursor position")?;
+
+    Ok((pointer.root_x() as i32, pointer.root_y() as i32))
+}
+
+/// Returns the geometry of the window in `_NET_ACTIVE_WINDOW` in X11 (physical) coordinates,
+/// without the decorations of the window manager
+pub fn active_window_geometry() -> Result<Rect> {
+    let (connection, root) = connect()?;
+
+    let cookie = connection.send_request(&InternAtom {
+        only_if_exists: true,
+        name: b"_NET_ACTIVE_WINDOW",
+    });
+    let active_window_atom = connection.wait_for_reply(cookie)?.atom();
+
+    let cookie = connection.send_request(&GetProperty {
+        delete: false,
+        window: root,
+        property: active_window_atom,
+        r#type: ATOM_WINDOW,
+        long_offset: 0,
+        long_length: 1,
+    });
+    let property = connection
+        .wait_for_reply(cookie)
+        .context("Getting the active window")?;
+    let window = property
+        .value::<Window>()
+        .first()
+        .copied()
+        .filter(|window| !window.is_none())
+        .context("The window manager doesn't report the focused window")?;
+
+    let cookie = connection.send_request(&GetGeometry {
+        drawable: Drawable::Window(window),
+    });
+    let geometry = connection
+        .wait_for_reply(cookie)
+        .context("Getting the geometry of the focused window")?;
+
+    // The position in the geometry is relative to the parent, which is usually a frame
+    let cookie = connection.send_request(&TranslateCoordinates {
+        src_window: window,
+        dst_window: root,
+        src_x: 0,
+        src_y: 0,
+    });
+    let position = connection
+        .wait_for_reply(cookie)
+        .context("Getting the position of the focused window")?;
+
+    Ok(Rect {
+        x: position.dst_x() as i32,
+        y: position.dst_y() as i32,
+        width: geometry.width() as u32,
+        height: geometry.height() as u32,
+    })
+}
+
+/// Returns the RandR name of the output, e.g. `DP-1` or `HDMI-A-0`
+pub fn output_name(output: Output) -> Result<String> {
+    let (connection, _) = Connection::connect(None).context("Connecting to X11")?;
+
+    let cookie = connection.send_request(&GetOutputInfo {
+        output,
+        config_timestamp: 0,
+    });
+    let output_info = connection
+        .wait_for_reply(cookie)
+        .context("Getting the monitor name")?;
+
+    Ok(String::from_utf8_lossy(output_info.name()).into_owned())
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use bookminer::anki_backend::AnkiBackend;
+use bookminer::anki_error_handling::{retry_request, ErrorFix};
+use bookminer::ankiconnect::AnkiConnectError;
+use common::client_for_url;
+use common::fake_anki::{unreachable_url, FakeAnkiServer, FakeAnkiState};
+
+fn server_with_api_key(api_key: &str) -> FakeAnkiServer {
+    FakeAnkiServer::start_with_state(FakeAnkiState {
+        decks: vec!["Default".to_string()],
+        api_key: Some(api_key.to_string()),
+        ..Default::default()
+    })
+}
+
+#[test]
+fn test_retry_with_entered_api_key() {
+    let server = server_with_api_key("secret");
+    let anki = client_for_url(server.url(), Some("wrong"));
+    let mut fixes = Vec::new();
+
+    let decks = retry_request(
+        &anki,
+        |anki| anki.get_deck_names(),
+        |fix| {
+            fixes.push(fix);
+            anki.set_api_key(Some("secret".to_string()));
+            Ok(true)
+        },
+    )
+    .unwrap();
+
+    assert_eq!(decks, ["Default"]);
+    assert_eq!(fixes, [ErrorFix::EnterApiKey]);
+}
+
+#[test]
+fn test_unfixed_errors_are_returned() {
+    let server = server_with_api_key("secret");
+    let anki = client_for_url(server.url(), None);
+
+    let declined = retry_request(&anki, |anki| anki.get_deck_names(), |_| Ok(false));
+    assert!(matches!(
+        declined.unwrap_err().downcast::<AnkiConnectError>(),
+        Ok(AnkiConnectError::InvalidApiKey)
+    ));
+
+    let not_running = client_for_url(unreachable_url(), None);
+    let mut fixes = Vec::new();
+    let result = retry_request(
+        &not_running,
+        |anki| anki.get_model_names(),
+        |fix| {
+            fixes.push(fix);
+            Ok(fixes.len() < 2)
+        },
+    );
+    assert!(result.is_err());
+    assert_eq!(fixes, [ErrorFix::StartAnki, ErrorFix::StartAnki]);
+
+    // Only errors that any request can run into are retried
+    let anki = client_for_url(server.url(), Some("secret"));
+    let result = retry_request(
+        &anki,
+        |anki| anki.get_field_names("Missing"),
+        |_| panic!("A missing note type can't be fixed by retrying"),
+    );
+    assert!(result.is_err());
+}
@@ -0,0 +1,381 @@
+mod common;
+
+use bookminer::anki_backend::{AnkiBackend, StoredMedia};
+use bookminer::ankiconnect::AnkiConnectError;
+use bookminer::note_type::{bookminer_note_type, known_field_mapping, BOOKMINER_NOTE_TYPE};
+use common::client_for_url;
+use common::fake_anki::{unreachable_url, FakeAnkiServer, FakeAnkiState};
+use std::collections::HashMap;
+use std::fs;
+
+fn basic_fields(front: &str, back: &str) -> HashMap<String, String> {
+    HashMap::from([
+        ("Front".to_string(), front.to_string()),
+        ("Back".to_string(), back.to_string()),
+    ])
+}
+
+#[test]
+fn test_lists_decks_models_and_fields() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    assert_eq!(anki.get_deck_names().unwrap(), vec!["Default"]);
+    assert_eq!(anki.get_model_names().unwrap(), vec!["Basic"]);
+    assert_eq!(
+        anki.get_field_names("Basic").unwrap(),
+        vec!["Front", "Back"]
+    );
+}
+
+#[test]
+fn test_field_names_of_unknown_model() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    let result = anki.get_field_names("Missing");
+
+    assert!(matches!(result, Err(AnkiConnectError::ModelNotFound(model)) if model == "Missing"));
+}
+
+#[test]
+fn test_send_note_stores_media_and_note() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let screenshot = tmp_dir.path().join("screenshot_20240101_120000.png");
+    fs::write(&screenshot, b"png data").unwrap();
+
+    let stored_media = anki.store_files(&[&screenshot]).unwrap();
+    let fields = basic_fields("[latex]front[/latex]", "[latex]back[/latex]");
+    let tags = vec!["analysis".to_string()];
+    let note_id = anki
+        .send_note("Default", "Basic", &fields, &tags, &stored_media)
+        .unwrap();
+
+    let state = server.state();
+    assert_eq!(
+        state.media.get("screenshot_20240101_120000.png").unwrap(),
+        b"png data"
+    );
+    assert_eq!(state.notes.len(), 1);
+    assert_eq!(state.notes[0].id, note_id);
+    assert_eq!(state.notes[0].deck, "Default");
+    assert_eq!(state.notes[0].fields, fields);
+    assert_eq!(state.notes[0].tags, tags);
+    assert_eq!(
+        state.requests,
+        vec![
+            "multi",
+            "getMediaFilesNames",
+            "multi",
+            "storeMediaFile",
+            "addNote"
+        ],
+        "Media should be sent in a single request"
+    );
+}
+
+#[test]
+fn test_identical_media_is_not_stored_again() {
+    let server = FakeAnkiServer::start();
+    server
+        .state()
+        .media
+        .insert("page_3_0123abcd.png".to_string(), b"png data".to_vec());
+    let anki = client_for_url(server.url(), None);
+
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let screenshot = tmp_dir.path().join("page_3_0123abcd.png");
+    fs::write(&screenshot, b"png data").unwrap();
+
+    let stored_media = anki.store_files(&[&screenshot]).unwrap();
+    let fields = basic_fields("front", "back");
+    assert!(anki
+        .send_note("Missing", "Basic", &fields, &[], &stored_media)
+        .is_err());
+
+    // The file may be used by another note, so the rollback must keep it
+    let state = server.state();
+    assert!(stored_media.is_empty());
+    assert!(!state.requests.contains(&"deleteMediaFile".to_string()));
+    assert_eq!(state.media.get("page_3_0123abcd.png").unwrap(), b"png data");
+}
+
+#[test]
+fn test_rejected_note_rolls_back_media() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let screenshot = tmp_dir.path().join("screenshot.png");
+    fs::write(&screenshot, b"png data").unwrap();
+
+    let stored_media = anki.store_files(&[&screenshot]).unwrap();
+    let fields = basic_fields("front", "back");
+    let result = anki.send_note("Missing", "Basic", &fields, &[], &stored_media);
+
+    assert!(result.is_err());
+    let state = server.state();
+    assert!(state.media.is_empty());
+    assert!(state.notes.is_empty());
+    assert!(state.requests.contains(&"deleteMediaFile".to_string()));
+}
+
+#[test]
+fn test_retry_after_rejection_stores_media_once() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let screenshot = tmp_dir.path().join("screenshot.png");
+    fs::write(&screenshot, b"png data").unwrap();
+
+    let fields = basic_fields("front", "back");
+    let stored_media = anki.store_files(&[&screenshot]).unwrap();
+    assert!(anki
+        .send_note("Missing", "Basic", &fields, &[], &stored_media)
+        .is_err());
+    server.state().decks.push("Missing".to_string());
+    let stored_media = anki.store_files(&[&screenshot]).unwrap();
+    anki.send_note("Missing", "Basic", &fields, &[], &stored_media)
+        .unwrap();
+
+    let state = server.state();
+    assert_eq!(state.media.len(), 1);
+    assert_eq!(state.notes.len(), 1);
+}
+
+#[test]
+fn test_send_note_to_missing_deck_fails() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    let fields = basic_fields("front", "back");
+    let result = anki.send_note("Missing", "Basic", &fields, &[], &StoredMedia::new());
+
+    match result {
+        Err(AnkiConnectError::DeckNotFound(deck)) => assert_eq!(deck, "Missing"),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+    assert!(server.state().notes.is_empty());
+}
+
+#[test]
+fn test_send_note_with_empty_first_field_fails() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    let fields = basic_fields("", "back");
+    let result = anki.send_note("Default", "Basic", &fields, &[], &StoredMedia::new());
+
+    assert!(matches!(result, Err(AnkiConnectError::EmptyNote)));
+    assert!(server.state().notes.is_empty());
+}
+
+#[test]
+fn test_send_note_with_unknown_note_type_fails() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    let fields = basic_fields("front", "back");
+    let result = anki.send_note("Default", "Cloze", &fields, &[], &StoredMedia::new());
+
+    assert!(matches!(result, Err(AnkiConnectError::ModelNotFound(model)) if model == "Cloze"));
+}
+
+#[test]
+fn test_set_api_key() {
+    let state = FakeAnkiState {
+        decks: vec!["Default".to_string()],
+        api_key: Some("secret".to_string()),
+        ..Default::default()
+    };
+    let server = FakeAnkiServer::start_with_state(state);
+    let anki = client_for_url(server.url(), Some("wrong"));
+
+    assert!(matches!(
+        anki.get_deck_names(),
+        Err(AnkiConnectError::InvalidApiKey)
+    ));
+    anki.set_api_key(Some("secret".to_string()));
+    assert_eq!(anki.get_deck_names().unwrap(), vec!["Default"]);
+}
+
+#[test]
+fn test_send_note_with_missing_file_fails() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let missing = tmp_dir.path().join("missing.png");
+    let result = anki.store_files(&[&missing]);
+
+    assert!(matches!(result, Err(AnkiConnectError::FileError { .. })));
+    assert!(server.state().requests.is_empty());
+}
+
+#[test]
+fn test_api_key_is_sent() {
+    let state = FakeAnkiState {
+        decks: vec!["Default".to_string()],
+        api_key: Some("secret".to_string()),
+        ..Default::default()
+    };
+    let server = FakeAnkiServer::start_with_state(state);
+
+    let without_key = client_for_url(server.url(), None);
+    assert!(matches!(
+        without_key.get_deck_names(),
+        Err(AnkiConnectError::InvalidApiKey)
+    ));
+
+    let with_key = client_for_url(server.url(), Some("secret"));
+    assert_eq!(with_key.get_deck_names().unwrap(), vec!["Default"]);
+}
+
+#[test]
+fn test_media_is_stored_and_rolled_back_with_api_key() {
+    let state = FakeAnkiState {
+        decks: vec!["Default".to_string()],
+        api_key: Some("secret".to_string()),
+        ..Default::default()
+    };
+    let server = FakeAnkiServer::start_with_state(state);
+    let anki = client_for_url(server.url(), Some("secret"));
+
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let screenshot = tmp_dir.path().join("screenshot.png");
+    fs::write(&screenshot, b"png data").unwrap();
+
+    let stored_media = anki.store_files(&[&screenshot]).unwrap();
+    assert_eq!(stored_media.get(&screenshot).unwrap(), "screenshot.png");
+    assert_eq!(server.state().media.len(), 1);
+
+    anki.delete_media_files(&stored_media).unwrap();
+    assert!(server.state().media.is_empty());
+}
+
+#[test]
+fn test_unreachable_server_is_not_running() {
+    let anki = client_for_url(unreachable_url(), None);
+
+    assert!(matches!(
+        anki.get_deck_names(),
+        Err(AnkiConnectError::NotRunning)
+    ));
+}
+
+#[test]
+fn test_store_files_returns_renamed_filename() {
+    let server = FakeAnkiServer::start();
+    server
+        .state()
+        .media
+        .insert("screenshot.png".to_string(), b"other image".to_vec());
+    let anki = client_for_url(server.url(), None);
+
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let screenshot = tmp_dir.path().join("screenshot.png");
+    fs::write(&screenshot, b"png data").unwrap();
+
+    let stored_media = anki.store_files(&[&screenshot]).unwrap();
+    assert_eq!(stored_media.get(&screenshot).unwrap(), "screenshot-1.png");
+    assert_eq!(
+        server.state().media.get("screenshot.png").unwrap(),
+        b"other image"
+    );
+}
+
+#[test]
+fn test_create_subdeck() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    anki.create_deck("Analysis::Measure Theory").unwrap();
+
+    assert_eq!(
+        anki.get_deck_names().unwrap(),
+        vec!["Default", "Analysis", "Analysis::Measure Theory"]
+    );
+}
+
+#[test]
+fn test_create_bookminer_note_type() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    anki.create_note_type(&bookminer_note_type()).unwrap();
+
+    let field_names = anki.get_field_names(BOOKMINER_NOTE_TYPE).unwrap();
+    assert_eq!(
+        field_names,
+        vec!["Front", "Back", "Screenshot", "Source", "Page"]
+    );
+    assert!(known_field_mapping(BOOKMINER_NOTE_TYPE, &field_names).is_some());
+    assert_eq!(
+        server.state().card_templates[BOOKMINER_NOTE_TYPE][0]["Front"],
+        "{{Front}}"
+    );
+
+    let result = anki.create_note_type(&bookminer_note_type());
+    assert!(matches!(result, Err(AnkiConnectError::BadRequestError(_))));
+}
+
+#[test]
+fn test_get_latex_preamble() {
+    let server = FakeAnkiServer::start();
+    server.state().latex_preambles.insert(
+        "Basic".to_string(),
+        "\\newcommand{\\R}{\\mathbb{R}}".to_string(),
+    );
+    let anki = client_for_url(server.url(), None);
+
+    assert_eq!(
+        anki.get_latex_preamble("Basic").unwrap(),
+        "\\newcommand{\\R}{\\mathbb{R}}"
+    );
+    assert!(matches!(
+        anki.get_latex_preamble("Missing"),
+        Err(AnkiConnectError::ModelNotFound(_))
+    ));
+}
+
+#[test]
+fn test_gui_add_cards_opens_prefilled_dialog() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+
+    let fields = basic_fields("front", "back");
+    anki.gui_add_cards("Default", "Basic", &fields, &["analysis".to_string()])
+        .unwrap();
+
+    let state = server.state();
+    assert!(state.notes.is_empty());
+    assert_eq!(state.add_dialogs.len(), 1);
+    assert_eq!(state.add_dialogs[0]["deckName"], "Default");
+    assert_eq!(state.add_dialogs[0]["fields"]["Front"], "front");
+    assert_eq!(state.add_dialogs[0]["tags"][0], "analysis");
+}
+
+#[test]
+fn test_is_cloze_note_type() {
+    let mut state = FakeAnkiState {
+        decks: vec!["Default".to_string()],
+        ..Default::default()
+    };
+    state.models.insert(
+        "Basic".to_string(),
+        vec!["Front".to_string(), "Back".to_string()],
+    );
+    state.models.insert(
+        "Cloze".to_string(),
+        vec!["Text".to_string(), "Back Extra".to_string()],
+    );
+    let server = FakeAnkiServer::start_with_state(state);
+    let anki = client_for_url(server.url(), None);
+
+    assert!(anki.is_cloze_note_type("Cloze").unwrap());
+    assert!(!anki.is_cloze_note_type("Basic").unwrap());
+}
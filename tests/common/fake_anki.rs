@@ -0,0 +1,526 @@
+//! A minimal in-process imitation of the AnkiConnect HTTP API.
+//!
+//! Decks, note types, media files and notes are kept in memory. The error
+//! messages mirror the ones returned by the real add-on so that the error
+//! handling of bookminer can be tested without a running Anki.
+
+use base64::engine::general_purpose;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+
+#[derive(Debug, Clone)]
+pub struct FakeNote {
+    pub id: u64,
+    pub deck: String,
+    pub model: String,
+    pub fields: HashMap<String, String>,
+    pub tags: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct FakeAnkiState {
+    pub decks: Vec<String>,
+    pub models: BTreeMap<String, Vec<String>>,
+    pub media: BTreeMap<String, Vec<u8>>,
+    pub notes: Vec<FakeNote>,
+    pub api_key: Option<String>,
+    /// Every action received, in order
+    pub requests: Vec<String>,
+    /// Queries passed to `guiBrowse`
+    pub browsed: Vec<String>,
+    /// Card templates of the note types created with `createModel`
+    pub card_templates: BTreeMap<String, Value>,
+    /// LaTeX preambles of the note types, empty if not set
+    pub latex_preambles: BTreeMap<String, String>,
+    /// Notes passed to `guiAddCards`
+    pub add_dialogs: Vec<Value>,
+}
+
+impl FakeAnkiState {
+    fn handle(&mut self, request: &Value) -> Result<Value, String> {
+        let action = request["action"].as_str().ok_or("missing action")?;
+
+        // Like the real add-on, the key of every action inside `multi` is checked as well
+        if let Some(api_key) = &self.api_key {
+            if request["key"].as_str() != Some(api_key.as_str()) {
+                return Err("valid api key must be provided".to_string());
+            }
+        }
+
+        self.handle_action(action, &request["params"])
+    }
+
+    fn handle_action(&mut self, action: &str, params: &Value) -> Result<Value, String> {
+        self.requests.push(action.to_string());
+
+        match action {
+            "multi" => {
+                let actions = params["actions"].as_array().ok_or("missing actions")?;
+                let results = actions
+                    .iter()
+                    .map(|a| to_response(self.handle(a)))
+                    .collect::<Vec<_>>();
+                Ok(json!(results))
+            }
+            "deckNames" => Ok(json!(self.decks)),
+            "modelNames" => Ok(json!(self.models.keys().collect::<Vec<_>>())),
+            "modelFieldNames" => {
+                let model_name = params["modelName"].as_str().unwrap_or_default();
+                self.models
+                    .get(model_name)
+                    .map(|fields| json!(fields))
+                    .ok_or_else(|| format!("model was not found: {}", model_name))
+            }
+            "storeMediaFile" => {
+                let filename = params["filename"].as_str().ok_or("missing filename")?;
+                let data = params["data"].as_str().ok_or("missing data")?;
+                let data = general_purpose::STANDARD
+                    .decode(data)
+                    .map_err(|e| e.to_string())?;
+                Ok(json!(self.store_media(filename, data)))
+            }
+            "getMediaFilesNames" => {
+                // Only escaped file names are supported, not real wildcards
+                let pattern = params["pattern"].as_str().ok_or("missing pattern")?;
+                let filename = unescape_pattern(pattern);
+                Ok(json!(self
+                    .media
+                    .keys()
+                    .filter(|name| **name == filename)
+                    .collect::<Vec<_>>()))
+            }
+            "deleteMediaFile" => {
+                let filename = params["filename"].as_str().ok_or("missing filename")?;
+                self.media.remove(filename);
+                Ok(Value::Null)
+            }
+            "deleteNotes" => {
+                let ids: Vec<u64> = params["notes"]
+                    .as_array()
+                    .map(|ids| ids.iter().filter_map(Value::as_u64).collect())
+                    .unwrap_or_default();
+                self.notes.retain(|note| !ids.contains(&note.id));
+                Ok(Value::Null)
+            }
+            "addNote" => self.add_note(&params["note"]).map(|id| json!(id)),
+            "canAddNotes" => {
+                let notes = params["notes"].as_array().ok_or("missing notes")?;
+                Ok(json!(notes
+                    .iter()
+                    .map(|note| !self.is_duplicate(note))
+                    .collect::<Vec<_>>()))
+            }
+            "findNotes" => {
+                let query = params["query"].as_str().unwrap_or_default();
+                Ok(json!(self.find_notes(query)))
+            }
+            "notesInfo" => {
+                let ids: Vec<u64> = params["notes"]
+                    .as_array()
+                    .map(|ids| ids.iter().filter_map(Value::as_u64).collect())
+                    .unwrap_or_default();
+                Ok(json!(ids
+                    .iter()
+                    .filter_map(|id| self.notes.iter().find(|note| note.id == *id))
+                    .map(|note| self.note_info(note))
+                    .collect::<Vec<_>>()))
+            }
+            "updateNoteFields" => {
+                let id = params["note"]["id"].as_u64().ok_or("missing note id")?;
+                let note = self
+                    .notes
+                    .iter_mut()
+                    .find(|note| note.id == id)
+                    .ok_or("Note was not found")?;
+                let fields = params["note"]["fields"]
+                    .as_object()
+                    .ok_or("missing fields")?;
+                // Like Anki, a field the note doesn't have rejects the whole update
+                if let Some(name) = fields.keys().find(|name| !note.fields.contains_key(*name)) {
+                    return Err(format!("'{}'", name));
+                }
+                for (name, value) in fields {
+                    note.fields
+                        .insert(name.clone(), value.as_str().unwrap_or_default().to_string());
+                }
+                Ok(Value::Null)
+            }
+            "updateNoteTags" => {
+                let id = params["note"].as_u64().ok_or("missing note id")?;
+                let note = self
+                    .notes
+                    .iter_mut()
+                    .find(|note| note.id == id)
+                    .ok_or("Note was not found")?;
+                note.tags = params["tags"]
+                    .as_array()
+                    .map(|tags| {
+                        tags.iter()
+                            .filter_map(|t| t.as_str().map(String::from))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                Ok(Value::Null)
+            }
+            "guiBrowse" => {
+                let query = params["query"].as_str().unwrap_or_default();
+                self.browsed.push(query.to_string());
+                Ok(json!(self.find_notes(query)))
+            }
+            "findModelsByName" => {
+                let names = params["modelNames"]
+                    .as_array()
+                    .ok_or("missing modelNames")?;
+                names
+                    .iter()
+                    .map(|name| {
+                        let name = name.as_str().unwrap_or_default();
+                        if !self.models.contains_key(name) {
+                            return Err(format!("model was not found: {}", name));
+                        }
+                        let latex_pre = self.latex_preambles.get(name).cloned();
+                        // Note types named "Cloze..." are treated as cloze note types
+                        let model_type = if name.starts_with("Cloze") { 1 } else { 0 };
+                        Ok(json!({
+                            "name": name,
+                            "type": model_type,
+                            "latexPre": latex_pre.unwrap_or_default(),
+                            "latexPost": "\\end{document}",
+                        }))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+                    .map(|models| json!(models))
+            }
+            "guiAddCards" => {
+                self.add_dialogs.push(params["note"].clone());
+                Ok(json!(1_000_001 + self.notes.len() as u64))
+            }
+            "createDeck" => {
+                let deck = params["deck"].as_str().ok_or("missing deck")?;
+                // Like Anki, the parents of a `Parent::Child` deck are created as well
+                let parts: Vec<&str> = deck.split("::").collect();
+                for end in 1..=parts.len() {
+                    let name = parts[..end].join("::");
+                    if !self.decks.contains(&name) {
+                        self.decks.push(name);
+                    }
+                }
+                let index = self
+                    .decks
+                    .iter()
+                    .position(|d| d == deck)
+                    .unwrap_or_default();
+                Ok(json!(1_500_000_000 + index as u64))
+            }
+            "createModel" => {
+                let model_name = params["modelName"].as_str().ok_or("missing modelName")?;
+                if self.models.contains_key(model_name) {
+                    return Err("Model name already exists".to_string());
+                }
+                let fields = params["inOrderFields"]
+                    .as_array()
+                    .map(|fields| {
+                        fields
+                            .iter()
+                            .filter_map(|f| f.as_str().map(String::from))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                self.models.insert(model_name.to_string(), fields);
+                self.card_templates
+                    .insert(model_name.to_string(), params["cardTemplates"].clone());
+                Ok(json!({ "name": model_name }))
+            }
+            _ => Err("unsupported action".to_string()),
+        }
+    }
+
+    /// Like Anki, a file that clashes with an existing file of a different
+    /// content is stored under a new name.
+    fn store_media(&mut self, filename: &str, data: Vec<u8>) -> String {
+        let (stem, extension) = filename.rsplit_once('.').unwrap_or((filename, ""));
+        let mut name = filename.to_string();
+        let mut counter = 0;
+        while self
+            .media
+            .get(&name)
+            .is_some_and(|existing| *existing != data)
+        {
+            counter += 1;
+            name = format!("{}-{}.{}", stem, counter, extension);
+        }
+        self.media.insert(name.clone(), data);
+        name
+    }
+
+    fn first_field<'a>(&self, note: &'a FakeNote) -> Option<&'a str> {
+        let first_field_name = self.models.get(&note.model)?.first()?;
+        note.fields.get(first_field_name).map(String::as_str)
+    }
+
+    fn is_duplicate(&self, note: &Value) -> bool {
+        let deck = note["deckName"].as_str().unwrap_or_default();
+        let model = note["modelName"].as_str().unwrap_or_default();
+        let in_deck_only = note["options"]["duplicateScope"].as_str() == Some("deck");
+        let Some(first_field_name) = self.models.get(model).and_then(|f| f.first()) else {
+            return false;
+        };
+        let first_field = note["fields"][first_field_name].as_str();
+
+        self.notes.iter().any(|existing| {
+            existing.model == model
+                && (!in_deck_only || existing.deck == deck)
+                && self.first_field(existing) == first_field
+        })
+    }
+
+    /// Supports queries made of `key:value` terms that all have to match,
+    /// where the key is `deck`, `note`, `nid` or a field name.
+    fn find_notes(&self, query: &str) -> Vec<u64> {
+        let terms = parse_query(query);
+        self.notes
+            .iter()
+            .filter(|note| {
+                terms.iter().all(|(key, value)| match key.as_str() {
+                    "deck" => note.deck == *value || note.deck.starts_with(&format!("{}::", value)),
+                    "note" => note.model == *value,
+                    "nid" => note.id.to_string() == *value,
+                    field => note.fields.iter().any(|(name, content)| {
+                        name.eq_ignore_ascii_case(field) && content == value
+                    }),
+                })
+            })
+            .map(|note| note.id)
+            .collect()
+    }
+
+    fn note_info(&self, note: &FakeNote) -> Value {
+        let field_names = self.models.get(&note.model).cloned().unwrap_or_default();
+        let fields: serde_json::Map<String, Value> = field_names
+            .iter()
+            .enumerate()
+            .map(|(order, name)| {
+                let value = note.fields.get(name).cloned().unwrap_or_default();
+                (name.clone(), json!({ "value": value, "order": order }))
+            })
+            .collect();
+
+        json!({
+            "noteId": note.id,
+            "modelName": note.model,
+            "tags": note.tags,
+            "fields": fields,
+        })
+    }
+
+    fn add_note(&mut self, note: &Value) -> Result<u64, String> {
+        let deck = note["deckName"].as_str().unwrap_or_default();
+        let model = note["modelName"].as_str().unwrap_or_default();
+
+        if !self.decks.iter().any(|d| d == deck) {
+            return Err(format!("deck was not found: {}", deck));
+        }
+        let model_fields = self
+            .models
+            .get(model)
+            .ok_or_else(|| format!("model was not found: {}", model))?;
+
+        // Like AnkiConnect, fields that the note type doesn't have are ignored
+        let fields: HashMap<String, String> = model_fields
+            .iter()
+            .map(|name| {
+                let value = note["fields"][name].as_str().unwrap_or_default();
+                (name.clone(), value.to_string())
+            })
+            .collect();
+
+        let first_field = model_fields.first().and_then(|name| fields.get(name));
+        if first_field.is_none_or(|value| value.trim().is_empty()) {
+            return Err("cannot create note because it is empty".to_string());
+        }
+
+        let tags = note["tags"]
+            .as_array()
+            .map(|tags| {
+                tags.iter()
+                    .filter_map(|t| t.as_str().map(String::from))
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let id = 1_000_001 + self.notes.len() as u64;
+        self.notes.push(FakeNote {
+            id,
+            deck: deck.to_string(),
+            model: model.to_string(),
+            fields,
+            tags,
+        });
+
+        Ok(id)
+    }
+}
+
+pub struct FakeAnkiServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<FakeAnkiState>>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl FakeAnkiServer {
+    /// Starts a server on an ephemeral port with a "Default" deck and a
+    /// "Basic" note type.
+    pub fn start() -> Self {
+        let mut state = FakeAnkiState::default();
+        state.decks.push("Default".to_string());
+        state.models.insert(
+            "Basic".to_string(),
+            vec!["Front".to_string(), "Back".to_string()],
+        );
+        Self::start_with_state(state)
+    }
+
+    pub fn start_with_state(state: FakeAnkiState) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Binding fake AnkiConnect");
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::new(Mutex::new(state));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let handle = {
+            let state = Arc::clone(&state);
+            let running = Arc::clone(&running);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    if let Ok(stream) = stream {
+                        handle_connection(stream, &state);
+                    }
+                }
+            })
+        };
+
+        Self {
+            addr,
+            state,
+            running,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, FakeAnkiState> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Drop for FakeAnkiServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        // Wake up the accept loop so that it notices the shutdown
+        let _ = TcpStream::connect(self.addr);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Returns the URL of a local port on which nothing is listening.
+pub fn unreachable_url() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    drop(listener);
+    format!("http://{}", addr)
+}
+
+/// Splits a search query into unescaped `(key, value)` terms
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    let mut terms = Vec::new();
+    let mut term = String::new();
+    let mut in_quotes = false;
+    let mut chars = query.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => term.extend(chars.next()),
+            '"' => in_quotes = !in_quotes,
+            ' ' if !in_quotes => terms.push(std::mem::take(&mut term)),
+            c => term.push(c),
+        }
+    }
+    terms.push(term);
+
+    terms
+        .into_iter()
+        .filter_map(|term| {
+            term.split_once(':')
+                .map(|(key, value)| (key.to_lowercase(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Turns the escaped wildcards `[*]`, `[?]` and `[[]` of a glob pattern back into characters
+fn unescape_pattern(pattern: &str) -> String {
+    pattern
+        .replace("[*]", "*")
+        .replace("[?]", "?")
+        .replace("[[]", "[")
+}
+
+fn to_response(result: Result<Value, String>) -> Value {
+    match result {
+        Ok(result) => json!({ "result": result, "error": null }),
+        Err(error) => json!({ "result": null, "error": error }),
+    }
+}
+
+fn handle_connection(stream: TcpStream, state: &Mutex<FakeAnkiState>) {
+    let mut reader = BufReader::new(&stream);
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+
+    let mut body = vec![0; content_length];
+    if reader.read_exact(&mut body).is_err() {
+        return;
+    }
+
+    let response = match serde_json::from_slice::<Value>(&body) {
+        Ok(request) => to_response(state.lock().unwrap().handle(&request)),
+        Err(e) => json!({ "result": null, "error": e.to_string() }),
+    };
+
+    let response = response.to_string();
+    let mut stream = &stream;
+    let _ = write!(
+        stream,
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.len(),
+        response
+    );
+}
@@ -0,0 +1,45 @@
+// Not every test binary uses every helper
+#![allow(dead_code)]
+
+pub mod fake_anki;
+
+use bookminer::anki_config::AnkiConfig;
+use bookminer::ankiconnect::AnkiConnectClient;
+use bookminer::possible_entries::PossibleContent;
+use bookminer::settings::AnkiConnectSettings;
+use fake_anki::{FakeAnkiServer, FakeAnkiState};
+
+pub fn client_for_url(url: String, api_key: Option<&str>) -> AnkiConnectClient {
+    let settings = AnkiConnectSettings {
+        url,
+        api_key: api_key.map(String::from),
+        timeout_secs: 5,
+    };
+    AnkiConnectClient::new(&settings).expect("Creating AnkiConnect client")
+}
+
+/// Starts a fake AnkiConnect with the given decks and a single note type
+pub fn start_server(decks: &[&str], note_type: &str, fields: &[&str]) -> FakeAnkiServer {
+    let mut state = FakeAnkiState {
+        decks: decks.iter().map(|deck| deck.to_string()).collect(),
+        ..Default::default()
+    };
+    state.models.insert(
+        note_type.to_string(),
+        fields.iter().map(|field| field.to_string()).collect(),
+    );
+    FakeAnkiServer::start_with_state(state)
+}
+
+/// Settings with the default duplicate handling that fill the fields with `mapping`
+pub fn create_config(
+    deck: &str,
+    note_type: &str,
+    mapping: &[(&str, PossibleContent)],
+) -> AnkiConfig {
+    let field_mapping = mapping
+        .iter()
+        .map(|(field, content)| (field.to_string(), content.clone()))
+        .collect();
+    AnkiConfig::new(deck, note_type, field_mapping)
+}
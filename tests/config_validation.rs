@@ -0,0 +1,51 @@
+mod common;
+
+use bookminer::anki_config::AnkiConfig;
+use bookminer::config_validation::find_stale_settings;
+use bookminer::possible_entries::PossibleContent;
+use common::client_for_url;
+use common::fake_anki::FakeAnkiServer;
+
+fn start_server() -> FakeAnkiServer {
+    common::start_server(&["Analysis"], "Book", &["Front", "Source", "Page"])
+}
+
+/// Settings saved before the "File" field was renamed to "Source"
+fn create_config(deck: &str, note_type: &str) -> AnkiConfig {
+    common::create_config(
+        deck,
+        note_type,
+        &[
+            ("Front", PossibleContent::Front),
+            ("File", PossibleContent::FileName),
+            ("Page", PossibleContent::PageNumber),
+        ],
+    )
+}
+
+#[test]
+fn test_renamed_field_is_stale() {
+    let server = start_server();
+    let client = client_for_url(server.url(), None);
+
+    let stale = find_stale_settings(&client, &create_config("Analysis", "Book")).unwrap();
+
+    assert!(!stale.deck_missing);
+    assert!(!stale.note_type_missing);
+    assert_eq!(stale.removed_fields, vec!["File"]);
+    assert_eq!(stale.new_fields, vec!["Source"]);
+    assert_eq!(stale.field_names, vec!["Front", "Source", "Page"]);
+}
+
+#[test]
+fn test_missing_deck_and_note_type() {
+    let server = start_server();
+    let client = client_for_url(server.url(), None);
+
+    let stale = find_stale_settings(&client, &create_config("Deleted", "Gone")).unwrap();
+
+    assert!(stale.deck_missing);
+    assert!(stale.note_type_missing);
+    assert!(stale.removed_fields.is_empty());
+    assert!(stale.new_fields.is_empty());
+}
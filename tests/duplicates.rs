@@ -0,0 +1,114 @@
+mod common;
+
+use bookminer::anki_backend::{AnkiBackend, StoredMedia};
+use bookminer::anki_config::{AnkiConfig, DuplicateScope};
+use bookminer::duplicates::find_similar_notes;
+use bookminer::possible_entries::PossibleContent;
+use common::client_for_url;
+use common::fake_anki::FakeAnkiServer;
+use std::collections::HashMap;
+
+fn start_server() -> FakeAnkiServer {
+    common::start_server(&["Analysis", "Algebra"], "Book", &["Front", "File", "Page"])
+}
+
+fn create_config(deck: &str, scope: DuplicateScope) -> AnkiConfig {
+    let mapping = [
+        ("Front", PossibleContent::Front),
+        ("File", PossibleContent::FileName),
+        ("Page", PossibleContent::PageNumber),
+    ];
+    AnkiConfig {
+        duplicate_scope: scope,
+        ..common::create_config(deck, "Book", &mapping)
+    }
+}
+
+fn create_fields(front: &str, file: &str, page: &str) -> HashMap<String, String> {
+    HashMap::from([
+        ("Front".to_string(), front.to_string()),
+        ("File".to_string(), file.to_string()),
+        ("Page".to_string(), page.to_string()),
+    ])
+}
+
+#[test]
+fn test_no_similar_notes_in_empty_deck() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+    let config = create_config("Analysis", DuplicateScope::Deck);
+
+    let fields = create_fields("[latex]$x_1$[/latex]", "book.pdf", "42");
+    let similar = find_similar_notes(&anki, &config, &fields).unwrap();
+
+    assert!(similar.is_empty());
+}
+
+#[test]
+fn test_finds_note_with_same_first_field() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+    let config = create_config("Analysis", DuplicateScope::Deck);
+
+    let fields = create_fields("[latex]$x_1$[/latex]", "book.pdf", "42");
+    anki.send_note("Analysis", "Book", &fields, &[], &StoredMedia::new())
+        .unwrap();
+
+    let new_fields = create_fields("[latex]$x_1$[/latex]", "other.pdf", "1");
+    let similar = find_similar_notes(&anki, &config, &new_fields).unwrap();
+
+    assert!(similar.first_field_duplicate);
+    assert_eq!(similar.notes.len(), 1);
+    assert_eq!(
+        similar.notes[0].field("Front"),
+        Some("[latex]$x_1$[/latex]")
+    );
+}
+
+#[test]
+fn test_finds_note_from_same_page() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+    let config = create_config("Analysis", DuplicateScope::Deck);
+
+    let fields = create_fields("Theorem 1", "book.pdf", "42");
+    anki.send_note("Analysis", "Book", &fields, &[], &StoredMedia::new())
+        .unwrap();
+
+    let new_fields = create_fields("Theorem 2", "book.pdf", "42");
+    let similar = find_similar_notes(&anki, &config, &new_fields).unwrap();
+
+    assert!(!similar.first_field_duplicate);
+    assert_eq!(similar.notes.len(), 1);
+    assert_eq!(similar.notes[0].field("Front"), Some("Theorem 1"));
+}
+
+#[test]
+fn test_duplicate_scope() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+
+    let fields = create_fields("Theorem 1", "book.pdf", "42");
+    anki.send_note("Algebra", "Book", &fields, &[], &StoredMedia::new())
+        .unwrap();
+
+    let deck_config = create_config("Analysis", DuplicateScope::Deck);
+    assert!(find_similar_notes(&anki, &deck_config, &fields)
+        .unwrap()
+        .is_empty());
+
+    let collection_config = create_config("Analysis", DuplicateScope::Collection);
+    let similar = find_similar_notes(&anki, &collection_config, &fields).unwrap();
+    assert!(similar.first_field_duplicate);
+    assert_eq!(similar.notes.len(), 1);
+}
+
+#[test]
+fn test_gui_browse_opens_note() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+
+    anki.gui_browse("nid:1000001").unwrap();
+
+    assert_eq!(server.state().browsed, vec!["nid:1000001"]);
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use anyhow::anyhow;
+use bookminer::anki_backend::AnkiBackend;
+use bookminer::anki_error_handling::{error_fix, ErrorFix};
+use bookminer::ankiconnect::AnkiConnectError;
+use bookminer::note_send::{send_prepared_note, NoteDestination};
+use common::client_for_url;
+use common::fake_anki::FakeAnkiServer;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+const DESTINATION: NoteDestination = NoteDestination {
+    deck_name: "Default",
+    note_type: "Basic",
+    note_to_update: None,
+};
+
+fn basic_fields(front: &str) -> HashMap<String, String> {
+    HashMap::from([
+        ("Front".to_string(), front.to_string()),
+        ("Back".to_string(), "<img src=\"figure.png\">".to_string()),
+    ])
+}
+
+fn write_figure(dir: &tempfile::TempDir) -> PathBuf {
+    let figure = dir.path().join("figure.png");
+    fs::write(&figure, b"png data").unwrap();
+    figure
+}
+
+#[test]
+fn test_send_and_update_prepared_note() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let figure = write_figure(&tmp_dir);
+    let tags = vec!["algebra".to_string()];
+
+    let stored_media = anki.store_files(&[&figure]).unwrap();
+    let note_id = send_prepared_note(
+        &anki,
+        DESTINATION,
+        &tags,
+        &stored_media,
+        Ok(Some(basic_fields("first"))),
+    )
+    .unwrap()
+    .expect("Sent");
+
+    let update = NoteDestination {
+        note_to_update: Some(note_id),
+        ..DESTINATION
+    };
+    let updated = send_prepared_note(
+        &anki,
+        update,
+        &[],
+        &stored_media,
+        Ok(Some(basic_fields("second"))),
+    )
+    .unwrap();
+
+    assert_eq!(updated, Some(note_id));
+    let state = server.state();
+    assert_eq!(state.notes.len(), 1);
+    assert_eq!(state.notes[0].fields["Front"], "second");
+    assert!(state.notes[0].tags.is_empty());
+    assert_eq!(state.media["figure.png"], b"png data");
+}
+
+#[test]
+fn test_cancelled_or_failed_preparation_deletes_media() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let figure = write_figure(&tmp_dir);
+
+    let stored_media = anki.store_files(&[&figure]).unwrap();
+    let cancelled = send_prepared_note(&anki, DESTINATION, &[], &stored_media, Ok(None));
+    assert!(matches!(cancelled, Ok(None)));
+    assert!(server.state().media.is_empty());
+
+    let stored_media = anki.store_files(&[&figure]).unwrap();
+    let failed = send_prepared_note(
+        &anki,
+        DESTINATION,
+        &[],
+        &stored_media,
+        Err(anyhow!("Parsing front.tex")),
+    );
+    assert!(failed.is_err());
+    assert!(server.state().media.is_empty());
+    assert!(server.state().notes.is_empty());
+}
+
+#[test]
+fn test_rejected_note_leads_to_its_fix() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let figure = write_figure(&tmp_dir);
+    let destination = NoteDestination {
+        deck_name: "Missing",
+        ..DESTINATION
+    };
+
+    let stored_media = anki.store_files(&[&figure]).unwrap();
+    let error = send_prepared_note(
+        &anki,
+        destination,
+        &[],
+        &stored_media,
+        Ok(Some(basic_fields("first"))),
+    )
+    .unwrap_err()
+    .downcast::<AnkiConnectError>()
+    .unwrap();
+
+    assert_eq!(
+        error_fix(&error),
+        Some(ErrorFix::SelectDeck("Missing".to_string()))
+    );
+    assert!(server.state().media.is_empty());
+    assert_eq!(
+        error_fix(&AnkiConnectError::EmptyNote),
+        Some(ErrorFix::RedoFieldMapping)
+    );
+    assert_eq!(error_fix(&AnkiConnectError::Duplicate), None);
+}
@@ -0,0 +1,150 @@
+mod common;
+
+use bookminer::anki_backend::{AnkiBackend, StoredMedia};
+use bookminer::anki_config::AnkiConfig;
+use bookminer::note_update::{
+    create_source_query, find_notes_from_page, load_note_into_files, select_fields_to_update,
+};
+use bookminer::possible_entries::PossibleContent;
+use common::client_for_url;
+use common::fake_anki::FakeAnkiServer;
+use std::collections::HashMap;
+use std::fs;
+
+fn start_server() -> FakeAnkiServer {
+    common::start_server(
+        &["Default"],
+        "Book",
+        &["Front", "Back", "Remarks", "File", "Page"],
+    )
+}
+
+fn create_config() -> AnkiConfig {
+    common::create_config(
+        "Default",
+        "Book",
+        &[
+            ("Front", PossibleContent::Front),
+            ("Back", PossibleContent::Back),
+            ("Remarks", PossibleContent::Empty),
+            ("File", PossibleContent::FileName),
+            ("Page", PossibleContent::PageNumber),
+        ],
+    )
+}
+
+fn add_note(anki: &dyn AnkiBackend, front: &str, page: &str) {
+    let fields = HashMap::from([
+        ("Front".to_string(), front.to_string()),
+        ("Back".to_string(), "[latex]old back[/latex]".to_string()),
+        ("Remarks".to_string(), "written in Anki".to_string()),
+        ("File".to_string(), "book.pdf".to_string()),
+        ("Page".to_string(), page.to_string()),
+    ]);
+    let tags = vec!["old".to_string()];
+    anki.send_note("Default", "Book", &fields, &tags, &StoredMedia::new())
+        .unwrap();
+}
+
+#[test]
+fn test_finds_only_notes_from_page() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+    add_note(&anki, "[latex]on page 3[/latex]", "3");
+    add_note(&anki, "[latex]on page 4[/latex]", "4");
+
+    let query = create_source_query(&create_config(), Some("book.pdf"), Some(3)).unwrap();
+    let notes = find_notes_from_page(&anki, &query).unwrap();
+
+    assert_eq!(notes.len(), 1);
+    assert_eq!(notes[0].field("Front"), Some("[latex]on page 3[/latex]"));
+}
+
+#[test]
+fn test_loads_note_into_files() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+    add_note(&anki, "[latex]$a &lt; b$[/latex]", "3");
+
+    let query = create_source_query(&create_config(), Some("book.pdf"), Some(3)).unwrap();
+    let notes = find_notes_from_page(&anki, &query).unwrap();
+    let tmp_dir = tempfile::tempdir().unwrap();
+    load_note_into_files(&notes[0], &create_config(), tmp_dir.path()).unwrap();
+
+    let front = fs::read_to_string(tmp_dir.path().join("front.tex")).unwrap();
+    let back = fs::read_to_string(tmp_dir.path().join("back.tex")).unwrap();
+    assert_eq!(front, "$a < b$");
+    assert_eq!(back, "old back");
+}
+
+#[test]
+fn test_loads_sections_into_back() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+    add_note(&anki, "[latex]front[/latex]", "3");
+    let mut config = create_config();
+    config.field_mapping[2].1 = PossibleContent::Section("REMARK".to_string());
+
+    let query = create_source_query(&config, Some("book.pdf"), Some(3)).unwrap();
+    let notes = find_notes_from_page(&anki, &query).unwrap();
+    let tmp_dir = tempfile::tempdir().unwrap();
+    load_note_into_files(&notes[0], &config, tmp_dir.path()).unwrap();
+
+    let back = fs::read_to_string(tmp_dir.path().join("back.tex")).unwrap();
+    assert_eq!(back, "old back\n\n!REMARK!\nwritten in Anki");
+}
+
+#[test]
+fn test_update_leaves_unmapped_fields_alone() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+    add_note(&anki, "[latex]front[/latex]", "3");
+    let note_id = server.state().notes[0].id;
+
+    let field_contents = HashMap::from([
+        ("Front".to_string(), "[latex]new front[/latex]".to_string()),
+        ("Back".to_string(), "[latex]new back[/latex]".to_string()),
+        ("Remarks".to_string(), "".to_string()),
+        ("File".to_string(), "book.pdf".to_string()),
+        ("Page".to_string(), "3".to_string()),
+    ]);
+    let fields = select_fields_to_update(&create_config(), field_contents);
+    anki.update_note(note_id, &fields, &["new".to_string()], &StoredMedia::new())
+        .unwrap();
+
+    let state = server.state();
+    let note = &state.notes[0];
+    assert_eq!(note.fields["Front"], "[latex]new front[/latex]");
+    assert_eq!(note.fields["Back"], "[latex]new back[/latex]");
+    assert_eq!(note.fields["Remarks"], "written in Anki");
+    assert_eq!(note.tags, vec!["new"]);
+}
+
+#[test]
+fn test_rejected_update_keeps_tags_and_removes_media() {
+    let server = start_server();
+    let anki = client_for_url(server.url(), None);
+    add_note(&anki, "[latex]front[/latex]", "3");
+    let note_id = server.state().notes[0].id;
+
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let screenshot = tmp_dir.path().join("screenshot.png");
+    fs::write(&screenshot, b"png data").unwrap();
+    let stored_media = anki.store_files(&[&screenshot]).unwrap();
+
+    // A field that was renamed in Anki after the mapping was saved
+    let fields = HashMap::from([
+        ("Front".to_string(), "[latex]new front[/latex]".to_string()),
+        (
+            "Image".to_string(),
+            "<img src=\"screenshot.png\">".to_string(),
+        ),
+    ]);
+    let result = anki.update_note(note_id, &fields, &["new".to_string()], &stored_media);
+
+    assert!(result.is_err());
+    let state = server.state();
+    assert_eq!(state.notes[0].fields["Front"], "[latex]front[/latex]");
+    assert_eq!(state.notes[0].tags, vec!["old"]);
+    assert!(state.media.is_empty());
+}
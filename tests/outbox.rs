@@ -0,0 +1,159 @@
+mod common;
+
+use bookminer::ankiconnect::AnkiConnectError;
+use bookminer::outbox::{flush_outbox, name_queued_media, queue_note, queued_entries, QueuedNote};
+use common::client_for_url;
+use common::fake_anki::{unreachable_url, FakeAnkiServer};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn queue_note_with_screenshot(outbox_dir: &Path, front: &str) {
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let screenshot = tmp_dir.path().join("screenshot.png");
+    fs::write(&screenshot, front.as_bytes()).unwrap();
+
+    let note = QueuedNote {
+        deck_name: "Default".to_string(),
+        note_type: "Basic".to_string(),
+        fields: HashMap::from([
+            ("Front".to_string(), front.to_string()),
+            (
+                "Back".to_string(),
+                "<img src=\"screenshot.png\">".to_string(),
+            ),
+        ]),
+        tags: vec!["queued".to_string()],
+        media: vec!["screenshot.png".to_string()],
+        note_to_update: None,
+    };
+    let media = name_queued_media(&[&screenshot]).unwrap();
+    queue_note(outbox_dir, &note, &media).unwrap();
+}
+
+#[test]
+fn test_flush_sends_and_removes_queued_notes() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+    let outbox_dir = tempfile::tempdir().unwrap();
+    queue_note_with_screenshot(outbox_dir.path(), "first");
+
+    let results = flush_outbox(&anki, outbox_dir.path()).unwrap();
+
+    assert_eq!(results.len(), 1);
+    assert!(results[0].result.is_ok());
+    assert!(queued_entries(outbox_dir.path()).unwrap().is_empty());
+
+    let state = server.state();
+    assert_eq!(state.notes.len(), 1);
+    assert_eq!(state.notes[0].fields["Front"], "first");
+    assert_eq!(state.notes[0].tags, vec!["queued"]);
+    assert_eq!(state.media["screenshot.png"], b"first");
+}
+
+#[test]
+fn test_flush_uses_renamed_media() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+    let outbox_dir = tempfile::tempdir().unwrap();
+    queue_note_with_screenshot(outbox_dir.path(), "first");
+    queue_note_with_screenshot(outbox_dir.path(), "second");
+
+    let results = flush_outbox(&anki, outbox_dir.path()).unwrap();
+
+    assert!(results.iter().all(|r| r.result.is_ok()));
+    let state = server.state();
+    assert_eq!(state.notes.len(), 2);
+    assert_eq!(
+        state.notes[1].fields["Back"],
+        "<img src=\"screenshot-1.png\">"
+    );
+    assert_eq!(state.media["screenshot-1.png"], b"second");
+}
+
+#[test]
+fn test_flush_keeps_notes_when_anki_is_not_running() {
+    let anki = client_for_url(unreachable_url(), None);
+    let outbox_dir = tempfile::tempdir().unwrap();
+    queue_note_with_screenshot(outbox_dir.path(), "first");
+    queue_note_with_screenshot(outbox_dir.path(), "second");
+
+    let results = flush_outbox(&anki, outbox_dir.path()).unwrap();
+
+    assert_eq!(
+        results.len(),
+        1,
+        "Flushing should stop at the first failure"
+    );
+    assert!(matches!(
+        results[0].result,
+        Err(AnkiConnectError::NotRunning)
+    ));
+    assert_eq!(queued_entries(outbox_dir.path()).unwrap().len(), 2);
+}
+
+#[test]
+fn test_flush_keeps_rejected_notes() {
+    let server = FakeAnkiServer::start();
+    server.state().decks.clear();
+    let anki = client_for_url(server.url(), None);
+    let outbox_dir = tempfile::tempdir().unwrap();
+    queue_note_with_screenshot(outbox_dir.path(), "first");
+
+    let results = flush_outbox(&anki, outbox_dir.path()).unwrap();
+
+    assert!(results[0].result.is_err());
+    assert_eq!(queued_entries(outbox_dir.path()).unwrap().len(), 1);
+    assert!(server.state().media.is_empty());
+}
+
+#[test]
+fn test_queued_media_with_the_same_name() {
+    let server = FakeAnkiServer::start();
+    let anki = client_for_url(server.url(), None);
+    let outbox_dir = tempfile::tempdir().unwrap();
+    let tmp_dir = tempfile::tempdir().unwrap();
+    let figures = [tmp_dir.path().join("a"), tmp_dir.path().join("b")];
+    let figures: Vec<PathBuf> = figures
+        .iter()
+        .map(|dir| {
+            fs::create_dir(dir).unwrap();
+            let figure = dir.join("figure.png");
+            fs::write(&figure, dir.to_string_lossy().as_bytes()).unwrap();
+            figure
+        })
+        .collect();
+
+    let media = name_queued_media(&[&figures[0], &figures[1]]).unwrap();
+    assert_eq!(media[&figures[0]], "figure.png");
+    assert_eq!(media[&figures[1]], "figure-1.png");
+
+    let note = QueuedNote {
+        deck_name: "Default".to_string(),
+        note_type: "Basic".to_string(),
+        fields: HashMap::from([
+            ("Front".to_string(), "figures".to_string()),
+            (
+                "Back".to_string(),
+                "<img src=\"figure.png\"><img src=\"figure-1.png\">".to_string(),
+            ),
+        ]),
+        tags: Vec::new(),
+        media: media.values().cloned().collect(),
+        note_to_update: None,
+    };
+    queue_note(outbox_dir.path(), &note, &media).unwrap();
+
+    let results = flush_outbox(&anki, outbox_dir.path()).unwrap();
+
+    assert!(results[0].result.is_ok());
+    let state = server.state();
+    assert_eq!(
+        state.media["figure.png"],
+        figures[0].parent().unwrap().to_string_lossy().as_bytes()
+    );
+    assert_eq!(
+        state.media["figure-1.png"],
+        figures[1].parent().unwrap().to_string_lossy().as_bytes()
+    );
+}